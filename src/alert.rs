@@ -0,0 +1,336 @@
+//! Low quota and low balance alerts, raised by the keep-alive daemon
+//!
+//! Thresholds are set in the config file under `alerts`. An alert fires once when a value drops
+//! below its threshold, and is re-armed after the value recovers (e.g., after a recharge):
+//!
+//! ```json
+//! {
+//!   "alerts": {
+//!     "remain_bytes": 5368709120,
+//!     "user_balance": 5.0,
+//!     "hook": "curl -d \"$BITSRUN_ALERT_MESSAGE\" https://ntfy.sh/my-lab",
+//!     "desktop": true
+//!   }
+//! }
+//! ```
+//!
+//! Every alert is logged as a warning. The `hook` is run with `sh -c` (`cmd /C` on Windows), with
+//! `BITSRUN_ALERT`, `BITSRUN_ALERT_ACCOUNT`, `BITSRUN_ALERT_VALUE`, `BITSRUN_ALERT_THRESHOLD` and
+//! `BITSRUN_ALERT_MESSAGE` set in its environment.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::client::SrunLoginState;
+
+use humansize::format_size;
+use humansize::BINARY;
+use log::{debug, warn};
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::process::Command;
+
+/// Alert thresholds and where alerts go, part of the config file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertConfig {
+    /// Alert when the remaining traffic of the package drops below this many bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remain_bytes: Option<i64>,
+    /// Alert when the remaining online time drops below this many seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remain_seconds: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_balance: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wallet_balance: Option<f64>,
+    /// Shell command run on every alert
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hook: Option<String>,
+    /// Show a desktop notification (`notify-send` on Linux, `osascript` on macOS)
+    pub desktop: bool,
+}
+
+impl AlertConfig {
+    /// Whether any threshold is set
+    pub fn is_empty(&self) -> bool {
+        self.remain_bytes.is_none()
+            && self.remain_seconds.is_none()
+            && self.user_balance.is_none()
+            && self.wallet_balance.is_none()
+    }
+}
+
+/// The login state value an alert is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AlertMetric {
+    RemainBytes,
+    RemainSeconds,
+    UserBalance,
+    WalletBalance,
+}
+
+impl AlertMetric {
+    const ALL: [AlertMetric; 4] = [
+        AlertMetric::RemainBytes,
+        AlertMetric::RemainSeconds,
+        AlertMetric::UserBalance,
+        AlertMetric::WalletBalance,
+    ];
+
+    /// Name of the config key and login state field
+    pub fn key(&self) -> &'static str {
+        match self {
+            AlertMetric::RemainBytes => "remain_bytes",
+            AlertMetric::RemainSeconds => "remain_seconds",
+            AlertMetric::UserBalance => "user_balance",
+            AlertMetric::WalletBalance => "wallet_balance",
+        }
+    }
+
+    fn threshold(&self, config: &AlertConfig) -> Option<f64> {
+        match self {
+            AlertMetric::RemainBytes => config.remain_bytes.map(|v| v as f64),
+            AlertMetric::RemainSeconds => config.remain_seconds.map(|v| v as f64),
+            AlertMetric::UserBalance => config.user_balance,
+            AlertMetric::WalletBalance => config.wallet_balance,
+        }
+    }
+
+    fn value(&self, state: &SrunLoginState) -> Option<f64> {
+        match self {
+            AlertMetric::RemainBytes => state.remain_bytes.map(|v| v as f64),
+            AlertMetric::RemainSeconds => state.remain_seconds.map(|v| v as f64),
+            AlertMetric::UserBalance => state.user_balance,
+            AlertMetric::WalletBalance => state.wallet_balance,
+        }
+    }
+
+    fn format(&self, value: f64) -> String {
+        match self {
+            AlertMetric::RemainBytes => format_size(value.max(0.0) as u64, BINARY),
+            AlertMetric::RemainSeconds => format!("{}s", value as i64),
+            AlertMetric::UserBalance | AlertMetric::WalletBalance => format!("{:.2}", value),
+        }
+    }
+}
+
+impl fmt::Display for AlertMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlertMetric::RemainBytes => write!(f, "remaining traffic"),
+            AlertMetric::RemainSeconds => write!(f, "remaining online time"),
+            AlertMetric::UserBalance => write!(f, "user balance"),
+            AlertMetric::WalletBalance => write!(f, "wallet balance"),
+        }
+    }
+}
+
+/// A value that has just dropped below its threshold
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub account: String,
+    pub metric: AlertMetric,
+    pub value: f64,
+    pub threshold: f64,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} is {}, below {}",
+            self.metric,
+            self.account,
+            self.metric.format(self.value),
+            self.metric.format(self.threshold)
+        )
+    }
+}
+
+/// Tracks which values are below their thresholds, to alert once per crossing
+#[derive(Debug, Default)]
+pub struct AlertMonitor {
+    pub config: AlertConfig,
+    /// Account and metric pairs already alerted, until the value recovers
+    below: BTreeSet<(String, AlertMetric)>,
+}
+
+impl AlertMonitor {
+    pub fn new(config: AlertConfig) -> Self {
+        Self {
+            config,
+            below: BTreeSet::new(),
+        }
+    }
+
+    /// Compare an account's login state against the thresholds, returning the new crossings
+    ///
+    /// Values missing from the login state leave their alerts as they were.
+    pub fn check(&mut self, account: &str, state: &SrunLoginState) -> Vec<Alert> {
+        let mut alerts = vec![];
+        if self.config.is_empty() {
+            return alerts;
+        }
+        for metric in AlertMetric::ALL {
+            let (Some(threshold), Some(value)) =
+                (metric.threshold(&self.config), metric.value(state))
+            else {
+                continue;
+            };
+
+            let key = (account.to_string(), metric);
+            if value >= threshold {
+                if self.below.remove(&key) {
+                    debug!("{} of {} recovered, alert re-armed", metric, account);
+                }
+            } else if self.below.insert(key) {
+                alerts.push(Alert {
+                    account: account.to_string(),
+                    metric,
+                    value,
+                    threshold,
+                });
+            }
+        }
+        alerts
+    }
+
+    /// Check the login state and raise every new alert
+    pub fn observe(&mut self, account: &str, state: &SrunLoginState) {
+        for alert in self.check(account, state) {
+            self.raise(&alert);
+        }
+    }
+
+    /// Log the alert, then run the hook and show the desktop notification if configured
+    ///
+    /// Commands are spawned in the background, and only their failures are logged.
+    pub fn raise(&self, alert: &Alert) {
+        warn!("Alert: {}", alert);
+
+        if let Some(hook) = &self.config.hook {
+            let mut cmd = shell(hook);
+            cmd.env("BITSRUN_ALERT", alert.metric.key())
+                .env("BITSRUN_ALERT_ACCOUNT", &alert.account)
+                .env("BITSRUN_ALERT_VALUE", alert.value.to_string())
+                .env("BITSRUN_ALERT_THRESHOLD", alert.threshold.to_string())
+                .env("BITSRUN_ALERT_MESSAGE", alert.to_string());
+            spawn("alert hook", cmd);
+        }
+
+        if self.config.desktop {
+            match desktop_notification(&alert.to_string()) {
+                Some(cmd) => spawn("desktop notification", cmd),
+                None => debug!("Desktop notifications are not supported on this platform"),
+            }
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(target_os = "linux")]
+fn desktop_notification(message: &str) -> Option<Command> {
+    let mut cmd = Command::new("notify-send");
+    cmd.args(["--app-name=bitsrun", "bitsrun", message]);
+    Some(cmd)
+}
+
+#[cfg(target_os = "macos")]
+fn desktop_notification(message: &str) -> Option<Command> {
+    // a debug formatted string is a valid AppleScript string literal
+    let script = format!("display notification {:?} with title \"bitsrun\"", message);
+    let mut cmd = Command::new("osascript");
+    cmd.args(["-e", &script]);
+    Some(cmd)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn desktop_notification(_message: &str) -> Option<Command> {
+    None
+}
+
+fn spawn(what: &'static str, mut cmd: Command) {
+    tokio::spawn(async move {
+        match cmd.status().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("The {} exited with {}", what, status),
+            Err(e) => warn!("Failed to run the {}: {}", what, e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AlertConfig;
+    use super::AlertMetric;
+    use super::AlertMonitor;
+
+    use crate::client::SrunLoginState;
+
+    use serde_json::json;
+
+    fn state(user_balance: f64) -> SrunLoginState {
+        serde_json::from_value(json!({
+            "error": "ok",
+            "online_ip": "10.1.2.3",
+            "user_balance": user_balance,
+        }))
+        .unwrap()
+    }
+
+    fn metrics(monitor: &mut AlertMonitor, account: &str, balance: f64) -> Vec<AlertMetric> {
+        let alerts = monitor.check(account, &state(balance));
+        alerts.iter().map(|alert| alert.metric).collect()
+    }
+
+    #[test]
+    fn alerts_once_per_crossing() {
+        let mut monitor = AlertMonitor::new(AlertConfig {
+            user_balance: Some(5.0),
+            ..Default::default()
+        });
+        assert!(metrics(&mut monitor, "alice", 10.0).is_empty());
+        assert_eq!(
+            metrics(&mut monitor, "alice", 4.0),
+            [AlertMetric::UserBalance]
+        );
+        // still below, already alerted
+        assert!(metrics(&mut monitor, "alice", 3.0).is_empty());
+        // tracked per account
+        assert_eq!(
+            metrics(&mut monitor, "bob", 3.0),
+            [AlertMetric::UserBalance]
+        );
+
+        // recovering re-arms the alert
+        assert!(metrics(&mut monitor, "alice", 5.0).is_empty());
+        assert_eq!(
+            metrics(&mut monitor, "alice", 4.5),
+            [AlertMetric::UserBalance]
+        );
+    }
+
+    #[test]
+    fn no_alerts_without_thresholds() {
+        let mut monitor = AlertMonitor::new(AlertConfig {
+            desktop: true,
+            ..Default::default()
+        });
+        assert!(monitor.config.is_empty());
+        assert!(metrics(&mut monitor, "alice", -1.0).is_empty());
+    }
+}
@@ -0,0 +1,123 @@
+use std::net::IpAddr;
+
+use anyhow::bail;
+use anyhow::Result;
+use log::debug;
+use reqwest::ClientBuilder;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Which local interface or address the portal traffic goes out from
+///
+/// On machines with several NICs (e.g., wired and Wi-Fi), binding makes the portal see, and
+/// authenticate, that NIC instead of whichever one the kernel routes through. Set in the config
+/// file at the top level:
+///
+/// ```json
+/// {
+///   "username": "...",
+///   "password": "...",
+///   "interface": "eth0"
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BindOptions {
+    /// Name of the network interface, e.g., `eth0` or `en0`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// Local address to send from, which must belong to one of the interfaces
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<IpAddr>,
+}
+
+impl BindOptions {
+    pub fn is_empty(&self) -> bool {
+        self.interface.is_none() && self.source_ip.is_none()
+    }
+
+    /// Finalize the options from the config file, where command line overrides replace them
+    /// as a whole (`--source-ip` drops an `interface` from the config and vice versa)
+    pub fn finalize(from_file: Option<BindOptions>, overrides: &BindOptions) -> Result<Self> {
+        let options = match overrides.is_empty() {
+            true => from_file.unwrap_or_default(),
+            false => overrides.clone(),
+        };
+        if options.interface.is_some() && options.source_ip.is_some() {
+            bail!("`interface` and `source_ip` can not be set at the same time")
+        }
+        Ok(options)
+    }
+
+    /// Bind the sockets of an http client to the interface or source address
+    ///
+    /// An interface is bound with `SO_BINDTODEVICE` on Linux (`IP_BOUND_IF` on macOS), so that
+    /// traffic leaves through it even when the kernel routes the portal through another NIC. On
+    /// other platforms, it is bound through its address instead, preferring IPv4 as the portal is
+    /// reached over IPv4.
+    pub fn bind(&self, builder: ClientBuilder) -> Result<ClientBuilder> {
+        match (&self.interface, self.source_ip) {
+            (Some(interface), _) => bind_interface(builder, interface),
+            (None, Some(source_ip)) => {
+                debug!("Binding portal traffic to {}", source_ip);
+                Ok(builder.local_address(source_ip))
+            }
+            (None, None) => Ok(builder),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn bind_interface(builder: ClientBuilder, interface: &str) -> Result<ClientBuilder> {
+    // fail early, instead of on every request, if the interface is not there
+    interface_address(interface)?;
+    debug!("Binding portal traffic to interface {}", interface);
+    Ok(builder.interface(interface))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn bind_interface(builder: ClientBuilder, interface: &str) -> Result<ClientBuilder> {
+    let address = interface_address(interface)?;
+    debug!("Binding portal traffic to {} of {}", address, interface);
+    Ok(builder.local_address(address))
+}
+
+#[cfg(unix)]
+fn interface_address(interface: &str) -> Result<IpAddr> {
+    use anyhow::Context;
+    use nix::ifaddrs::getifaddrs;
+
+    let addresses: Vec<IpAddr> = getifaddrs()
+        .with_context(|| "failed to list network interfaces")?
+        .filter(|ifaddr| ifaddr.interface_name == interface)
+        .filter_map(|ifaddr| {
+            let address = ifaddr.address?;
+            match (address.as_sockaddr_in(), address.as_sockaddr_in6()) {
+                (Some(v4), _) => Some(IpAddr::V4(v4.ip())),
+                (None, Some(v6)) => Some(IpAddr::V6(v6.ip())),
+                _ => None,
+            }
+        })
+        .collect();
+
+    // link-local IPv6 addresses are useless without a scope id, skip them
+    let usable = |ip: &&IpAddr| match ip {
+        IpAddr::V4(_) => true,
+        IpAddr::V6(v6) => (v6.segments()[0] & 0xffc0) != 0xfe80,
+    };
+    match addresses
+        .iter()
+        .filter(usable)
+        .min_by_key(|ip| ip.is_ipv6())
+    {
+        Some(ip) => Ok(*ip),
+        None => bail!("interface `{}` not found or has no address", interface),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_address(interface: &str) -> Result<IpAddr> {
+    bail!(
+        "binding to interface `{}` is not supported on this platform, use `source_ip` instead",
+        interface
+    )
+}
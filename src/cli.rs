@@ -1,4 +1,15 @@
 use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::bind::BindOptions;
+use crate::control::ControlCommand;
+use crate::encoder::EncoderKind;
+use crate::format::StatusFormat;
+use crate::mock::MockFault;
+use crate::portal::PortalOverrides;
+use crate::usage::UsageFormat;
+use crate::usage::UsagePeriod;
 
 use clap::Args;
 use clap::Parser;
@@ -13,6 +24,40 @@ pub struct Arguments {
     /// Verbose output
     #[arg(short, long, global = true)]
     pub verbose: bool,
+
+    /// Base URL of the SRUN portal (overrides the config file, defaults to http://10.0.0.55)
+    #[arg(long, global = true)]
+    pub portal: Option<String>,
+
+    /// Authenticate the IPv6 address as well as the IPv4 one (dual-stack)
+    #[arg(long, global = true)]
+    pub dual_stack: bool,
+
+    /// Send portal traffic from this network interface (overrides the config file)
+    #[arg(long, global = true, conflicts_with = "source_ip")]
+    pub interface: Option<String>,
+
+    /// Send portal traffic from this local address (overrides the config file)
+    #[arg(long, global = true)]
+    pub source_ip: Option<IpAddr>,
+}
+
+impl Arguments {
+    /// Portal profile overrides given on the command line
+    pub fn portal_overrides(&self) -> PortalOverrides {
+        PortalOverrides {
+            url: self.portal.clone(),
+            dual_stack: self.dual_stack,
+        }
+    }
+
+    /// Interface or source address given on the command line
+    pub fn bind_overrides(&self) -> BindOptions {
+        BindOptions {
+            interface: self.interface.clone(),
+            source_ip: self.source_ip,
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -31,13 +76,102 @@ pub enum Commands {
 
     /// Poll the server with login requests to keep the session alive
     KeepAlive(DaemonArgs),
+
+    /// Control a running keep-alive daemon
+    Ctl(CtlArgs),
+
+    /// Show what the keep-alive daemon did last, from its state file (no network access)
+    DaemonStatus(DaemonStatusArgs),
+
+    /// Install or uninstall keep-alive as a systemd service
+    Service(ServiceArgs),
+
+    /// Check if this device is online, intercepted or unreachable with connectivity probes
+    Probe(ProbeArgs),
+
+    /// Report traffic and online time from the recorded usage history
+    Usage(UsageArgs),
+
+    /// Serve a mock SRUN portal for offline testing
+    MockPortal(MockPortalArgs),
+
+    /// Tools for debugging failed logins
+    Debug(DebugArgs),
 }
 
 #[derive(Args)]
 pub struct StatusArgs {
+    /// Output JSON literal (same as `--format json`)
+    #[arg(short, long, conflicts_with = "format")]
+    pub json: bool,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = StatusFormat::Table)]
+    pub format: StatusFormat,
+
+    /// Render `{field}` or `{field:human}` placeholders over the login state fields instead
+    #[arg(short, long, conflicts_with_all = ["json", "format", "devices"])]
+    pub template: Option<String>,
+
+    /// Check the router mode `devices` listed in the config file instead of this device
+    #[arg(short, long)]
+    pub devices: bool,
+
+    /// Append the login state to the usage history
+    #[arg(short, long)]
+    pub record: bool,
+
+    /// Poll the login state and redraw it in place with throughput, until Ctrl-C
+    #[arg(short, long, conflicts_with_all = ["json", "format", "template", "devices"])]
+    pub watch: bool,
+
+    /// Seconds between polls in watch mode
+    #[arg(short, long, default_value_t = 2, requires = "watch")]
+    pub interval: u64,
+
+    /// Optionally provide path to the config file (for the portal profile)
+    #[arg(short, long)]
+    pub config: Option<String>,
+}
+
+impl StatusArgs {
+    /// The output format, with `--json` as a shorthand
+    pub fn format(&self) -> StatusFormat {
+        match self.json {
+            true => StatusFormat::Json,
+            false => self.format,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct ProbeArgs {
     /// Output JSON literal
     #[arg(short, long)]
     pub json: bool,
+
+    /// Optionally provide path to the config file (for the portal profile)
+    #[arg(short, long)]
+    pub config: Option<String>,
+}
+
+#[derive(Args)]
+pub struct UsageArgs {
+    /// Group usage by day, week or month
+    #[arg(short, long, value_enum, default_value_t = UsagePeriod::Daily)]
+    pub period: UsagePeriod,
+
+    /// Number of most recent periods to report
+    #[arg(short = 'n', long, default_value_t = 14)]
+    pub last: usize,
+
+    /// Only report the usage of this account
+    #[arg(short, long)]
+    pub username: Option<String>,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = UsageFormat::Table)]
+    pub format: UsageFormat,
 }
 
 #[derive(Args)]
@@ -50,10 +184,18 @@ pub struct ClientArgs {
     #[arg(short, long)]
     pub password: Option<String>,
 
-    /// Manually specify IP address (IPv4)
+    /// Manually specify IP address (IPv4 or IPv6)
     #[arg(long)]
     pub ip: Option<IpAddr>,
 
+    /// Manually specify the IPv6 address to authenticate in dual-stack mode
+    #[arg(long)]
+    pub ipv6: Option<IpAddr>,
+
+    /// Manually specify the `ac_id`, skipping its discovery (overrides the config file)
+    #[arg(long)]
+    pub ac_id: Option<String>,
+
     /// Use alternative `dm` logout endpoint for registered dumb terminals
     #[arg(long)]
     pub dm: bool,
@@ -73,3 +215,135 @@ pub struct DaemonArgs {
     #[arg(short, long)]
     pub config: Option<String>,
 }
+
+#[derive(Args)]
+pub struct CtlArgs {
+    #[arg(value_enum)]
+    pub command: ControlCommand,
+
+    /// Path to the daemon's control socket, if `control_socket` is set in its config
+    ///
+    /// Defaults to this user's daemon, or the system service's if that is not running.
+    #[arg(short, long)]
+    pub socket: Option<PathBuf>,
+
+    /// Output JSON literal
+    #[arg(short, long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct DaemonStatusArgs {
+    /// Output JSON literal
+    #[arg(short, long)]
+    pub json: bool,
+
+    /// Number of most recent transitions to show
+    #[arg(short = 'n', long, default_value_t = 10)]
+    pub history: usize,
+}
+
+#[derive(Args)]
+pub struct ServiceArgs {
+    #[command(subcommand)]
+    pub command: ServiceCommands,
+}
+
+#[derive(Subcommand)]
+pub enum ServiceCommands {
+    /// Write a systemd unit running `keep-alive` with the config file, and enable it
+    Install(ServiceInstallArgs),
+    /// Disable and remove the unit written by `install`
+    Uninstall(ServiceUninstallArgs),
+}
+
+#[derive(Args)]
+pub struct ServiceInstallArgs {
+    /// Install a user unit instead of a system one (which needs root)
+    #[arg(long)]
+    pub user: bool,
+
+    /// Path to the config file (resolved as `keep-alive` would by default)
+    #[arg(short, long)]
+    pub config: Option<String>,
+
+    /// Only write the unit, without enabling and starting it
+    #[arg(long)]
+    pub no_enable: bool,
+
+    /// Print the unit instead of installing it
+    #[arg(long, conflicts_with = "no_enable")]
+    pub print: bool,
+}
+
+#[derive(Args)]
+pub struct ServiceUninstallArgs {
+    /// Uninstall the user unit instead of the system one
+    #[arg(long)]
+    pub user: bool,
+}
+
+#[derive(Args)]
+pub struct MockPortalArgs {
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:8055")]
+    pub listen: SocketAddr,
+
+    /// Account allowed to login, as `<username>:<password>` (repeatable)
+    #[arg(short, long = "user", value_name = "USERNAME:PASSWORD")]
+    pub users: Vec<String>,
+
+    /// The `ac_id` that captive portal requests are redirected with
+    #[arg(long, default_value = "1")]
+    pub ac_id: String,
+
+    /// Encoder the portal expects logins to use
+    #[arg(long, value_enum, default_value_t = EncoderKind::SrunBx1)]
+    pub encoder: EncoderKind,
+
+    /// Inject a fault: `latency:<ms>`, `ip-mismatch:<ip>`, `malformed-jsonp` or `error:<code>`
+    #[arg(long = "fault", value_name = "FAULT")]
+    pub faults: Vec<MockFault>,
+}
+
+#[derive(Args)]
+pub struct DebugArgs {
+    #[command(subcommand)]
+    pub command: DebugCommands,
+}
+
+#[derive(Subcommand)]
+pub enum DebugCommands {
+    /// Decode the `info` of a captured login request and check its fields
+    Decode(DecodeArgs),
+}
+
+#[derive(Args)]
+pub struct DecodeArgs {
+    /// Captured `/cgi-bin/srun_portal` login URL, or its query string
+    pub request: String,
+
+    /// Challenge token the login was encoded with (from `/cgi-bin/get_challenge`)
+    #[arg(short, long)]
+    pub token: String,
+
+    /// Expected username (defaults to the `username` param of the request)
+    #[arg(short, long)]
+    pub username: Option<String>,
+
+    /// Expected password, to check the `{MD5}` password and the one inside `info`
+    #[arg(short, long)]
+    pub password: Option<String>,
+
+    /// Expected IP address (defaults to the `ip` param of the request)
+    #[arg(long)]
+    pub ip: Option<String>,
+
+    /// Expected `ac_id` (defaults to the `ac_id` param of the request)
+    #[arg(long)]
+    pub ac_id: Option<String>,
+
+    /// Encoder the login was expected to use
+    #[arg(long, value_enum, default_value_t = EncoderKind::SrunBx1)]
+    pub encoder: EncoderKind,
+}
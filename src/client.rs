@@ -1,33 +1,37 @@
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use crate::xencode::fkbase64;
-use crate::xencode::xencode;
+use crate::bind::BindOptions;
+use crate::error::SrunError;
+use crate::jsonp;
+use crate::lock::PortalLock;
+use crate::metrics;
+use crate::portal::PortalProfile;
+use crate::probe::probe_connectivity;
+use crate::probe::Connectivity;
+use crate::probe::ProbeReport;
+use crate::retry::RetryPolicy;
+use crate::state::current_network;
+use crate::state::AcIdCache;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
-use hmac::Hmac;
-use hmac::Mac;
-use md5::Digest;
-use md5::Md5;
 use owo_colors::OwoColorize;
+use owo_colors::Stream::Stderr;
 use owo_colors::Stream::Stdout;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderName;
+use reqwest::header::HeaderValue;
 use reqwest::Client;
+use sha1::Digest;
 
-use log::{debug, info};
+use log::{debug, info, warn};
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::json;
 use sha1::Sha1;
 
-/// Constants used for the /srun_portal endpoint
-pub const SRUN_PORTAL: &str = "http://10.0.0.55";
-pub const SRUN_TYPE: &str = "1";
-pub const SRUN_N: &str = "200";
-
-/// An arbitrary HTTP URL for srun to redirect
-pub const CAPTIVE_PORTAL_TEST: &str = "http://www.bit.edu.cn";
-
 /// The response from the `/rad_user_info` endpoint
 ///
 /// This response is used to determine if the device is logged in or not, and if it is logged in,
@@ -95,40 +99,80 @@ pub struct SrunLoginState {
     pub st: Option<i64>,
 }
 
+impl SrunLoginState {
+    /// The typed `error` field, `SrunError::Ok` when logged in
+    pub fn srun_error(&self) -> SrunError {
+        SrunError::from_response(&self.error, self.error_msg.as_deref().unwrap_or_default())
+    }
+}
+
 /// Get the login state of the current device
-pub async fn get_login_state(client: &Client, verbose: bool) -> Result<SrunLoginState> {
+pub async fn get_login_state(
+    client: &Client,
+    portal: &PortalProfile,
+    verbose: bool,
+) -> Result<SrunLoginState> {
+    get_login_state_by_url(client, &portal.endpoint("rad_user_info"), None, verbose).await
+}
+
+/// Get the login state of another device on the network, e.g., a LAN client behind a router
+pub async fn get_login_state_of(
+    client: &Client,
+    portal: &PortalProfile,
+    ip: IpAddr,
+    verbose: bool,
+) -> Result<SrunLoginState> {
+    get_login_state_by_url(client, &portal.endpoint("rad_user_info"), Some(ip), verbose).await
+}
+
+/// Get the login state of the current device's IPv6 address
+///
+/// Returns `Ok(None)` if the portal has no `url_v6` to reach it over IPv6.
+pub async fn get_login_state_v6(
+    client: &Client,
+    portal: &PortalProfile,
+    verbose: bool,
+) -> Result<Option<SrunLoginState>> {
+    if portal.url_v6.is_none() {
+        return Ok(None);
+    }
+    let url = portal.endpoint_v6("rad_user_info");
+    get_login_state_by_url(client, &url, None, verbose)
+        .await
+        .map(Some)
+}
+
+async fn get_login_state_by_url(
+    client: &Client,
+    url: &str,
+    ip: Option<IpAddr>,
+    verbose: bool,
+) -> Result<SrunLoginState> {
     // call /rad_user_info with callback=jsonp to get the login state
-    let params = [("callback", "jsonp")];
-    let url = format!("{}/cgi-bin/rad_user_info", SRUN_PORTAL);
+    let mut params = vec![("callback", "jsonp".to_string())];
+    if let Some(ip) = ip {
+        params.push(("ip", ip.to_string()));
+    }
 
     // get the response and extract the json
-    let resp = client
-        .get(&url)
-        .query(&params)
-        .send()
+    let resp = metrics::timed("rad_user_info", client.get(url).query(&params).send())
         .await
         .with_context(|| "failed to get login state")?;
     let raw_text = resp.text().await?;
 
+    // to stderr, to keep `status --format` output parsable
     if verbose {
-        println!(
+        eprintln!(
             "{} status response from portal:\n{}",
-            "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
-            raw_text.if_supports_color(Stdout, |t| t.dimmed())
+            "bitsrun:".if_supports_color(Stderr, |t| t.blue()),
+            raw_text.if_supports_color(Stderr, |t| t.dimmed())
         );
     }
 
-    // valid json starts at index 6 and ends at the second to last character
-    if raw_text.len() < 8 {
-        bail!("login status response too short: `{}`", raw_text)
-    }
-    let raw_json = &raw_text[6..raw_text.len() - 1];
-    let parsed_json = match serde_json::from_str::<SrunLoginState>(raw_json) {
-        Ok(json) => json,
-        Err(err) => bail!("failed to parse login status response: {}", err),
-    };
-
-    Ok(parsed_json)
+    let state = jsonp::decode::<SrunLoginState>(&raw_text)
+        .with_context(|| "failed to parse login status response")?;
+    metrics::record_login_state(&state, ip);
+    Ok(state)
 }
 
 /// Get the ac_id of the current device by visiting a URL
@@ -157,53 +201,9 @@ async fn get_acid_by_url(client: &Client, url: &str) -> Result<String> {
     Ok(ac_id.1)
 }
 
-/// Check if the current connection is truly online or intercepted.
-/// Returns Ok(None) if truly online (204 received).
-/// Returns Ok(Some(ac_id)) if intercepted by captive portal.
-/// Returns Err if network is not reachable at all.
-pub(crate) async fn check_connectivity(client: &Client) -> Result<Option<String>> {
-    // Use a domestic connectivity check endpoint for better stability in China
-    let target = "http://connect.rom.miui.com/generate_204";
-
-    let resp = client
-        .get(target)
-        .timeout(Duration::from_secs(3))
-        .send()
-        .await?;
-
-    if resp.status().as_u16() == 204 {
-        // Truly online
-        return Ok(None);
-    }
-
-    // If not 204, we are likely intercepted. Try to get ac_id from redirect or body.
-    let final_url = resp.url().to_string();
-    if let Ok(parsed_url) = url::Url::parse(&final_url) {
-        if let Some((_, ac_id)) = parsed_url.query_pairs().find(|(k, _)| k == "ac_id") {
-            return Ok(Some(ac_id.into_owned()));
-        }
-    }
-
-    // Fallback: search for ac_id in the response body (for WISPr XML like responses)
-    let body = resp.text().await.unwrap_or_default();
-    if let Some(pos) = body.find("index_") {
-        let sub = &body[pos..];
-        if let Some(ac_pos) = sub.find("ac_id=") {
-            let ac_val = sub[ac_pos + 6..]
-                .split(|c: char| !c.is_ascii_digit())
-                .next()
-                .unwrap_or("43");
-            return Ok(Some(ac_val.to_string()));
-        }
-    }
-
-    // Default to a sensible ac_id if intercepted but can't find specific id
-    Ok(Some("43".to_string()))
-}
-
 /// Cheap probe: is the SRUN gateway reachable? Off-campus → false.
-pub async fn is_on_campus(client: &Client) -> bool {
-    let url = format!("{}/cgi-bin/rad_user_info", SRUN_PORTAL);
+pub async fn is_on_campus(client: &Client, portal: &PortalProfile) -> bool {
+    let url = portal.endpoint("rad_user_info");
     matches!(
         tokio::time::timeout(
             Duration::from_millis(400),
@@ -215,17 +215,17 @@ pub async fn is_on_campus(client: &Client) -> bool {
 }
 
 /// Get the ac_id of the current device
-async fn get_acid(client: &Client) -> Result<String> {
-    // Try to visit `CAPTIVE_PORTAL_TEST`.
-    // If not logged in, it will be redirected to `SRUN_PORTAL` with ac_id.
-    // Otherwise, we fall back to visit `SRUN_PORTAL` directly.
+async fn get_acid(client: &Client, portal: &PortalProfile) -> Result<String> {
+    // Try to visit the profile's `captive_portal_test`.
+    // If not logged in, it will be redirected to the portal with ac_id.
+    // Otherwise, we fall back to visit the portal directly.
     // https://en.wikipedia.org/wiki/Captive_portal#Detection
     //
-    // Because of ITC's double authentication mechanism, visiting `SRUN_PORTAL` directly is not preferred.
+    // Because of ITC's double authentication mechanism, visiting the portal directly is not preferred.
     // https://itc.bit.edu.cn/fwzn/zxbl/f2c0c8e939ce4e9cace880d5403fe4b5.htm
-    get_acid_by_url(client, CAPTIVE_PORTAL_TEST)
+    get_acid_by_url(client, &portal.captive_portal_test)
         .await
-        .or(get_acid_by_url(client, SRUN_PORTAL).await)
+        .or(get_acid_by_url(client, &portal.url).await)
 }
 
 /// SRUN portal response type when calling login/logout
@@ -249,6 +249,93 @@ pub struct SrunPortalResponse {
     pub res: String,
 }
 
+impl SrunPortalResponse {
+    /// The typed `error` and `error_msg` fields
+    pub fn srun_error(&self) -> SrunError {
+        SrunError::from_response(&self.error, &self.error_msg)
+    }
+}
+
+/// The encoded `password`, `chksum` and `info` parameters of a `/srun_portal` login request
+///
+/// These are derived from the challenge token, and are checked independently by the
+/// [mock portal](crate::mock) when verifying a login.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrunLoginPayload {
+    pub password: String,
+    pub chksum: String,
+    pub info: String,
+}
+
+impl SrunLoginPayload {
+    pub fn new(
+        token: &str,
+        username: &str,
+        password: &str,
+        ip: &str,
+        ac_id: &str,
+        portal: &PortalProfile,
+    ) -> Result<Self> {
+        let encoder = portal.encoder.encoder();
+        let chksum_data = json!({
+            "username": username,
+            "password": password,
+            "ip": ip,
+            "acid": ac_id,
+            "enc_ver": encoder.enc_ver(),
+        });
+
+        let json_chksum_data = serde_json::to_string(&chksum_data)?;
+        let info = encoder.encode_info(&json_chksum_data, token);
+
+        // construct param payload
+        let hmd5 = encoder.hmd5(password, token);
+        let chksum = encoder.chksum(
+            token,
+            &[
+                username,
+                &hmd5,
+                ac_id,
+                ip,
+                &portal.n,
+                &portal.srun_type,
+                &info,
+            ],
+        );
+
+        Ok(Self {
+            password: format!("{}{}", "{MD5}", hmd5),
+            chksum,
+            info,
+        })
+    }
+}
+
+/// Whether a failed login may be due to a wrong `ac_id`, rather than the account itself
+fn blames_ac_id(err: &SrunError) -> bool {
+    !matches!(
+        err,
+        SrunError::Ok
+            | SrunError::IpAlreadyOnline
+            | SrunError::TooManyDevices
+            | SrunError::UserNotFound
+            | SrunError::WrongPassword
+            | SrunError::UserDisabled
+            | SrunError::Arrears
+            | SrunError::QuotaExhausted
+            | SrunError::TooFrequent
+    )
+}
+
+/// Sign a `/rad_user_dm` logout request for registered dumb terminals
+pub fn dm_logout_sign(timestamp: &str, username: &str, ip: &str, unbind: &str) -> String {
+    let mut hasher = Sha1::new();
+    let sn = format!("{0}{1}{2}{3}{0}", timestamp, username, ip, unbind);
+
+    hasher.update(sn);
+    format!("{:x}", hasher.finalize())
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct SrunChallenge {
     // the only useful field that must be present
@@ -257,7 +344,7 @@ pub struct SrunChallenge {
 }
 
 /// SRUN client
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SrunClient {
     // reusable http client
     pub http_client: Client,
@@ -267,66 +354,385 @@ pub struct SrunClient {
     pub password: String,
 
     // srun portal info
+    pub portal: PortalProfile,
     pub ip: IpAddr,
     pub ac_id: String,
+    // network the ac_id was cached for, evicted from the cache if a login with it fails
+    pub ac_id_cached_for: Option<String>,
     pub dm: bool, // whether the device is authenticated with its mac address
+    pub retry: RetryPolicy,
+    pub fix_ip_mismatch: bool, // follow the IP the gateway saw in `get_challenge`
+    pub lock_dir: Option<PathBuf>, // where `portal.lock` is kept, the shared lock dir if unset
     pub login_state: SrunLoginState,
+
+    // dual-stack only, discovered through the portal's `url_v6`
+    pub ipv6: Option<IpAddr>,
+    pub login_state_v6: Option<SrunLoginState>,
+}
+
+/// How the underlying HTTP client talks to the portal
+///
+/// Shared by everything that builds a `reqwest::Client`, so that status checks, logins and the
+/// daemon all use the same timeouts and headers.
+#[derive(Debug, Clone)]
+pub struct TransportOptions {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub user_agent: Option<String>,
+    /// Extra headers sent with every request, as `(name, value)`
+    pub headers: Vec<(String, String)>,
+    /// Local interface or address to send from
+    pub bind: BindOptions,
+}
+
+impl Default for TransportOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_millis(400),
+            request_timeout: Duration::from_millis(400),
+            user_agent: None,
+            headers: vec![],
+            bind: BindOptions::default(),
+        }
+    }
+}
+
+impl TransportOptions {
+    /// Build an http client without proxy, as the portal is only reachable directly
+    pub fn build_client(&self) -> Result<Client> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name `{}`", name))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("invalid value for header `{}`", name))?;
+            headers.append(name, value);
+        }
+
+        let mut builder = Client::builder()
+            .no_proxy()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .default_headers(headers);
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        self.bind
+            .bind(builder)?
+            .build()
+            .with_context(|| "failed to build http client")
+    }
+}
+
+/// Builder for [`SrunClient`]
+///
+/// Everything but the credentials is optional, by default BIT's portal is used, and the IP and
+/// `ac_id` are discovered from the portal.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use std::time::Duration;
+/// use bitsrun::client::SrunClient;
+///
+/// let client = SrunClient::builder("username", "password")
+///     .request_timeout(Duration::from_secs(1))
+///     .user_agent("bitsrun")
+///     .dm(true)
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SrunClientBuilder {
+    username: String,
+    password: String,
+    portal: PortalProfile,
+    transport: TransportOptions,
+    discovery_timeout: Duration,
+    http_client: Option<Client>,
+    ip: Option<IpAddr>,
+    ipv6: Option<IpAddr>,
+    dm: bool,
+    ac_id: Option<String>,
+    ac_id_cache: Option<AcIdCache>,
+    fix_ip_mismatch: bool,
+    lock_dir: Option<PathBuf>,
+    retry: RetryPolicy,
+}
+
+impl SrunClientBuilder {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+            portal: PortalProfile::default(),
+            transport: TransportOptions::default(),
+            discovery_timeout: Duration::from_secs(1),
+            http_client: None,
+            ip: None,
+            ipv6: None,
+            dm: false,
+            ac_id: None,
+            ac_id_cache: None,
+            fix_ip_mismatch: true,
+            lock_dir: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// The SRUN portal deployment to talk to (BIT's 10.0.0.55 by default)
+    pub fn portal(mut self, portal: PortalProfile) -> Self {
+        self.portal = portal;
+        self
+    }
+
+    /// Timeouts, user agent and headers of the http client, replacing any set before
+    pub fn transport(mut self, transport: TransportOptions) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Timeout for establishing a connection to the portal (400ms by default)
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.transport.connect_timeout = timeout;
+        self
+    }
+
+    /// Timeout for each request to the portal (400ms by default)
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.transport.request_timeout = timeout;
+        self
+    }
+
+    /// Timeout for each discovery step (ac_id, login state) in `build` (1s by default)
+    pub fn discovery_timeout(mut self, timeout: Duration) -> Self {
+        self.discovery_timeout = timeout;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.transport.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Send from a local interface or address, resolved in `build`
+    pub fn bind(mut self, bind: BindOptions) -> Self {
+        self.transport.bind = bind;
+        self
+    }
+
+    /// Add a header sent with every request, validated in `build`
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.transport.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Reuse a pre-built http client, in which case the transport options are ignored
+    pub fn http_client(mut self, http_client: Client) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    /// The IP address to authenticate (`online_ip` from the login portal by default)
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    /// The IPv6 address to authenticate in dual-stack mode (discovered by default)
+    pub fn ipv6(mut self, ipv6: IpAddr) -> Self {
+        self.ipv6 = Some(ipv6);
+        self
+    }
+
+    /// Whether the device is authenticated through the campus login portal with its mac address
+    /// (important for dumb terminals!!!)
+    pub fn dm(mut self, dm: bool) -> Self {
+        self.dm = dm;
+        self
+    }
+
+    /// Skip `ac_id` discovery and use this one
+    pub fn ac_id(mut self, ac_id: impl Into<String>) -> Self {
+        self.ac_id = Some(ac_id.into());
+        self
+    }
+
+    /// How failed logins are retried in `login_with_retry` and `ensure_online`
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Look up the `ac_id` of the current network in the cache before discovering it, and
+    /// remember newly discovered ones
+    pub fn ac_id_cache(mut self, cache: AcIdCache) -> Self {
+        self.ac_id_cache = Some(cache);
+        self
+    }
+
+    /// Whether to switch to the IP the gateway saw if it differs from the one requested (on by
+    /// default), turn off when authenticating other devices than this one
+    pub fn fix_ip_mismatch(mut self, fix_ip_mismatch: bool) -> Self {
+        self.fix_ip_mismatch = fix_ip_mismatch;
+        self
+    }
+
+    /// Keep `portal.lock` in `dir` instead of the lock dir shared by every bitsrun process, e.g.,
+    /// so that tests do not wait on a running daemon
+    pub fn lock_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.lock_dir = Some(dir.into());
+        self
+    }
+
+    /// Build the http client (unless given), and discover the IP, ac_id and login state
+    pub async fn build(self) -> Result<SrunClient> {
+        if let Some(ipv6) = self.ipv6.filter(|ip| !ip.is_ipv6()) {
+            bail!("`{}` is not an IPv6 address", ipv6)
+        }
+        let http_client = match self.http_client {
+            Some(client) => client,
+            None => self.transport.build_client()?,
+        };
+        let mut portal = self.portal;
+        let discovery_timeout = self.discovery_timeout;
+
+        // explicit ac_id > cached for this network > discovered
+        let network = match &self.ac_id_cache {
+            Some(_) => current_network(&self.transport.bind),
+            None => None,
+        };
+        let cached = match (&self.ac_id_cache, &network) {
+            (Some(cache), Some(network)) => cache.get(&portal, network).map(str::to_string),
+            _ => None,
+        };
+        let ac_id_cached_for = match (&self.ac_id, &cached) {
+            (None, Some(_)) => network.clone(),
+            _ => None,
+        };
+        let ac_id = match self.ac_id.or(cached) {
+            Some(ac_id) => ac_id,
+            None => {
+                // a generous timeout for discovery allows for micro-retries or slow responses
+                let ac_id =
+                    tokio::time::timeout(discovery_timeout, get_acid(&http_client, &portal))
+                        .await
+                        .context("Discovery timeout (ac_id)")??;
+                if let (Some(mut cache), Some(network)) = (self.ac_id_cache, &network) {
+                    debug!("Caching ac_id={} for {}", ac_id, network);
+                    cache.insert(&portal, network, &ac_id);
+                    if let Err(e) = cache.save() {
+                        warn!("Failed to save ac_id cache: {:#}", e);
+                    }
+                }
+                ac_id
+            }
+        };
+
+        let login_state = tokio::time::timeout(
+            discovery_timeout,
+            get_login_state(&http_client, &portal, false),
+        )
+        .await
+        .context("Discovery timeout (login_state)")??;
+        portal.encoder = portal.encoder.resolve(&login_state);
+
+        // IPv6 discovery is best effort, the IPv4 login is still useful without it
+        let login_state_v6 = match portal.double_stack {
+            true => tokio::time::timeout(
+                discovery_timeout,
+                get_login_state_v6(&http_client, &portal, false),
+            )
+            .await
+            .ok()
+            .and_then(|state| state.ok())
+            .flatten(),
+            false => None,
+        };
+        let ipv6 = self.ipv6.or_else(|| {
+            login_state_v6
+                .as_ref()
+                .map(|state| state.online_ip)
+                .filter(|ip| ip.is_ipv6())
+        });
+        let ip = self.ip.unwrap_or(login_state.online_ip);
+        Ok(SrunClient {
+            http_client,
+            username: self.username,
+            password: self.password,
+            portal,
+            ip,
+            ac_id,
+            ac_id_cached_for,
+            dm: self.dm,
+            retry: self.retry,
+            fix_ip_mismatch: self.fix_ip_mismatch,
+            lock_dir: self.lock_dir,
+            login_state,
+            ipv6,
+            login_state_v6,
+        })
+    }
+}
+
+/// How [`SrunClient::retry_login`] went
+struct LoginAttempts {
+    /// The last attempt's response (or error)
+    result: Result<SrunPortalResponse>,
+    attempts: u32,
+    /// Whether it ran out of attempts, instead of stopping at a success or a permanent error
+    exhausted: bool,
 }
 
 impl SrunClient {
+    /// Start building a SRUN client for an account (username is the student id)
+    pub fn builder(username: impl Into<String>, password: impl Into<String>) -> SrunClientBuilder {
+        SrunClientBuilder::new(username, password)
+    }
+
     /// Create a new SRUN client, where the http client will be reused if provided
     ///
     /// # Arguments
     ///
     /// * `username` - The username of the SRUN account (student id)
     /// * `password` - The password of the SRUN account
+    /// * `http_client` - The http client to be used (a new one will be created if not specified)
     /// * `ip` - The IP address (`online_ip` from the login portal if not specified)
     /// * `dm` - Whether the device is authenticated through the campus login portal with its mac
     ///   address (important for dumb terminals!!!)
-    /// * `http_client` - The http client to be used (a new one will be created if not specified)
+    /// * `portal` - The SRUN portal deployment to talk to (BIT's 10.0.0.55 if not specified)
+    #[deprecated(note = "use `SrunClient::builder` instead")]
     pub async fn new(
         username: String,
         password: String,
         http_client: Option<Client>,
         ip: Option<IpAddr>,
         dm: Option<bool>,
+        portal: Option<PortalProfile>,
     ) -> Result<SrunClient> {
-        let http_client = http_client.unwrap_or_else(|| {
-            Client::builder()
-                .no_proxy()
-                .connect_timeout(Duration::from_millis(400))
-                .timeout(Duration::from_millis(400))
-                .build()
-                .unwrap_or_default()
-        });
-
-        // Use a 1s timeout for initial discovery to allow for multiple micro-retries or slow responses
-        let ac_id = tokio::time::timeout(Duration::from_secs(1), get_acid(&http_client))
-            .await
-            .context("Discovery timeout (ac_id)")??;
-
-        let login_state =
-            tokio::time::timeout(Duration::from_secs(1), get_login_state(&http_client, false))
-                .await
-                .context("Discovery timeout (login_state)")??;
-
-        let ip = ip.unwrap_or(login_state.online_ip);
-        let dm = dm.unwrap_or(false);
-        Ok(SrunClient {
-            http_client,
-            username,
-            password,
-            ip,
-            ac_id,
-            dm,
-            login_state,
-        })
+        let mut builder = SrunClientBuilder::new(username, password).dm(dm.unwrap_or(false));
+        if let Some(http_client) = http_client {
+            builder = builder.http_client(http_client);
+        }
+        if let Some(ip) = ip {
+            builder = builder.ip(ip);
+        }
+        if let Some(portal) = portal {
+            builder = builder.portal(portal);
+        }
+        builder.build().await
     }
 
     /// Login to the SRUN portal
     pub async fn login(&self, force: bool, verbose: bool) -> Result<SrunPortalResponse> {
-        // check if already logged in
-        if (self.login_state.error == "ok") & !force {
+        self.check_logged_out(force)?;
+        self.login_ip(self.ip, false, verbose).await
+    }
+
+    /// Bail if already logged in, unless `force`
+    fn check_logged_out(&self, force: bool) -> Result<()> {
+        if (self.login_state.srun_error() == SrunError::Ok) & !force {
             bail!(
                 "{} already logged in",
                 self.login_state
@@ -335,63 +741,155 @@ impl SrunClient {
                     .if_supports_color(Stdout, |t| t.underline())
             )
         }
+        Ok(())
+    }
 
-        // construct checksum and crypto encodings
-        let (token, real_ip) = self.get_challenge(verbose).await?;
-        let real_ip_str = real_ip.to_string();
-
-        let chksum_data = json!({
-            "username": self.username.clone(),
-            "password": self.password.clone(),
-            "ip": real_ip_str,
-            "acid": self.ac_id.clone(),
-            "enc_ver": String::from("srun_bx1"),
-        });
-
-        let json_chksum_data = serde_json::to_string(&chksum_data)?;
-        let encoded_data = xencode(json_chksum_data.as_str(), token.as_str());
-        let info = format!("{}{}", "{SRBX1}", fkbase64(encoded_data));
+    /// Login to the SRUN portal, retrying failed attempts as the retry policy allows
+    ///
+    /// Stops at the first response that is not worth retrying: a success, or a permanent error
+    /// such as a wrong password. The last response (or error) is returned.
+    pub async fn login_with_retry(&self, force: bool, verbose: bool) -> Result<SrunPortalResponse> {
+        self.check_logged_out(force)?;
+        self.retry_login(false, verbose).await.result
+    }
 
-        // construct param payload
-        let mac = Hmac::<Md5>::new_from_slice(token.as_bytes())?;
-        let hmd5 = format!("{:x}", mac.finalize().into_bytes());
+    /// Attempt logins as the retry policy allows, logging every failed attempt that is retried
+    ///
+    /// With `verify`, a successful login only counts once the connectivity probes no longer find
+    /// the network unreachable.
+    async fn retry_login(&self, verify: bool, verbose: bool) -> LoginAttempts {
+        let max_attempts = self.retry.max_attempts.max(1);
+        let mut attempts = 1;
+        loop {
+            let result = self.login_ip(self.ip, false, verbose).await;
+            let reason = match &result {
+                Ok(resp) => match resp.srun_error() {
+                    SrunError::Ok | SrunError::IpAlreadyOnline if verify => {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        match self.probe().await.connectivity {
+                            Connectivity::Unreachable => {
+                                Some(String::from("still unreachable after login"))
+                            }
+                            _ => None,
+                        }
+                    }
+                    SrunError::Ok | SrunError::IpAlreadyOnline => None,
+                    err if self.retry.gives_up_on(&err) => None,
+                    err => Some(err.to_string()),
+                },
+                // the portal is unreachable
+                Err(e) => Some(format!("{:#}", e)),
+            };
+            if reason.is_none() || attempts >= max_attempts {
+                return LoginAttempts {
+                    result,
+                    attempts,
+                    exhausted: reason.is_some(),
+                };
+            }
 
-        let chksum = {
-            let chk = format!(
-                "{0}{1}{0}{2}{0}{3}{0}{4}{0}{5}{0}{6}{0}{7}",
-                token, self.username, hmd5, self.ac_id, real_ip_str, SRUN_N, SRUN_TYPE, info
+            let delay = self.retry.delay(attempts);
+            info!(
+                "Login attempt {}/{} failed ({}), retrying in {:.1}s...",
+                attempts,
+                max_attempts,
+                reason.unwrap_or_default(),
+                delay.as_secs_f64()
             );
-            let mut hasher = Sha1::new();
-            hasher.update(chk);
-            format!("{:x}", hasher.finalize())
+            tokio::time::sleep(delay).await;
+            attempts += 1;
+        }
+    }
+
+    /// Login the IPv6 address as well, for dual-stack portals
+    ///
+    /// Returns `Ok(None)` if the portal is not dual-stack or no IPv6 address is known.
+    pub async fn login_v6(&self, force: bool, verbose: bool) -> Result<Option<SrunPortalResponse>> {
+        let Some(ipv6) = self.ipv6.filter(|_| self.portal.double_stack) else {
+            return Ok(None);
         };
 
+        // check if already logged in
+        let online = self
+            .login_state_v6
+            .as_ref()
+            .is_some_and(|state| state.srun_error() == SrunError::Ok);
+        if online & !force {
+            bail!(
+                "{} already logged in",
+                ipv6.to_string()
+                    .if_supports_color(Stdout, |t| t.underline())
+            )
+        }
+
+        self.login_ip(ipv6, true, verbose).await.map(Some)
+    }
+
+    /// Login `ip` through the portal, reached over IPv6 if `v6` is set
+    async fn login_ip(&self, ip: IpAddr, v6: bool, verbose: bool) -> Result<SrunPortalResponse> {
+        let _lock = PortalLock::acquire(self.lock_dir.as_deref()).await;
+        let result = self.try_login_ip(ip, v6, verbose).await;
+        metrics::record_login(&result);
+        if let (Ok(resp), Some(network)) = (&result, &self.ac_id_cached_for) {
+            if blames_ac_id(&resp.srun_error()) {
+                debug!("Evicting cached ac_id={} for {}", self.ac_id, network);
+                if let Err(e) = AcIdCache::evict(&self.portal, network) {
+                    warn!("Failed to evict cached ac_id: {:#}", e);
+                }
+            }
+        }
+        result
+    }
+
+    async fn try_login_ip(
+        &self,
+        ip: IpAddr,
+        v6: bool,
+        verbose: bool,
+    ) -> Result<SrunPortalResponse> {
+        // construct checksum and crypto encodings
+        let (token, real_ip) = self.get_challenge(ip, v6, verbose).await?;
+        let real_ip_str = real_ip.to_string();
+
+        let payload = SrunLoginPayload::new(
+            &token,
+            &self.username,
+            &self.password,
+            &real_ip_str,
+            &self.ac_id,
+            &self.portal,
+        )?;
+
         // construct request body
-        let password_encoded = format!("{}{}", "{MD5}", hmd5);
-        let params = [
+        let mut params = vec![
             ("callback", "jsonp"),
             ("action", "login"),
             ("username", self.username.as_str()),
-            ("password", password_encoded.as_str()),
-            ("chksum", chksum.as_str()),
-            ("info", info.as_str()),
+            ("password", payload.password.as_str()),
+            ("chksum", payload.chksum.as_str()),
+            ("info", payload.info.as_str()),
             ("ac_id", self.ac_id.as_str()),
             ("ip", real_ip_str.as_str()),
-            ("type", SRUN_TYPE),
-            ("n", SRUN_N),
+            ("type", self.portal.srun_type.as_str()),
+            ("n", self.portal.n.as_str()),
         ];
-        let url = format!("{}/cgi-bin/srun_portal", SRUN_PORTAL);
+        if self.portal.double_stack {
+            params.push(("double_stack", "1"));
+        }
+        let url = match v6 {
+            true => self.portal.endpoint_v6("srun_portal"),
+            false => self.portal.endpoint("srun_portal"),
+        };
 
         debug!("Portal Request: {}?{:?}", url, params);
 
         // send login request
-        let resp = self
-            .http_client
-            .get(&url)
-            .query(&params)
-            .send()
-            .await
-            .with_context(|| "failed to send request when logging in")?;
+        let resp = metrics::timed(
+            "srun_portal",
+            self.http_client.get(&url).query(&params).send(),
+        )
+        .await
+        .with_context(|| "failed to send request when logging in")?;
         let raw_text = resp.text().await?;
         debug!("Portal Output: {}", raw_text);
 
@@ -403,18 +901,14 @@ impl SrunClient {
             );
         }
 
-        if raw_text.len() < 8 {
-            bail!("login response too short: `{}`", raw_text)
-        }
-        let raw_json = &raw_text[6..raw_text.len() - 1];
-        serde_json::from_str::<SrunPortalResponse>(raw_json)
-            .with_context(|| format!("failed to parse malformed login response:\n  {}", raw_json))
+        jsonp::decode::<SrunPortalResponse>(&raw_text)
+            .with_context(|| "failed to parse login response")
     }
 
     /// Logout of the SRUN portal
     pub async fn logout(&self, force: bool, verbose: bool) -> Result<SrunPortalResponse> {
         // check if already logged out
-        if (self.login_state.error == "not_online_error") & !force {
+        if (self.login_state.srun_error() == SrunError::NotOnline) & !force {
             bail!(
                 "{} already logged out",
                 self.ip
@@ -449,20 +943,64 @@ impl SrunClient {
             );
         }
 
-        // perform logout action
-        let url = {
-            // dumb terminals use a different endpoint (dm logout)
-            match self.dm {
-                true => format!("{}/cgi-bin/rad_user_dm", SRUN_PORTAL),
-                false => format!("{}/cgi-bin/srun_portal", SRUN_PORTAL),
-            }
+        self.logout_ip(self.ip, false, &logged_in_username, verbose)
+            .await
+    }
+
+    /// Logout the IPv6 address as well, for dual-stack portals
+    ///
+    /// Returns `Ok(None)` if the portal is not dual-stack or no IPv6 address is known.
+    pub async fn logout_v6(
+        &self,
+        force: bool,
+        verbose: bool,
+    ) -> Result<Option<SrunPortalResponse>> {
+        let Some(ipv6) = self.ipv6.filter(|_| self.portal.double_stack) else {
+            return Ok(None);
+        };
+
+        // check if already logged out
+        let state = self.login_state_v6.as_ref();
+        let online = state.is_some_and(|state| state.srun_error() == SrunError::Ok);
+        if !online & !force {
+            bail!(
+                "{} already logged out",
+                ipv6.to_string()
+                    .if_supports_color(Stdout, |t| t.underline())
+            )
+        }
+
+        let username = state
+            .and_then(|state| state.user_name.clone())
+            .unwrap_or_else(|| self.username.clone());
+        self.logout_ip(ipv6, true, &username, verbose)
+            .await
+            .map(Some)
+    }
+
+    /// Logout `ip` logged in as `username`, through the portal reached over IPv6 if `v6` is set
+    async fn logout_ip(
+        &self,
+        ip: IpAddr,
+        v6: bool,
+        username: &str,
+        verbose: bool,
+    ) -> Result<SrunPortalResponse> {
+        // dumb terminals use a different endpoint (dm logout)
+        let endpoint = match self.dm {
+            true => "rad_user_dm",
+            false => "srun_portal",
+        };
+        let url = match v6 {
+            true => self.portal.endpoint_v6(endpoint),
+            false => self.portal.endpoint(endpoint),
         };
 
-        let ip_str = self.ip.to_string();
+        let ip_str = ip.to_string();
         let mut params = vec![
             ("callback", String::from("jsonp")),
-            ("ip", self.ip.to_string()),
-            ("username", logged_in_username.clone()),
+            ("ip", ip_str.clone()),
+            ("username", username.to_string()),
         ];
 
         if self.dm {
@@ -470,16 +1008,7 @@ impl SrunClient {
             let timestamp = Utc::now().timestamp().to_string();
             let unbind = String::from("1");
 
-            let sign = {
-                let mut hasher = Sha1::new();
-                let sn = format!(
-                    "{0}{1}{2}{3}{0}",
-                    timestamp, logged_in_username, ip_str, unbind
-                );
-
-                hasher.update(sn);
-                format!("{:x}", hasher.finalize())
-            };
+            let sign = dm_logout_sign(&timestamp, username, &ip_str, &unbind);
 
             params.push(("time", timestamp));
             params.push(("unbind", unbind));
@@ -489,11 +1018,8 @@ impl SrunClient {
             params.push(("ac_id", self.ac_id.clone()));
         }
 
-        let resp = self
-            .http_client
-            .get(&url)
-            .query(&params)
-            .send()
+        let _lock = PortalLock::acquire(self.lock_dir.as_deref()).await;
+        let resp = metrics::timed(endpoint, self.http_client.get(&url).query(&params).send())
             .await
             .with_context(|| "failed to send request when logging out")?;
         let raw_text = resp.text().await?;
@@ -506,57 +1032,115 @@ impl SrunClient {
             );
         }
 
-        if raw_text.len() < 8 {
-            bail!("login response too short: `{}`", raw_text)
-        }
-        let raw_json = &raw_text[6..raw_text.len() - 1];
-        serde_json::from_str::<SrunPortalResponse>(raw_json)
-            .with_context(|| format!("failed to parse malformed logout response:\n  {}", raw_json))
+        jsonp::decode::<SrunPortalResponse>(&raw_text)
+            .with_context(|| "failed to parse logout response")
     }
 
     /// Ensure the client is online by checking connectivity and performing login if needed.
     pub async fn ensure_online(&self) -> Result<()> {
-        match check_connectivity(&self.http_client).await {
-            Ok(None) => {
-                debug!("Client is already online.");
+        let report = self.probe().await;
+        let decided_by = report.decided_by.as_deref().unwrap_or_default();
+        match report.connectivity {
+            Connectivity::Online => {
+                debug!("Client is already online (decided by {}).", decided_by);
+                self.ensure_online_v6().await;
                 return Ok(());
             }
-            Ok(Some(ac_id)) => {
+            Connectivity::Intercepted(ac_id) => {
                 info!(
-                    "Client intercepted (ac_id={}), initiating smart login...",
-                    ac_id
+                    "Client intercepted (ac_id={}, decided by {}), initiating smart login...",
+                    ac_id.as_deref().unwrap_or("unknown"),
+                    decided_by
                 );
             }
-            Err(e) => {
-                info!(
-                    "Network unreachable ({}), waiting for interface to be ready...",
-                    e
-                );
+            Connectivity::Unreachable => {
+                info!("Network unreachable (no probe verdict reached the quorum), waiting for interface to be ready...");
                 tokio::time::sleep(Duration::from_millis(500)).await;
             }
         }
 
-        // Attempt login retries every 0.5s for 5s total
-        for i in 1..=10 {
-            match self.login(true, false).await {
-                Ok(resp) if resp.error == "ok" || resp.error == "ip_already_online_error" => {
-                    tokio::time::sleep(Duration::from_millis(500)).await;
-                    if check_connectivity(&self.http_client).await.is_ok() {
-                        info!("Smart login success (attempt {}).", i);
-                        return Ok(());
-                    }
-                }
-                _ => {
-                    debug!("Login attempt {} failed, retrying in 500ms...", i);
+        let LoginAttempts {
+            result,
+            attempts,
+            exhausted,
+        } = self.retry_login(true, false).await;
+        let context = format!("Failed to ensure online after {} attempts", attempts);
+        // keep the last portal error around for callers to `downcast_ref::<SrunError>()`
+        let err = match result {
+            Ok(resp) => resp.srun_error(),
+            Err(e) => return Err(e.context(context)),
+        };
+        match err {
+            SrunError::Ok | SrunError::IpAlreadyOnline if !exhausted => {
+                info!("Smart login success (attempt {}).", attempts);
+                self.ensure_online_v6().await;
+                Ok(())
+            }
+            SrunError::Ok | SrunError::IpAlreadyOnline => bail!(context),
+            // retrying a wrong password or arrears only risks locking the account
+            err if !exhausted => Err(anyhow::Error::new(err)
+                .context(format!("Login refused (attempt {}), giving up", attempts))),
+            err => Err(anyhow::Error::new(err).context(context)),
+        }
+    }
+
+    /// Race the portal profile's connectivity probes
+    pub async fn probe(&self) -> ProbeReport {
+        probe_connectivity(
+            &self.http_client,
+            &self.portal.connectivity,
+            &self.portal.ac_id,
+        )
+        .await
+    }
+
+    /// Login the IPv6 address if the portal is dual-stack and it has dropped offline
+    async fn ensure_online_v6(&self) {
+        if !self.portal.double_stack {
+            return;
+        }
+        let state = get_login_state_v6(&self.http_client, &self.portal, false).await;
+        if let Ok(Some(state)) = &state {
+            if state.srun_error() == SrunError::Ok {
+                debug!("IPv6 address {} is already online.", state.online_ip);
+                return;
+            }
+        }
+
+        match self.login_v6(true, false).await {
+            Ok(Some(resp)) if resp.srun_error().is_ok() => {
+                info!("IPv6 login success ({}).", resp.online_ip)
+            }
+            Ok(Some(resp)) => warn!("IPv6 login failed: {}", resp.srun_error()),
+            Ok(None) => debug!("No IPv6 address known, skipping IPv6 login."),
+            Err(e) => warn!("IPv6 login failed: {}", e),
+        }
+    }
+
+    /// Whether the portal now sees this device at another IPv4 (or IPv6) address
+    ///
+    /// Only addresses of the same family are compared, so that a portal reporting an IPv4
+    /// address on its `url_v6` does not count as a change on every call.
+    pub async fn addresses_changed(&self) -> bool {
+        if let Ok(state) = get_login_state(&self.http_client, &self.portal, false).await {
+            if state.online_ip.is_ipv6() == self.ip.is_ipv6() && state.online_ip != self.ip {
+                return true;
+            }
+        }
+        if self.portal.double_stack {
+            if let Ok(Some(state)) =
+                get_login_state_v6(&self.http_client, &self.portal, false).await
+            {
+                if state.online_ip.is_ipv6() && Some(state.online_ip) != self.ipv6 {
+                    return true;
                 }
             }
-            tokio::time::sleep(Duration::from_millis(500)).await;
         }
-        bail!("Failed to ensure online after 10 attempts (5s)")
+        false
     }
 
-    async fn get_challenge(&self, _verbose: bool) -> Result<(String, IpAddr)> {
-        let mut request_ip = self.ip;
+    async fn get_challenge(&self, ip: IpAddr, v6: bool, verbose: bool) -> Result<(String, IpAddr)> {
+        let mut request_ip = ip;
         let mut challenge = String::new();
 
         // Try up to 2 times to align with gateway's detected IP
@@ -567,38 +1151,43 @@ impl SrunClient {
                 ("username", self.username.as_str()),
                 ("ip", &ip_str),
             ];
-            let url = format!("{}/cgi-bin/get_challenge", SRUN_PORTAL);
+            let url = match v6 {
+                true => self.portal.endpoint_v6("get_challenge"),
+                false => self.portal.endpoint("get_challenge"),
+            };
 
             debug!(
                 "Challenge Request (attempt {}): {}?{:?}",
                 attempt, url, params
             );
 
-            let resp = self
-                .http_client
-                .get(&url)
-                .query(&params)
-                .send()
-                .await
-                .with_context(|| "failed to get challenge")?;
+            let resp = metrics::timed(
+                "get_challenge",
+                self.http_client.get(&url).query(&params).send(),
+            )
+            .await
+            .with_context(|| "failed to get challenge")?;
             let raw_text = resp.text().await?;
             debug!("Challenge Output (attempt {}): {}", attempt, raw_text);
 
-            if raw_text.len() < 8 {
-                bail!("challenge response too short: `{}`", raw_text)
+            if verbose {
+                println!(
+                    "{} challenge response from portal:\n{}",
+                    "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+                    raw_text.if_supports_color(Stdout, |t| t.dimmed())
+                );
             }
-            let raw_json = &raw_text[6..raw_text.len() - 1];
-            let parsed_json =
-                serde_json::from_str::<SrunChallenge>(raw_json).with_context(|| {
-                    format!(
-                        "failed to parse malformed get_challenge response:\n  {}",
-                        raw_json
-                    )
-                })?;
+
+            let parsed_json = jsonp::decode::<SrunChallenge>(&raw_text)
+                .with_context(|| "failed to parse get_challenge response")?;
 
             challenge = parsed_json.challenge;
 
-            if let Some(detected_ip) = parsed_json.client_ip {
+            // an IPv4 seen by a portal reached over IPv4 says nothing about the IPv6 address
+            let detected_ip = parsed_json
+                .client_ip
+                .filter(|ip| self.fix_ip_mismatch && ip.is_ipv6() == request_ip.is_ipv6());
+            if let Some(detected_ip) = detected_ip {
                 if detected_ip != request_ip {
                     info!("IP Mismatch! Requested: {}, Gateway saw: {}. Re-fetching challenge with correct IP...", request_ip, detected_ip);
                     request_ip = detected_ip;
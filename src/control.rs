@@ -0,0 +1,397 @@
+//! Control socket of the keep-alive daemon, and the client behind `bitsrun ctl`
+//!
+//! The daemon listens on a Unix socket, `$XDG_RUNTIME_DIR/bitsrun/control/control.sock` by default
+//! (or under the state dir if there is no runtime dir), overridden by `control_socket` in the
+//! config file. The socket's dir must be private to the daemon's user, as anyone who can connect
+//! can logout the account. Every connection sends one [`ControlCommand`] as a JSON line, and gets
+//! one [`ControlResponse`] back.
+//!
+//! `status` is answered from the state the daemon last published, so that it does not wait for a
+//! check or login in progress.
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use crate::client::SrunLoginState;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+
+/// What `bitsrun ctl` can ask the daemon to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ControlCommand {
+    /// Print the state cached by the daemon, without querying the portal
+    Status,
+    /// Check connectivity (and login if needed) right away
+    CheckNow,
+    /// Login now, even after the portal refused a login for good
+    Login,
+    /// Logout now, and pause until resumed
+    Logout,
+    /// Stop checking and logging in until resumed
+    Pause,
+    /// Resume checking and logging in
+    Resume,
+    /// Reload the config file
+    Reload,
+}
+
+/// The daemon's answer to a [`ControlCommand`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<DaemonStatus>,
+}
+
+impl ControlResponse {
+    pub fn ok(message: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            message: message.into(),
+            status: None,
+        }
+    }
+
+    pub fn err(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            message: message.into(),
+            status: None,
+        }
+    }
+
+    pub fn with_status(mut self, status: DaemonStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+}
+
+/// A LAN device's state in router mode, as last checked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceSummary {
+    pub name: String,
+    pub ip: IpAddr,
+    pub username: String,
+    pub state: String,
+}
+
+/// What the daemon knows without asking the portal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub username: String,
+    /// Whether the daemon keeps LAN devices online instead of this host
+    pub router: bool,
+    pub paused: bool,
+    /// Set after the portal refused a login for good, until `login` or `reload`
+    pub gave_up: bool,
+    pub poll_interval: u64,
+    /// Unix timestamp of the last check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_check: Option<i64>,
+    /// This host's login state as of the last check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub login_state: Option<SrunLoginState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceSummary>,
+}
+
+/// A command received on the socket, with where to send the response
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub reply: oneshot::Sender<ControlResponse>,
+}
+
+/// The default control socket path
+pub fn default_socket_path() -> Option<PathBuf> {
+    Some(crate::state::runtime_dir()?.join("control/control.sock"))
+}
+
+#[cfg(unix)]
+pub use unix::*;
+
+#[cfg(unix)]
+mod unix {
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::DirBuilderExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    use super::ControlCommand;
+    use super::ControlRequest;
+    use super::ControlResponse;
+    use super::DaemonStatus;
+
+    use anyhow::anyhow;
+    use anyhow::bail;
+    use anyhow::Context;
+    use anyhow::Result;
+    use log::{debug, info};
+    use nix::unistd::geteuid;
+    use tokio::io::AsyncBufReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::io::BufReader;
+    use tokio::net::UnixListener;
+    use tokio::net::UnixStream;
+    use tokio::sync::mpsc;
+    use tokio::sync::oneshot;
+    use tokio::sync::watch;
+
+    /// A bound control socket, removed from the filesystem when dropped
+    #[derive(Debug)]
+    pub struct ControlSocket {
+        pub path: PathBuf,
+        listener: UnixListener,
+    }
+
+    impl Drop for ControlSocket {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    impl ControlSocket {
+        /// Bind the socket, replacing a stale one left behind by a daemon that did not exit cleanly
+        pub async fn bind(path: &Path) -> Result<Self> {
+            let dir = path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            private_dir(dir)?;
+            if path.exists() {
+                if UnixStream::connect(path).await.is_ok() {
+                    bail!(
+                        "another daemon is listening on `{}` already",
+                        path.display()
+                    );
+                }
+                fs::remove_file(path)
+                    .with_context(|| format!("failed to remove stale `{}`", path.display()))?;
+            }
+
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("failed to bind control socket `{}`", path.display()))?;
+            // no one else can reach it through the private dir in the meantime
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+            info!("Listening for `bitsrun ctl` on {}", path.display());
+            Ok(Self {
+                path: path.to_path_buf(),
+                listener,
+            })
+        }
+
+        /// Accept connections forever, answering `status` from `status` and forwarding other
+        /// commands to `requests`
+        pub async fn serve(
+            &self,
+            requests: mpsc::Sender<ControlRequest>,
+            status: watch::Receiver<DaemonStatus>,
+        ) -> Result<()> {
+            loop {
+                let (stream, _) = self.listener.accept().await?;
+                let requests = requests.clone();
+                let status = status.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, requests, status).await {
+                        debug!("Control connection failed: {:#}", e);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Create `dir` for the socket with mode 0700, or make sure an existing one is as private
+    fn private_dir(dir: &Path) -> Result<()> {
+        if let Some(parent) = dir.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create `{}`", parent.display()))?;
+        }
+        match fs::DirBuilder::new().mode(0o700).create(dir) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to create `{}`", dir.display()))
+            }
+        }
+
+        let meta = fs::symlink_metadata(dir)
+            .with_context(|| format!("failed to inspect `{}`", dir.display()))?;
+        if !meta.is_dir() {
+            bail!("`{}` is not a dir", dir.display());
+        }
+        if meta.uid() != geteuid().as_raw() {
+            bail!(
+                "`{}` belongs to another user, refusing to put the control socket there",
+                dir.display()
+            );
+        }
+        if meta.mode() & 0o077 != 0 {
+            bail!(
+                "`{}` is open to other users, refusing to put the control socket there, \
+                set its permissions with `chmod 700 {}`",
+                dir.display(),
+                dir.display()
+            );
+        }
+        Ok(())
+    }
+
+    async fn handle_connection(
+        stream: UnixStream,
+        requests: mpsc::Sender<ControlRequest>,
+        status: watch::Receiver<DaemonStatus>,
+    ) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await?;
+
+        let response = match serde_json::from_str::<ControlCommand>(line.trim()) {
+            Ok(ControlCommand::Status) => {
+                let status = status.borrow().clone();
+                ControlResponse::ok(if status.paused { "paused" } else { "running" })
+                    .with_status(status)
+            }
+            Ok(command) => {
+                debug!("Control command: {:?}", command);
+                let (reply, response) = oneshot::channel();
+                requests
+                    .send(ControlRequest { command, reply })
+                    .await
+                    .map_err(|_| anyhow!("daemon is shutting down"))?;
+                response.await?
+            }
+            Err(e) => ControlResponse::err(format!("invalid command: {}", e)),
+        };
+
+        let mut json = serde_json::to_string(&response)?;
+        json.push('\n');
+        writer.write_all(json.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Send a command to the daemon listening on `path`, and wait for its response
+    pub async fn send_command(path: &Path, command: ControlCommand) -> Result<ControlResponse> {
+        let stream = UnixStream::connect(path).await.with_context(|| {
+            format!(
+                "failed to connect to `{}`, is `bitsrun keep-alive` running?",
+                path.display()
+            )
+        })?;
+        let (reader, mut writer) = stream.into_split();
+
+        let mut json = serde_json::to_string(&command)?;
+        json.push('\n');
+        writer.write_all(json.as_bytes()).await?;
+
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await?;
+        serde_json::from_str(&line).with_context(|| "failed to parse the daemon's response")
+    }
+}
+
+/// Start serving the control socket at `path` in the background, answering `status` from `status`
+///
+/// Returns where its other commands arrive, or `None` where Unix sockets are not supported. The
+/// socket file is removed once the runtime shuts down.
+#[cfg(unix)]
+pub async fn start(
+    path: &std::path::Path,
+    status: watch::Receiver<DaemonStatus>,
+) -> anyhow::Result<Option<mpsc::Receiver<ControlRequest>>> {
+    let socket = ControlSocket::bind(path).await?;
+    let (tx, rx) = mpsc::channel(8);
+    tokio::spawn(async move {
+        if let Err(e) = socket.serve(tx, status).await {
+            log::warn!("Control socket stopped: {}", e);
+        }
+    });
+    Ok(Some(rx))
+}
+
+#[cfg(not(unix))]
+pub async fn send_command(
+    _path: &std::path::Path,
+    _command: ControlCommand,
+) -> anyhow::Result<ControlResponse> {
+    anyhow::bail!("`bitsrun ctl` needs Unix sockets, which are unavailable on this platform")
+}
+
+#[cfg(not(unix))]
+pub async fn start(
+    _path: &std::path::Path,
+    _status: watch::Receiver<DaemonStatus>,
+) -> anyhow::Result<Option<mpsc::Receiver<ControlRequest>>> {
+    log::debug!("Control sockets are only supported on Unix, `bitsrun ctl` is unavailable");
+    Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    use super::send_command;
+    use super::start;
+    use super::ControlCommand;
+    use super::ControlSocket;
+    use super::DaemonStatus;
+
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn answers_status_while_busy_on_a_private_socket() {
+        let dir = std::env::temp_dir().join(format!("bitsrun-control-{}", std::process::id()));
+        let path = dir.join("control.sock");
+        let status = DaemonStatus {
+            username: "alice".to_string(),
+            router: false,
+            paused: true,
+            gave_up: false,
+            poll_interval: 60,
+            last_check: None,
+            login_state: None,
+            devices: Vec::new(),
+        };
+        let (_status_tx, status_rx) = watch::channel(status);
+        // never received from, as if the daemon were stuck on a login
+        let _requests = start(&path, status_rx).await.unwrap().unwrap();
+
+        let mode = |path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&path), 0o600);
+
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            send_command(&path, ControlCommand::Status),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(response.ok);
+        assert_eq!(response.message, "paused");
+        assert_eq!(response.status.unwrap().username, "alice");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn refuses_dirs_open_to_others() {
+        let dir = std::env::temp_dir().join(format!("bitsrun-open-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let err = ControlSocket::bind(&dir.join("control.sock"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("open to other users"));
+        assert!(!dir.join("control.sock").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,28 +1,124 @@
+use crate::alert::AlertConfig;
+use crate::alert::AlertMonitor;
+use crate::bind::BindOptions;
+use crate::client::get_login_state;
 use crate::client::SrunClient;
+use crate::client::SrunLoginState;
+use crate::client::TransportOptions;
 use crate::config;
+use crate::control;
+use crate::control::ControlCommand;
+use crate::control::ControlRequest;
+use crate::control::ControlResponse;
+use crate::control::DaemonStatus;
+use crate::control::DeviceSummary;
+use crate::error::SrunError;
+use crate::failover::runs_out;
+use crate::failover::Account;
+use crate::failover::Failover;
+use crate::lock::DaemonLock;
+use crate::metrics;
 use crate::monitor::start_hardware_monitor;
 use crate::monitor::HardwareEvent;
+use crate::portal::PortalOverrides;
+use crate::portal::PortalProfile;
+use crate::retry::RetryPolicy;
+use crate::router;
+use crate::router::log_device_statuses;
+use crate::router::DeviceState;
+use crate::router::DeviceStatus;
+use crate::router::Router;
+use crate::router::RouterDevice;
+use crate::state::AcIdCache;
+use crate::state::DaemonPhase;
+use crate::state::DaemonState;
+use crate::systemd;
+use crate::usage::UsageSample;
+use crate::usage::UsageStore;
 
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
+use chrono::Local;
+use chrono::Utc;
 use log::{debug, info, warn};
 use owo_colors::OwoColorize;
 use owo_colors::Stream::Stdout;
 use std::fs;
+use std::future::Future;
+use std::iter;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::Duration;
+use std::time::Instant;
+#[cfg(not(unix))]
 use tokio::signal::ctrl_c;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio::time::Interval;
 
-#[derive(serde::Deserialize)]
+#[derive(Debug, Clone, serde::Deserialize)]
 pub struct SrunDaemon {
     pub username: String,
     pub password: String,
     pub dm: bool,
     pub poll_interval: u64,
+    #[serde(default)]
+    pub portal: Option<PortalProfile>,
+    /// Skips `ac_id` discovery if set
+    #[serde(default)]
+    pub ac_id: Option<String>,
+    #[serde(flatten)]
+    pub bind: BindOptions,
+    /// LAN devices to keep online instead of this host (router mode)
+    #[serde(default)]
+    pub devices: Vec<RouterDevice>,
+    /// Accounts to switch to in order when the previous one runs out, see [`crate::failover`]
+    #[serde(default)]
+    pub accounts: Vec<Account>,
+    /// Day of the month billing cycles start on, the 1st if unset
+    #[serde(default)]
+    pub billing_day: Option<u32>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// Low quota and low balance thresholds
+    #[serde(default)]
+    pub alerts: AlertConfig,
+    /// Address to serve Prometheus metrics on
+    #[serde(default)]
+    pub metrics: Option<SocketAddr>,
+    /// Unix socket `bitsrun ctl` talks to, see [`control::default_socket_path`]
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+    /// Reload the config file whenever it changes, not only on SIGHUP
+    #[serde(default)]
+    pub watch_config: bool,
 }
 
-impl SrunDaemon {
-    pub async fn run(config: Option<String>) -> Result<()> {
-        let finalized_cfg = config::validate_config_file(&config)?;
+/// How often the config file is checked for changes with `watch_config`
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Longest a check, refresh or control command may take before it is abandoned, so that a login
+/// stuck on retries, locks and failovers does not hold up the daemon for good
+const MAX_WORK: Duration = Duration::from_secs(4 * 60);
+
+/// How long the daemon may stay busy before the watchdog is no longer pinged, with some leeway
+/// over [`MAX_WORK`] for the work to be abandoned
+const MAX_BUSY: Duration = Duration::from_secs(5 * 60);
+
+/// Where the daemon config comes from, kept around to reload it
+struct ConfigSource {
+    config: Option<String>,
+    overrides: PortalOverrides,
+    bind_overrides: BindOptions,
+}
+
+impl ConfigSource {
+    /// Read the config file, applying the command line overrides
+    fn load(&self) -> Result<(SrunDaemon, PortalProfile)> {
+        let finalized_cfg = config::validate_config_file(&self.config)?;
         let daemon_cfg_str = fs::read_to_string(&finalized_cfg).with_context(|| {
             format!(
                 "failed to read config file `{}`",
@@ -30,88 +126,1271 @@ impl SrunDaemon {
             )
         })?;
 
-        let daemon: SrunDaemon = serde_json::from_str(&daemon_cfg_str)?;
-        let http_client = reqwest::Client::builder()
-            .no_proxy()
-            .connect_timeout(Duration::from_millis(400))
-            .timeout(Duration::from_millis(400))
-            .build()?;
-
-        let mut srun = SrunClient::new(
-            daemon.username.clone(),
-            daemon.password.clone(),
-            Some(http_client.clone()),
-            None,
-            Some(daemon.dm),
-        )
-        .await?;
+        let mut daemon: SrunDaemon = serde_json::from_str(&daemon_cfg_str).with_context(|| {
+            format!(
+                "failed to parse config file `{}`",
+                finalized_cfg.if_supports_color(Stdout, |t: &String| t.underline())
+            )
+        })?;
+        if let Some(day) = daemon.billing_day.filter(|day| !(1..=31).contains(day)) {
+            bail!("`billing_day` must be between 1 and 31, not {}", day);
+        }
+        let portal = PortalProfile::finalize(daemon.portal.clone(), &self.overrides)?;
+        daemon.bind = BindOptions::finalize(Some(daemon.bind.clone()), &self.bind_overrides)?;
+        router::finalize_portals(&mut daemon.devices)?;
+        Ok((daemon, portal))
+    }
+}
+
+impl SrunDaemon {
+    pub async fn run(
+        config: Option<String>,
+        overrides: PortalOverrides,
+        bind_overrides: BindOptions,
+    ) -> Result<()> {
+        let source = ConfigSource {
+            config,
+            overrides,
+            bind_overrides,
+        };
+        let (daemon, portal) = source.load()?;
+        // held until the daemon exits
+        let _lock = DaemonLock::acquire()?;
+
+        if let Some(listen) = daemon.metrics {
+            let listener = metrics::bind(listen).await?;
+            tokio::spawn(metrics::serve(listener));
+        }
+        let control_socket = daemon
+            .control_socket
+            .clone()
+            .or_else(control::default_socket_path);
 
-        let poll_interval = if daemon.poll_interval == 0 {
-            3600
+        // discovery may take a while on a flaky network, do not hold up a shutdown for it
+        let shutdown = shutdown_signals();
+        let keeper = if daemon.devices.is_empty() {
+            let host = HostKeeper::new(daemon, portal);
+            match until_shutdown(&shutdown, host).await {
+                Some(host) => Keeper::Host(Box::new(host?)),
+                None => {
+                    let signal = shutdown.borrow().unwrap_or("shutdown");
+                    info!("Received {} while starting up, exiting...", signal);
+                    return Ok(());
+                }
+            }
         } else {
-            daemon.poll_interval
+            Keeper::Router(Box::new(RouterKeeper::new(daemon, portal)?))
         };
-        let mut srun_ticker = tokio::time::interval(Duration::from_secs(poll_interval));
-        let mut hardware_events = start_hardware_monitor();
 
-        info!(
-            "Starting smart daemon for {} (interval={}s)",
-            daemon.username, poll_interval
-        );
+        // bound last, as the socket file is only removed on a clean exit
+        let (status_tx, status_rx) = watch::channel(keeper.status());
+        let control_rx = match control_socket {
+            Some(path) => control::start(&path, status_rx).await?,
+            None => {
+                warn!("No control socket path, `bitsrun ctl` is unavailable");
+                None
+            }
+        };
+        keeper.run(source, control_rx, status_tx, shutdown).await
+    }
+
+    /// Seconds between scheduled checks, an hour if unset
+    pub fn poll_interval(&self) -> u64 {
+        match self.poll_interval {
+            0 => 3600,
+            interval => interval,
+        }
+    }
+
+    /// All accounts in the order they are failed over to, starting with the top-level one
+    pub fn failover(&self) -> Failover {
+        let account = Account {
+            username: self.username.clone(),
+            password: self.password.clone(),
+        };
+        Failover::new(
+            iter::once(account).chain(self.accounts.clone()).collect(),
+            self.billing_day.unwrap_or(1),
+        )
+    }
 
+    /// Discover the current IP (v4 and v6) and ac_id with a fresh srun client for `account`
+    async fn discover(
+        &self,
+        http_client: reqwest::Client,
+        portal: &PortalProfile,
+        account: &Account,
+    ) -> Result<SrunClient> {
+        let mut builder = SrunClient::builder(&account.username, &account.password)
+            .http_client(http_client)
+            .portal(portal.clone())
+            .bind(self.bind.clone())
+            .retry(self.retry.clone())
+            .dm(self.dm);
+        if let Some(ac_id) = &self.ac_id {
+            builder = builder.ac_id(ac_id);
+        }
+        // reloaded on every discovery, as the network may have changed
+        if let Some(cache) = AcIdCache::load() {
+            builder = builder.ac_id_cache(cache);
+        }
+        builder.build().await
+    }
+}
+
+/// What the daemon keeps online: this host, or LAN devices in router mode
+enum Keeper {
+    Host(Box<HostKeeper>),
+    Router(Box<RouterKeeper>),
+}
+
+impl Keeper {
+    fn daemon(&self) -> &SrunDaemon {
+        match self {
+            Keeper::Host(host) => &host.daemon,
+            Keeper::Router(router) => &router.daemon,
+        }
+    }
+
+    fn failover(&self) -> &Failover {
+        match self {
+            Keeper::Host(host) => &host.failover,
+            Keeper::Router(router) => &router.failover,
+        }
+    }
+
+    /// Carry on with the account the last daemon failed over to, if any
+    fn restore_failover(&mut self, previous: &DaemonState) {
+        let restored = match self {
+            Keeper::Host(host) => host
+                .failover
+                .restore(&previous.username, previous.switched_on),
+            Keeper::Router(router) => {
+                let restored = router
+                    .failover
+                    .restore(&previous.username, previous.switched_on);
+                router.use_account();
+                restored
+            }
+        };
+        if restored {
+            info!(
+                "Carrying on with {}, failed over to before the restart",
+                previous.username
+            );
+        }
+    }
+
+    /// Check and login as needed until SIGTERM or SIGINT, serving control commands meanwhile
+    async fn run(
+        mut self,
+        source: ConfigSource,
+        mut control_rx: Option<mpsc::Receiver<ControlRequest>>,
+        status_tx: watch::Sender<DaemonStatus>,
+        shutdown: watch::Receiver<Option<&'static str>>,
+    ) -> Result<()> {
+        let mut poll_interval = self.daemon().poll_interval();
+        let mut srun_ticker = ticker(poll_interval);
+        // devices behind a router are not affected by this host's network changes
+        let mut hardware_events = match &self {
+            Keeper::Host(_) => Some(start_hardware_monitor()),
+            Keeper::Router(_) => None,
+        };
+        let mut reloads = reload_triggers(&source, self.daemon().watch_config);
+        let mut checks = check_triggers();
+        let busy = start_watchdog();
+        let mut paused = false;
+        let previous = DaemonState::load().unwrap_or_else(|e| {
+            warn!("Starting over with the daemon state: {:#}", e);
+            None
+        });
+        if let Some(previous) = &previous {
+            self.restore_failover(previous);
+        }
+        let router = matches!(self, Keeper::Router(_));
+        let mut daemon_state = DaemonState::start(&self.status().username, router, previous);
+
+        match &self {
+            Keeper::Host(host) => info!(
+                "Starting smart daemon for {} (interval={}s)",
+                host.daemon.username, poll_interval
+            ),
+            Keeper::Router(router) => info!(
+                "Starting router daemon for {} devices (interval={}s)",
+                router.daemon.devices.len(),
+                poll_interval
+            ),
+        }
+        systemd::ready();
+
+        // work in the branches below is raced against shutdown, so that a signal cancels its
+        // in-flight portal requests instead of waiting for them
         loop {
+            systemd::status(&self.summary(paused));
+            // answers `bitsrun ctl status` while the branches below are busy
+            let mut status = self.status();
+            status.paused = paused;
+            status_tx.send_replace(status);
+            if let Some(state) = &mut daemon_state {
+                self.save_state(state, paused);
+            }
+            if self.daemon().poll_interval() != poll_interval {
+                poll_interval = self.daemon().poll_interval();
+                srun_ticker = ticker(poll_interval);
+                info!("Polling every {}s from now on", poll_interval);
+            }
             tokio::select! {
                 _ = srun_ticker.tick() => {
-                    if !crate::client::is_on_campus(&http_client).await {
-                        debug!("Not on campus, skipping keep-alive tick.");
+                    if paused {
+                        debug!("Paused, skipping keep-alive tick.");
                         continue;
                     }
-                    debug!("Scheduled keep-alive check...");
-                    let _ = srun.ensure_online().await;
+                    let checked = bounded(&busy, "The check", self.tick());
+                    if until_shutdown(&shutdown, checked).await.is_none() {
+                        break;
+                    }
                 }
-                event = hardware_events.recv() => {
-                    if let Some(HardwareEvent::Refresh) = event {
-                        info!("Hardware event received, checking network...");
-
-                        // Re-create the http_client to clear all connection pools/cache
-                        let new_http_client = reqwest::Client::builder()
-                            .no_proxy()
-                            .connect_timeout(Duration::from_millis(400))
-                            .timeout(Duration::from_millis(400))
-                            .build()
-                            .unwrap_or(http_client.clone());
-
-                        if !crate::client::is_on_campus(&new_http_client).await {
-                            debug!("Not on campus after hardware event, skipping refresh.");
-                            continue;
+                Some(HardwareEvent::Refresh) = recv(&mut hardware_events) => {
+                    if let (false, Keeper::Host(host)) = (paused, &mut self) {
+                        let refreshed = bounded(&busy, "The refresh", host.refresh());
+                        if until_shutdown(&shutdown, refreshed).await.is_none() {
+                            break;
                         }
+                    }
+                }
+                Some(trigger) = checks.recv() => {
+                    info!("Checking now, as asked by {}", trigger);
+                    let checked = bounded(&busy, "The check", self.tick());
+                    if until_shutdown(&shutdown, checked).await.is_none() {
+                        break;
+                    }
+                }
+                Some(request) = recv(&mut control_rx) => {
+                    let command = request.command;
+                    let handled = self.control(command, &mut paused, &source);
+                    let handled = bounded(&busy, "The control command", handled);
+                    let Some(response) = until_shutdown(&shutdown, handled).await else {
+                        let _ = request.reply.send(ControlResponse::err("daemon is shutting down"));
+                        break;
+                    };
+                    let response = response.unwrap_or_else(|| {
+                        ControlResponse::err(format!("timed out after {}s", MAX_WORK.as_secs()))
+                    });
+                    if command == ControlCommand::Resume {
+                        srun_ticker.reset_immediately();
+                    }
+                    let mut status = self.status();
+                    status.paused = paused;
+                    // the client may have hung up already
+                    let _ = request.reply.send(response.with_status(status));
+                }
+                Some(trigger) = reloads.recv() => {
+                    // the old config stays in effect on failure, which is logged already
+                    let _ = self.reload(&source, trigger);
+                }
+                _ = shut_down(shutdown.clone()) => break,
+            }
+        }
 
-                        info!("On campus, refreshing client context...");
-                        // Re-instantiate srun client to pick up the most accurate IP and ac_id for the current interface
-                        match SrunClient::new(
-                            daemon.username.clone(),
-                            daemon.password.clone(),
-                            Some(new_http_client),
-                            None,
-                            Some(daemon.dm),
-                        ).await {
-                            Ok(new_srun) => {
-                                info!("Network discovery successful, applying new context.");
-                                srun = new_srun;
-                                let _ = srun.ensure_online().await;
-                            }
-                            Err(e) => {
-                                warn!("Network discovery failed: {}. This is expected during interface switching. Retrying later...", e);
-                            }
-                        }
+        let signal = shutdown.borrow().unwrap_or("shutdown");
+        info!("Received {}, gracefully exiting...", signal);
+        systemd::stopping();
+        if let Some(state) = &mut daemon_state {
+            state.transition(DaemonPhase::Stopped, format!("stopped by {}", signal));
+        }
+        Ok(())
+    }
+
+    /// Carry out a command from `bitsrun ctl`
+    async fn control(
+        &mut self,
+        command: ControlCommand,
+        paused: &mut bool,
+        source: &ConfigSource,
+    ) -> ControlResponse {
+        match command {
+            ControlCommand::Status => {
+                ControlResponse::ok(if *paused { "paused" } else { "running" })
+            }
+            ControlCommand::CheckNow => {
+                self.tick().await;
+                ControlResponse::ok("checked")
+            }
+            ControlCommand::Login => self.login().await,
+            ControlCommand::Logout => {
+                let response = self.logout().await;
+                if response.ok {
+                    *paused = true;
+                }
+                response
+            }
+            ControlCommand::Pause => {
+                *paused = true;
+                info!("Paused by `bitsrun ctl`");
+                ControlResponse::ok("paused")
+            }
+            ControlCommand::Resume => {
+                *paused = false;
+                info!("Resumed by `bitsrun ctl`");
+                ControlResponse::ok("resumed")
+            }
+            ControlCommand::Reload => match self.reload(source, "`bitsrun ctl`") {
+                Ok(()) => ControlResponse::ok("reloaded"),
+                Err(e) => ControlResponse::err(format!("failed to reload: {:#}", e)),
+            },
+        }
+    }
+
+    /// One line on what the daemon is up to, for `systemctl status`
+    fn summary(&self, paused: bool) -> String {
+        let status = self.status();
+        let state = match self {
+            _ if paused => "paused".to_string(),
+            Keeper::Host(_) if status.gave_up => {
+                "login refused, waiting for `bitsrun ctl login`".to_string()
+            }
+            Keeper::Host(host) if host.consecutive_failures > 0 => format!(
+                "failing to login ({} in a row): {}",
+                host.consecutive_failures,
+                host.last_error.as_deref().unwrap_or_default()
+            ),
+            Keeper::Host(host) => match &host.last_state {
+                Some(state) if state.srun_error() == SrunError::Ok => {
+                    format!("online at {}", state.online_ip)
+                }
+                Some(state) => format!("offline at {}", state.online_ip),
+                None if host.last_check.is_none() => "starting".to_string(),
+                None => "portal unreachable".to_string(),
+            },
+            Keeper::Router(_) => {
+                let online = status
+                    .devices
+                    .iter()
+                    .filter(|device| matches!(device.state.as_str(), "online" | "logged in"))
+                    .count();
+                format!("{}/{} devices online", online, status.devices.len())
+            }
+        };
+        format!("{}: {}", status.username, state)
+    }
+
+    fn phase(&self, paused: bool) -> DaemonPhase {
+        match self {
+            _ if paused => DaemonPhase::Paused,
+            Keeper::Host(host) if host.gave_up => DaemonPhase::GaveUp,
+            Keeper::Host(host) if host.consecutive_failures > 0 => DaemonPhase::Failing,
+            Keeper::Host(host) => match &host.last_state {
+                Some(state) if state.srun_error() == SrunError::Ok => DaemonPhase::Online,
+                Some(_) => DaemonPhase::Offline,
+                None if host.last_check.is_none() => DaemonPhase::Starting,
+                None => DaemonPhase::Offline,
+            },
+            Keeper::Router(router) if router.consecutive_failures > 0 => DaemonPhase::Failing,
+            Keeper::Router(router) if router.last_check.is_none() => DaemonPhase::Starting,
+            Keeper::Router(router) => {
+                let all_online = router.last_statuses.iter().all(|status| {
+                    matches!(status.state, DeviceState::Online(_) | DeviceState::LoggedIn)
+                });
+                match all_online {
+                    true => DaemonPhase::Online,
+                    false => DaemonPhase::Offline,
+                }
+            }
+        }
+    }
+
+    /// Update the persistent state, saving it if anything but the time of the last check changed
+    fn save_state(&self, state: &mut DaemonState, paused: bool) {
+        let (ip, ac_id, consecutive_failures, last_error) = match self {
+            Keeper::Host(host) => (
+                Some(host.srun.ip),
+                Some(host.srun.ac_id.clone()),
+                host.consecutive_failures,
+                host.last_error.clone(),
+            ),
+            Keeper::Router(router) => (
+                None,
+                router.router.ac_id(),
+                router.consecutive_failures,
+                router.last_error.clone(),
+            ),
+        };
+        let status = self.status();
+        let last_login = metrics::last_login().or(state.last_login);
+        let phase = self.phase(paused);
+
+        let logged_in = last_login != state.last_login;
+        // a fresh state has no transition of its own yet
+        let changed = state.updated_at < state.started_at
+            || logged_in
+            || status.username != state.username
+            || phase != state.phase
+            || ip != state.ip
+            || ac_id != state.ac_id
+            || consecutive_failures != state.consecutive_failures
+            || last_error != state.last_error;
+        // a check that changed nothing is saved as well, so that `last_check` stays current
+        let checked = status.last_check != state.last_check;
+        state.last_check = status.last_check;
+        state.username = status.username;
+        state.switched_on = self.failover().switched_on;
+        state.last_login = last_login;
+        state.ip = ip;
+        state.ac_id = ac_id;
+        state.consecutive_failures = consecutive_failures;
+        state.last_error = last_error;
+        if changed {
+            let mut summary = self.summary(paused);
+            if logged_in {
+                summary.push_str(" (logged in)");
+            }
+            state.transition(phase, summary);
+        } else if checked {
+            state.persist();
+        }
+    }
+
+    async fn tick(&mut self) {
+        match self {
+            Keeper::Host(host) => host.tick().await,
+            Keeper::Router(router) => router.tick().await,
+        }
+    }
+
+    async fn login(&mut self) -> ControlResponse {
+        match self {
+            Keeper::Host(host) => host.login().await,
+            Keeper::Router(_) => ControlResponse::err("not supported in router mode"),
+        }
+    }
+
+    async fn logout(&mut self) -> ControlResponse {
+        match self {
+            Keeper::Host(host) => host.logout().await,
+            Keeper::Router(_) => ControlResponse::err("not supported in router mode"),
+        }
+    }
+
+    /// Reload the config file as asked by `trigger`, logging the outcome
+    ///
+    /// The new config replaces the old one only once it is read, parsed and applied in full.
+    ///
+    /// Nothing is sent to the portal, the new config is picked up by the next check.
+    fn reload(&mut self, source: &ConfigSource, trigger: &str) -> Result<()> {
+        info!("Reloading the config file, as asked by {}", trigger);
+        match self.try_reload(source) {
+            Ok(()) => {
+                info!("Reloaded the config file");
+                Ok(())
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to reload the config file, keeping the old one: {:#}",
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Re-read the config file, keeping what only applies at startup
+    fn try_reload(&mut self, source: &ConfigSource) -> Result<()> {
+        let (mut daemon, portal) = source.load()?;
+        let current = self.daemon();
+        if daemon.devices.is_empty() != current.devices.is_empty() {
+            bail!("switching between host and router mode needs a restart");
+        }
+        if daemon.metrics != current.metrics {
+            warn!("Changing `metrics` needs a restart, keeping the current endpoint");
+        }
+        if daemon.control_socket != current.control_socket {
+            warn!("Changing `control_socket` needs a restart, keeping the current socket");
+        }
+        if daemon.watch_config != current.watch_config {
+            warn!("Changing `watch_config` needs a restart");
+        }
+        daemon.metrics = current.metrics;
+        daemon.control_socket = current.control_socket.clone();
+        daemon.watch_config = current.watch_config;
+        match self {
+            Keeper::Host(host) => host.reload(daemon, portal),
+            Keeper::Router(router) => router.reload(daemon, portal),
+        }
+    }
+
+    fn status(&self) -> DaemonStatus {
+        match self {
+            Keeper::Host(host) => DaemonStatus {
+                username: host.failover.active().username.clone(),
+                router: false,
+                paused: false,
+                gave_up: host.gave_up,
+                poll_interval: host.daemon.poll_interval(),
+                last_check: host.last_check,
+                login_state: host.last_state.clone(),
+                devices: Vec::new(),
+            },
+            Keeper::Router(router) => DaemonStatus {
+                username: router.failover.active().username.clone(),
+                router: true,
+                paused: false,
+                gave_up: false,
+                poll_interval: router.daemon.poll_interval(),
+                last_check: router.last_check,
+                login_state: None,
+                devices: router
+                    .last_statuses
+                    .iter()
+                    .map(|status| DeviceSummary {
+                        name: status.device.name(),
+                        ip: status.device.ip,
+                        username: status.username.clone(),
+                        state: status.state.to_string(),
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
+
+fn ticker(poll_interval: u64) -> Interval {
+    tokio::time::interval(Duration::from_secs(poll_interval))
+}
+
+/// Start listening for what asks to reload the config file: SIGHUP (on Unix), and changes to
+/// the file itself if `watch` is set
+///
+/// Each trigger arrives as a description for the logs.
+fn reload_triggers(source: &ConfigSource, watch: bool) -> mpsc::Receiver<&'static str> {
+    let (tx, rx) = mpsc::channel(4);
+    #[cfg(unix)]
+    forward_signal(SignalKind::hangup(), "SIGHUP", tx.clone());
+
+    if watch {
+        match config::validate_config_file(&source.config) {
+            Ok(path) => {
+                info!("Watching `{}` for changes", path);
+                tokio::spawn(watch_config(path.into(), tx));
+            }
+            Err(e) => warn!("Not watching the config file: {:#}", e),
+        }
+    }
+    rx
+}
+
+/// Start listening for what asks for an immediate check: SIGUSR1 (on Unix)
+fn check_triggers() -> mpsc::Receiver<&'static str> {
+    let (tx, rx) = mpsc::channel(4);
+    #[cfg(unix)]
+    forward_signal(SignalKind::user_defined1(), "SIGUSR1", tx);
+    #[cfg(not(unix))]
+    drop(tx);
+    rx
+}
+
+/// Forward every `kind` signal to `tx` as `name`, in the background
+#[cfg(unix)]
+fn forward_signal(kind: SignalKind, name: &'static str, tx: mpsc::Sender<&'static str>) {
+    match signal(kind) {
+        Ok(mut signals) => {
+            tokio::spawn(async move {
+                while signals.recv().await.is_some() {
+                    if tx.send(name).await.is_err() {
+                        break;
                     }
                 }
-                _ = ctrl_c() => {
-                    info!("Gracefully exiting...");
-                    break;
+            });
+        }
+        Err(e) => warn!("Failed to listen for {}: {}", name, e),
+    }
+}
+
+/// Start listening for SIGTERM and SIGINT (only Ctrl-C off Unix), which end the daemon
+///
+/// The receiver holds the name of the first signal received.
+fn shutdown_signals() -> watch::Receiver<Option<&'static str>> {
+    let (tx, rx) = watch::channel(None);
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let signal = {
+            let (mut terms, mut ints) = match (
+                signal(SignalKind::terminate()),
+                signal(SignalKind::interrupt()),
+            ) {
+                (Ok(terms), Ok(ints)) => (terms, ints),
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!("Failed to listen for SIGTERM and SIGINT: {}", e);
+                    return;
                 }
+            };
+            tokio::select! {
+                _ = terms.recv() => "SIGTERM",
+                _ = ints.recv() => "SIGINT",
             }
+        };
+        #[cfg(not(unix))]
+        let signal = match ctrl_c().await {
+            Ok(()) => "Ctrl-C",
+            Err(e) => {
+                warn!("Failed to listen for Ctrl-C: {}", e);
+                return;
+            }
+        };
+        let _ = tx.send(Some(signal));
+    });
+    rx
+}
+
+/// Run `work` to completion, unless a shutdown signal arrives first
+///
+/// Returns `None` on shutdown, after dropping `work` along with its in-flight requests.
+async fn until_shutdown<T>(
+    shutdown: &watch::Receiver<Option<&'static str>>,
+    work: impl Future<Output = T>,
+) -> Option<T> {
+    tokio::select! {
+        output = work => Some(output),
+        _ = shut_down(shutdown.clone()) => None,
+    }
+}
+
+/// Wait for a shutdown signal, or forever if signals could not be listened for
+async fn shut_down(mut shutdown: watch::Receiver<Option<&'static str>>) {
+    if shutdown.wait_for(Option::is_some).await.is_err() {
+        std::future::pending().await
+    }
+}
+
+/// Run `work`, abandoning it after [`MAX_WORK`], and marking the daemon `busy` meanwhile
+async fn bounded<T>(
+    busy: &watch::Sender<Option<Instant>>,
+    what: &str,
+    work: impl Future<Output = T>,
+) -> Option<T> {
+    busy.send_replace(Some(Instant::now()));
+    let output = tokio::time::timeout(MAX_WORK, work).await;
+    busy.send_replace(None);
+    match output {
+        Ok(output) => Some(output),
+        Err(_) => {
+            warn!(
+                "{} took longer than {}s, abandoned it",
+                what,
+                MAX_WORK.as_secs()
+            );
+            None
+        }
+    }
+}
+
+/// Ping the systemd watchdog from a task of its own, if it is enabled, for as long as the
+/// daemon has not been busy for longer than [`MAX_BUSY`]
+///
+/// The loop itself may wait on the portal for minutes, so it cannot ping in time. The daemon
+/// marks itself busy through the returned sender, see [`bounded`].
+fn start_watchdog() -> watch::Sender<Option<Instant>> {
+    let (tx, rx) = watch::channel(None::<Instant>);
+    let Some(interval) = systemd::watchdog_interval() else {
+        return tx;
+    };
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if rx.has_changed().is_err() {
+                break;
+            }
+            let busy = *rx.borrow();
+            match busy.map(|since| since.elapsed()) {
+                Some(busy) if busy > MAX_BUSY => {
+                    warn!("Busy for {}s, leaving it to the watchdog", busy.as_secs())
+                }
+                _ => {
+                    systemd::watchdog();
+                }
+            }
+        }
+    });
+    tx
+}
+
+/// Poll the modification time of the config file, sending a trigger whenever it changes
+async fn watch_config(path: PathBuf, tx: mpsc::Sender<&'static str>) {
+    let modified = |path: &PathBuf| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    let mut last = modified(&path);
+    let mut ticker = tokio::time::interval(CONFIG_WATCH_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let current = modified(&path);
+        // editors may replace the file, leaving it missing for a moment
+        if current.is_none() || current == last {
+            continue;
+        }
+        last = current;
+        debug!("`{}` changed", path.display());
+        if tx.send("a change to the config file").await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Receive from a channel, or never if there is none
+async fn recv<T>(rx: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Keeps this host online
+struct HostKeeper {
+    daemon: SrunDaemon,
+    portal: PortalProfile,
+    transport: TransportOptions,
+    http_client: reqwest::Client,
+    srun: SrunClient,
+    failover: Failover,
+    /// Set once the portal refuses the login for good, retrying could lock the account
+    gave_up: bool,
+    /// Set when the config was reloaded since `srun` was discovered
+    reloaded: bool,
+    /// Set when a new billing cycle started, until the account failed over to is logged out
+    switching_back: bool,
+    alerts: AlertMonitor,
+    usage: Option<UsageStore>,
+    /// Unix timestamp of the last check
+    last_check: Option<i64>,
+    last_state: Option<SrunLoginState>,
+    /// Failed `ensure_online`s in a row, and the last one's error
+    consecutive_failures: u32,
+    last_error: Option<String>,
+}
+
+impl HostKeeper {
+    async fn new(daemon: SrunDaemon, portal: PortalProfile) -> Result<Self> {
+        let transport = TransportOptions {
+            bind: daemon.bind.clone(),
+            ..Default::default()
+        };
+        let http_client = transport.build_client()?;
+        let failover = daemon.failover();
+        let srun = daemon
+            .discover(http_client.clone(), &portal, failover.active())
+            .await?;
+        Ok(Self {
+            alerts: AlertMonitor::new(daemon.alerts.clone()),
+            usage: UsageStore::open(),
+            daemon,
+            portal,
+            transport,
+            http_client,
+            srun,
+            failover,
+            gave_up: false,
+            reloaded: false,
+            switching_back: false,
+            last_check: None,
+            last_state: None,
+            consecutive_failures: 0,
+            last_error: None,
+        })
+    }
+
+    /// Scheduled check, logging in again if offline
+    async fn tick(&mut self) {
+        if self.failover.renew(Local::now().date_naive()) {
+            info!(
+                "A new billing cycle started, switching back to {}",
+                self.failover.active().username
+            );
+            self.gave_up = false;
+            self.switching_back = true;
+        }
+        if self.gave_up {
+            debug!("Login was refused permanently, skipping keep-alive tick.");
+            return;
+        }
+        if !crate::client::is_on_campus(&self.http_client, &self.portal).await {
+            debug!("Not on campus, skipping keep-alive tick.");
+            return;
+        }
+        // the account changed if the last switch could not discover with the new one
+        let switched = self.srun.username != self.failover.active().username;
+        // a cached ac_id is evicted when a login with it fails, discover it again
+        let cached_ac_id_failed =
+            self.consecutive_failures > 0 && self.srun.ac_id_cached_for.is_some();
+        if switched || cached_ac_id_failed || self.reloaded || self.srun.addresses_changed().await {
+            info!("IP address, account, ac_id or config changed, refreshing client context...");
+            match self.discover(self.http_client.clone()).await {
+                Ok(new_srun) => self.use_client(new_srun),
+                Err(e) => {
+                    warn!("Network discovery failed: {}. Retrying later...", e);
+                    return;
+                }
+            }
+        }
+        if self.switching_back {
+            match self.reclaim().await {
+                Ok(()) => self.switching_back = false,
+                Err(e) => warn!("{:#}. Retrying on the next check...", e),
+            }
+        }
+        debug!("Scheduled keep-alive check...");
+        let result = self.srun.ensure_online().await;
+        let result = self.fail_over(result).await;
+        self.record(result);
+        self.observe().await;
+    }
+
+    /// Network changed, rediscover and login again if needed
+    async fn refresh(&mut self) {
+        if self.gave_up {
+            return;
+        }
+        info!("Hardware event received, checking network...");
+
+        // Re-create the http_client to clear all connection pools/cache
+        let new_http_client = self
+            .transport
+            .build_client()
+            .unwrap_or(self.http_client.clone());
+
+        if !crate::client::is_on_campus(&new_http_client, &self.portal).await {
+            debug!("Not on campus after hardware event, skipping refresh.");
+            return;
+        }
+
+        info!("On campus, refreshing client context...");
+        // Re-instantiate srun client to pick up the most accurate IP and ac_id for the current interface
+        match self.discover(new_http_client).await {
+            Ok(new_srun) => {
+                info!("Network discovery successful, applying new context.");
+                self.use_client(new_srun);
+                let result = self.srun.ensure_online().await;
+                let result = self.fail_over(result).await;
+                self.record(result);
+            }
+            Err(e) => {
+                warn!("Network discovery failed: {}. This is expected during interface switching. Retrying later...", e);
+            }
+        }
+    }
+
+    /// Switch to the next account and login again for as long as the active one runs out
+    async fn fail_over(&mut self, mut result: Result<()>) -> Result<()> {
+        loop {
+            let cause = match &result {
+                Err(e) if e.downcast_ref::<SrunError>().is_some_and(runs_out) => e.root_cause(),
+                _ => return result,
+            };
+            let previous = self.failover.active().username.clone();
+            let Some(next) = self.failover.fail_over() else {
+                return result;
+            };
+            warn!(
+                "{} ran out ({}), switching to {}",
+                previous, cause, next.username
+            );
+            self.rediscover().await?;
+            result = self.srun.ensure_online().await;
+        }
+    }
+
+    /// Log and keep track of the outcome of `ensure_online`
+    fn record(&mut self, result: Result<()>) {
+        match &result {
+            Ok(()) => {
+                self.consecutive_failures = 0;
+                self.last_error = None;
+            }
+            Err(e) => {
+                self.consecutive_failures += 1;
+                self.last_error = Some(format!("{:#}", e));
+            }
+        }
+        self.gave_up = log_ensure_online(result, &self.daemon.retry);
+    }
+
+    /// Login now, clearing a previous refusal
+    async fn login(&mut self) -> ControlResponse {
+        self.gave_up = false;
+        let response = match self.rediscover().await {
+            Ok(()) => portal_response(self.srun.login_with_retry(true, false).await, "logged in"),
+            Err(e) => ControlResponse::err(format!("{:#}", e)),
+        };
+        self.observe().await;
+        response
+    }
+
+    async fn logout(&mut self) -> ControlResponse {
+        let response = match self.rediscover().await {
+            Ok(()) => {
+                let response = portal_response(self.srun.logout(true, false).await, "logged out");
+                match self.srun.logout_v6(true, false).await {
+                    Ok(Some(resp)) if !resp.srun_error().is_ok() => {
+                        warn!("IPv6 logout failed: {}", resp.srun_error())
+                    }
+                    Err(e) => warn!("IPv6 logout failed: {}", e),
+                    _ => {}
+                }
+                response
+            }
+            Err(e) => ControlResponse::err(format!("{:#}", e)),
+        };
+        self.observe().await;
+        response
+    }
+
+    /// Logout the account failed over to if it is still online, so that the active one logs in
+    async fn reclaim(&self) -> Result<()> {
+        let state = &self.srun.login_state;
+        let Some(other) = state
+            .user_name
+            .clone()
+            .filter(|other| state.srun_error() == SrunError::Ok && *other != self.srun.username)
+        else {
+            return Ok(());
+        };
+        info!(
+            "{} is still online, logging it out to login as {}",
+            other, self.srun.username
+        );
+        let client = SrunClient {
+            username: other.clone(),
+            ..self.srun.clone()
+        };
+        let err = client
+            .logout(true, false)
+            .await
+            .with_context(|| format!("failed to logout {}", other))?
+            .srun_error();
+        if !err.is_ok() {
+            bail!("failed to logout {}: {}", other, err);
+        }
+        match client.logout_v6(false, false).await {
+            Ok(Some(resp)) if !resp.srun_error().is_ok() => {
+                warn!("IPv6 logout failed: {}", resp.srun_error())
+            }
+            // not online over IPv6 at all, or not dual-stack
+            Ok(_) | Err(_) => {}
         }
         Ok(())
     }
+
+    /// Refresh the srun client, whose login state may be as old as the last discovery
+    async fn rediscover(&mut self) -> Result<()> {
+        let srun = self.discover(self.http_client.clone()).await?;
+        self.use_client(srun);
+        Ok(())
+    }
+
+    /// Use a freshly discovered srun client from now on
+    fn use_client(&mut self, srun: SrunClient) {
+        self.srun = srun;
+        self.reloaded = false;
+    }
+
+    /// Discover with the active account
+    async fn discover(&self, http_client: reqwest::Client) -> Result<SrunClient> {
+        self.daemon
+            .discover(http_client, &self.portal, self.failover.active())
+            .await
+    }
+
+    /// Apply a reloaded config, rediscovering with it on the next check
+    fn reload(&mut self, daemon: SrunDaemon, portal: PortalProfile) -> Result<()> {
+        let transport = TransportOptions {
+            bind: daemon.bind.clone(),
+            ..Default::default()
+        };
+        let http_client = transport.build_client()?;
+        self.failover.reload(daemon.failover());
+        self.reloaded = true;
+        self.alerts.config = daemon.alerts.clone();
+        self.transport = transport;
+        self.http_client = http_client;
+        self.daemon = daemon;
+        self.portal = portal;
+        self.gave_up = false;
+        Ok(())
+    }
+
+    async fn observe(&mut self) {
+        self.last_check = Some(Utc::now().timestamp());
+        self.last_state = observe_login_state(
+            &mut self.alerts,
+            self.usage.as_ref(),
+            &self.http_client,
+            &self.portal,
+            &self.failover.active().username,
+        )
+        .await;
+    }
+}
+
+/// Keeps the configured LAN devices online, re-logging in any that drop
+struct RouterKeeper {
+    daemon: SrunDaemon,
+    router: Router,
+    failover: Failover,
+    alerts: AlertMonitor,
+    usage: Option<UsageStore>,
+    /// Unix timestamp of the last check
+    last_check: Option<i64>,
+    last_statuses: Vec<DeviceStatus>,
+    /// Set when a new billing cycle started, until the devices are logged out of the account
+    /// failed over to
+    switching_back: bool,
+    /// Checks in a row where some device failed, and the last failure
+    consecutive_failures: u32,
+    last_error: Option<String>,
+}
+
+impl RouterKeeper {
+    fn new(daemon: SrunDaemon, portal: PortalProfile) -> Result<Self> {
+        let failover = daemon.failover();
+        Ok(Self {
+            router: router(&daemon, portal, failover.active())?,
+            failover,
+            alerts: AlertMonitor::new(daemon.alerts.clone()),
+            usage: UsageStore::open(),
+            daemon,
+            last_check: None,
+            last_statuses: Vec::new(),
+            switching_back: false,
+            consecutive_failures: 0,
+            last_error: None,
+        })
+    }
+
+    async fn tick(&mut self) {
+        if self.failover.renew(Local::now().date_naive()) {
+            info!(
+                "A new billing cycle started, switching back to {}",
+                self.failover.active().username
+            );
+            self.use_account();
+            self.switching_back = true;
+        }
+        if self.switching_back {
+            self.switching_back = !self.router.reclaim().await;
+        }
+        debug!("Scheduled router check...");
+        let mut statuses = self.router.keep_online().await;
+        // devices with their own credentials are up to those accounts
+        while let Some(cause) = statuses.iter().find_map(|status| match &status.state {
+            DeviceState::Offline(err) if status.device.username.is_none() && runs_out(err) => {
+                Some(err.clone())
+            }
+            _ => None,
+        }) {
+            let previous = self.failover.active().username.clone();
+            let Some(next) = self.failover.fail_over() else {
+                break;
+            };
+            warn!(
+                "{} ran out ({}), switching to {}",
+                previous, cause, next.username
+            );
+            self.use_account();
+            statuses = self.router.keep_online().await;
+        }
+        log_device_statuses(&statuses);
+        for status in &statuses {
+            if let DeviceState::Online(state) = &status.state {
+                self.alerts.observe(&status.username, state);
+                record_usage(self.usage.as_ref(), &status.username, state);
+            }
+        }
+        let failure = statuses.iter().find_map(|status| match &status.state {
+            DeviceState::Offline(SrunError::NotOnline) => None,
+            state @ (DeviceState::Offline(_) | DeviceState::Failed(_)) => {
+                Some(format!("{} is {}", status.device, state))
+            }
+            _ => None,
+        });
+        match failure {
+            Some(failure) => {
+                self.consecutive_failures += 1;
+                self.last_error = Some(failure);
+            }
+            None => {
+                self.consecutive_failures = 0;
+                self.last_error = None;
+            }
+        }
+        self.last_check = Some(Utc::now().timestamp());
+        self.last_statuses = statuses;
+    }
+
+    /// Log devices in with the active account from now on
+    fn use_account(&mut self) {
+        let account = self.failover.active();
+        self.router.username = account.username.clone();
+        self.router.password = account.password.clone();
+    }
+
+    fn reload(&mut self, daemon: SrunDaemon, portal: PortalProfile) -> Result<()> {
+        let mut failover = self.failover.clone();
+        failover.reload(daemon.failover());
+        self.router = router(&daemon, portal, failover.active())?;
+        self.failover = failover;
+        self.alerts.config = daemon.alerts.clone();
+        self.daemon = daemon;
+        self.last_statuses.clear();
+        Ok(())
+    }
+}
+
+fn router(daemon: &SrunDaemon, portal: PortalProfile, account: &Account) -> Result<Router> {
+    let transport = TransportOptions {
+        bind: daemon.bind.clone(),
+        ..Default::default()
+    };
+    Ok(Router::new(
+        transport.build_client()?,
+        portal,
+        daemon.devices.clone(),
+        account.username.clone(),
+        account.password.clone(),
+    ))
+}
+
+/// A login or logout response for `bitsrun ctl`
+fn portal_response(
+    result: Result<crate::client::SrunPortalResponse>,
+    done: &str,
+) -> ControlResponse {
+    match result {
+        Ok(resp) => match resp.srun_error() {
+            err if err.is_ok() || err == SrunError::IpAlreadyOnline => {
+                ControlResponse::ok(format!("{} {}", done, resp.online_ip))
+            }
+            err => ControlResponse::err(err.to_string()),
+        },
+        Err(e) => ControlResponse::err(format!("{:#}", e)),
+    }
+}
+
+/// Query the login state of this device, record it to the usage history, and raise alerts for
+/// values below their thresholds
+///
+/// Returns the login state, unless the portal could not be reached.
+async fn observe_login_state(
+    alerts: &mut AlertMonitor,
+    usage: Option<&UsageStore>,
+    http_client: &reqwest::Client,
+    portal: &PortalProfile,
+    username: &str,
+) -> Option<SrunLoginState> {
+    match get_login_state(http_client, portal, false).await {
+        Ok(state) if state.srun_error() == SrunError::Ok => {
+            alerts.observe(username, &state);
+            record_usage(usage, username, &state);
+            Some(state)
+        }
+        Ok(state) => {
+            debug!("Not online, skipping usage and alert checks.");
+            Some(state)
+        }
+        Err(e) => {
+            debug!("Failed to query login state: {}", e);
+            None
+        }
+    }
+}
+
+fn record_usage(usage: Option<&UsageStore>, username: &str, state: &SrunLoginState) {
+    if let Some(store) = usage {
+        if let Err(e) = store.append(&UsageSample::new(username, state)) {
+            warn!("Failed to record usage: {:#}", e);
+        }
+    }
+}
+
+/// Log the outcome of `ensure_online`, branching on the portal error if there was one
+///
+/// Returns whether the error is one the retry policy gives up on, after which the daemon stops
+/// logging in until told to by `bitsrun ctl`, or restarted.
+fn log_ensure_online(result: Result<()>, retry: &RetryPolicy) -> bool {
+    let Err(e) = result else {
+        return false;
+    };
+    let gave_up = e
+        .downcast_ref::<SrunError>()
+        .is_some_and(|err| retry.gives_up_on(err));
+    match e.downcast_ref::<SrunError>() {
+        Some(SrunError::WrongPassword) | Some(SrunError::UserNotFound) => {
+            warn!(
+                "{}: check the credentials in your config file",
+                e.root_cause()
+            )
+        }
+        Some(SrunError::Arrears) | Some(SrunError::QuotaExhausted) => {
+            warn!(
+                "{}: recharge your account to get back online",
+                e.root_cause()
+            )
+        }
+        Some(err) => warn!("{} (portal error: {})", e, err),
+        None => warn!("{}", e),
+    }
+    if gave_up {
+        warn!("Not logging in again until `bitsrun ctl login`, a reload or a restart");
+    }
+    gave_up
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigSource;
+    use super::Keeper;
+    use super::RouterKeeper;
+
+    use crate::bind::BindOptions;
+    use crate::portal::PortalOverrides;
+
+    fn write_config(path: &std::path::Path, poll_interval: u64, metrics: &str) {
+        let config = serde_json::json!({
+            "username": "alice",
+            "password": "secret",
+            "dm": false,
+            "poll_interval": poll_interval,
+            "metrics": metrics,
+            "devices": [{ "ip": "10.0.0.2" }],
+        });
+        std::fs::write(path, config.to_string()).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(0o600);
+            std::fs::set_permissions(path, permissions).unwrap();
+        }
+    }
+
+    #[test]
+    fn reload_applies_the_config_but_keeps_what_needs_a_restart() {
+        let path = std::env::temp_dir().join(format!("bitsrun-reload-{}.json", std::process::id()));
+        write_config(&path, 60, "127.0.0.1:9477");
+        let source = ConfigSource {
+            config: Some(path.to_string_lossy().into_owned()),
+            overrides: PortalOverrides::default(),
+            bind_overrides: BindOptions::default(),
+        };
+        let (daemon, portal) = source.load().unwrap();
+        let mut keeper = Keeper::Router(Box::new(RouterKeeper::new(daemon, portal).unwrap()));
+
+        write_config(&path, 120, "127.0.0.1:9478");
+        keeper.reload(&source, "test").unwrap();
+        assert_eq!(keeper.daemon().poll_interval(), 120);
+        assert_eq!(
+            keeper.daemon().metrics,
+            Some("127.0.0.1:9477".parse().unwrap())
+        );
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(keeper.reload(&source, "test").is_err());
+        assert_eq!(keeper.daemon().poll_interval(), 120);
+        let _ = std::fs::remove_file(&path);
+    }
 }
@@ -0,0 +1,169 @@
+//! Inspect captured `/srun_portal` login requests
+//!
+//! Decodes the `info` parameter with its challenge token, recomputes `chksum` and the `{MD5}`
+//! password from what was sent, and compares each field against what the client expected.
+
+use std::collections::HashMap;
+
+use crate::encoder::EncoderKind;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde_json::Value;
+
+/// Values the login is expected to carry, where `None` falls back to the request's own params
+#[derive(Debug, Clone, Default)]
+pub struct Expected {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub ip: Option<String>,
+    pub ac_id: Option<String>,
+}
+
+/// A field of the login request, as sent and as expected
+#[derive(Debug, Clone)]
+pub struct FieldCheck {
+    pub name: &'static str,
+    pub sent: String,
+    /// `None` when there is nothing to check against
+    pub expected: Option<String>,
+}
+
+impl FieldCheck {
+    /// Whether the field matches, or `None` if it could not be checked
+    pub fn matches(&self) -> Option<bool> {
+        self.expected.as_ref().map(|e| e == &self.sent)
+    }
+}
+
+/// A decoded login request
+#[derive(Debug, Clone)]
+pub struct DecodedLogin {
+    /// The JSON inside `info`
+    pub info: Value,
+    pub checks: Vec<FieldCheck>,
+}
+
+impl DecodedLogin {
+    /// Names of the fields that do not match
+    pub fn mismatches(&self) -> Vec<&'static str> {
+        self.checks
+            .iter()
+            .filter(|c| c.matches() == Some(false))
+            .map(|c| c.name)
+            .collect()
+    }
+}
+
+/// Parse the params of a captured login URL, or of its bare query string
+pub fn parse_request(request: &str) -> HashMap<String, String> {
+    let query = request.trim();
+    let query = query.split_once('?').map_or(query, |(_, q)| q);
+    let query = query.split_once('#').map_or(query, |(q, _)| q);
+    url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .map(|(k, v)| match k.as_str() {
+            // `+` is part of the fkbase64 alphabet, and becomes a space if copied unescaped
+            "info" => (k, v.replace(' ', "+")),
+            _ => (k, v),
+        })
+        .collect()
+}
+
+/// Decode the `info` of a login request and check it, the `{MD5}` password and `chksum`
+pub fn decode_login(
+    request: &str,
+    token: &str,
+    expected: &Expected,
+    encoder: EncoderKind,
+) -> Result<DecodedLogin> {
+    let params = parse_request(request);
+    let param = |name: &str| params.get(name).cloned().unwrap_or_default();
+    let sent_info = params
+        .get("info")
+        .with_context(|| "request has no `info` parameter")?;
+
+    let encoder = encoder.encoder();
+    let info_json = encoder
+        .decode_info(sent_info, token)
+        .with_context(|| "failed to decode `info`")?;
+    let info: Value = serde_json::from_str(&info_json)
+        .with_context(|| format!("decoded `info` is not JSON: {}", info_json))?;
+    let info_field = |name: &str| match info.get(name) {
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+        None => String::new(),
+    };
+
+    // expected values given explicitly, falling back to the outer params of the request
+    let or_param =
+        |value: &Option<String>, name: &str| value.clone().or_else(|| params.get(name).cloned());
+    let explicit = |value: &Option<String>, name: &'static str| FieldCheck {
+        name,
+        sent: param(name),
+        expected: value.clone(),
+    };
+
+    // the `{MD5}` password is derived from the plain one, which `info` carries as well
+    let password = expected
+        .password
+        .clone()
+        .unwrap_or_else(|| info_field("password"));
+    let sent_hmd5 = param("password");
+    let sent_hmd5 = sent_hmd5.strip_prefix("{MD5}").unwrap_or(&sent_hmd5);
+    let chksum = encoder.chksum(
+        token,
+        &[
+            &param("username"),
+            sent_hmd5,
+            &param("ac_id"),
+            &param("ip"),
+            &param("n"),
+            &param("type"),
+            sent_info,
+        ],
+    );
+
+    let checks = vec![
+        FieldCheck {
+            name: "info.username",
+            sent: info_field("username"),
+            expected: or_param(&expected.username, "username"),
+        },
+        FieldCheck {
+            name: "info.ip",
+            sent: info_field("ip"),
+            expected: or_param(&expected.ip, "ip"),
+        },
+        FieldCheck {
+            name: "info.acid",
+            sent: info_field("acid"),
+            expected: or_param(&expected.ac_id, "ac_id"),
+        },
+        FieldCheck {
+            name: "info.enc_ver",
+            sent: info_field("enc_ver"),
+            expected: Some(encoder.enc_ver().to_string()),
+        },
+        FieldCheck {
+            name: "info.password",
+            sent: info_field("password"),
+            expected: expected.password.clone(),
+        },
+        explicit(&expected.username, "username"),
+        explicit(&expected.ip, "ip"),
+        explicit(&expected.ac_id, "ac_id"),
+        FieldCheck {
+            name: "password",
+            sent: param("password"),
+            expected: Some(format!("{}{}", "{MD5}", encoder.hmd5(&password, token))),
+        },
+        FieldCheck {
+            name: "chksum",
+            sent: param("chksum"),
+            expected: Some(chksum),
+        },
+    ];
+
+    Ok(DecodedLogin { info, checks })
+}
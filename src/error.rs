@@ -0,0 +1,213 @@
+use std::fmt;
+
+/// Known SRUN portal error codes
+///
+/// The portal reports errors either as a plain string in the `error` field (e.g.,
+/// `not_online_error`), or as a generic `login_error` with an `E####` code prefixed to
+/// `error_msg` (e.g., `E2553: Password is error.`). Both are folded into this enum, with anything
+/// unrecognized kept as [`SrunError::Unknown`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SrunError {
+    /// `ok`, the request succeeded
+    Ok,
+    /// `logout_ok`, returned by the `dm` logout endpoint
+    LogoutOk,
+    /// `not_online_error`
+    NotOnline,
+    /// `ip_already_online_error`, or `E2620`
+    IpAlreadyOnline,
+    /// `E2621`, the account has reached its limit of online devices
+    TooManyDevices,
+    /// `challenge_expire_error`
+    ChallengeExpired,
+    /// `sign_error`, the `chksum` or `sign` did not match
+    SignError,
+    /// `missing_required_parameters_error`
+    MissingParameters,
+    /// `E2531`
+    UserNotFound,
+    /// `E2553`, or `E2901` when the third party (LDAP) bind fails
+    WrongPassword,
+    /// `E2606`
+    UserDisabled,
+    /// `E2616` or `E3004`, the account is in arrears
+    Arrears,
+    /// `E3001`, the traffic or time quota is used up
+    QuotaExhausted,
+    /// `E2532` or `E2533`, authenticating too frequently
+    TooFrequent,
+    /// Anything else, with the raw `error` (or `E####` code) kept as is
+    Unknown(String),
+}
+
+impl SrunError {
+    /// Parse the error from the `error` and `error_msg` fields of a portal response
+    pub fn from_response(error: &str, error_msg: &str) -> Self {
+        match error {
+            "ok" => return SrunError::Ok,
+            "logout_ok" => return SrunError::LogoutOk,
+            "not_online_error" => return SrunError::NotOnline,
+            "ip_already_online_error" => return SrunError::IpAlreadyOnline,
+            "challenge_expire_error" => return SrunError::ChallengeExpired,
+            "sign_error" => return SrunError::SignError,
+            "missing_required_parameters_error" => return SrunError::MissingParameters,
+            _ => {}
+        }
+
+        // `E####` codes come first in `error_msg`, or sometimes as the `error` itself
+        let code = [error, error_msg]
+            .into_iter()
+            .filter_map(|s| s.trim().get(..5))
+            .find(|c| c.starts_with('E') && c[1..].chars().all(|c| c.is_ascii_digit()));
+        match code {
+            Some("E2620") => SrunError::IpAlreadyOnline,
+            Some("E2621") => SrunError::TooManyDevices,
+            Some("E2531") => SrunError::UserNotFound,
+            Some("E2553") | Some("E2901") => SrunError::WrongPassword,
+            Some("E2606") => SrunError::UserDisabled,
+            Some("E2616") | Some("E3004") => SrunError::Arrears,
+            Some("E3001") => SrunError::QuotaExhausted,
+            Some("E2532") | Some("E2533") => SrunError::TooFrequent,
+            Some(code) => SrunError::Unknown(code.to_string()),
+            None => SrunError::Unknown(error.to_string()),
+        }
+    }
+
+    /// The raw `error` values and `E####` codes that parse into this error
+    pub fn codes(&self) -> Vec<&str> {
+        match self {
+            SrunError::Ok => vec!["ok"],
+            SrunError::LogoutOk => vec!["logout_ok"],
+            SrunError::NotOnline => vec!["not_online_error"],
+            SrunError::IpAlreadyOnline => vec!["ip_already_online_error", "E2620"],
+            SrunError::TooManyDevices => vec!["E2621"],
+            SrunError::ChallengeExpired => vec!["challenge_expire_error"],
+            SrunError::SignError => vec!["sign_error"],
+            SrunError::MissingParameters => vec!["missing_required_parameters_error"],
+            SrunError::UserNotFound => vec!["E2531"],
+            SrunError::WrongPassword => vec!["E2553", "E2901"],
+            SrunError::UserDisabled => vec!["E2606"],
+            SrunError::Arrears => vec!["E2616", "E3004"],
+            SrunError::QuotaExhausted => vec!["E3001"],
+            SrunError::TooFrequent => vec!["E2532", "E2533"],
+            SrunError::Unknown(raw) => vec![raw.as_str()],
+        }
+    }
+
+    /// Whether the response is a success (`ok` or `logout_ok`)
+    pub fn is_ok(&self) -> bool {
+        matches!(self, SrunError::Ok | SrunError::LogoutOk)
+    }
+}
+
+impl fmt::Display for SrunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SrunError::Ok => write!(f, "ok"),
+            SrunError::LogoutOk => write!(f, "logged out"),
+            SrunError::NotOnline => write!(f, "not online"),
+            SrunError::IpAlreadyOnline => write!(f, "ip already online"),
+            SrunError::TooManyDevices => write!(f, "too many online devices"),
+            SrunError::ChallengeExpired => write!(f, "challenge expired"),
+            SrunError::SignError => write!(f, "checksum mismatch"),
+            SrunError::MissingParameters => write!(f, "missing required parameters"),
+            SrunError::UserNotFound => write!(f, "user not found"),
+            SrunError::WrongPassword => write!(f, "wrong password"),
+            SrunError::UserDisabled => write!(f, "user disabled"),
+            SrunError::Arrears => write!(f, "account in arrears"),
+            SrunError::QuotaExhausted => write!(f, "traffic or time quota exhausted"),
+            SrunError::TooFrequent => write!(f, "authenticating too frequently"),
+            SrunError::Unknown(raw) => write!(f, "{}", raw),
+        }
+    }
+}
+
+impl std::error::Error for SrunError {}
+
+#[cfg(test)]
+mod tests {
+    use super::SrunError;
+
+    #[test]
+    fn parses_plain_errors() {
+        let cases = [
+            ("ok", SrunError::Ok),
+            ("logout_ok", SrunError::LogoutOk),
+            ("not_online_error", SrunError::NotOnline),
+            ("ip_already_online_error", SrunError::IpAlreadyOnline),
+            ("challenge_expire_error", SrunError::ChallengeExpired),
+            ("sign_error", SrunError::SignError),
+            (
+                "missing_required_parameters_error",
+                SrunError::MissingParameters,
+            ),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(SrunError::from_response(error, ""), expected, "{}", error);
+        }
+    }
+
+    #[test]
+    fn parses_codes_in_error_msg() {
+        let cases = [
+            ("E2620", SrunError::IpAlreadyOnline),
+            ("E2621", SrunError::TooManyDevices),
+            ("E2531", SrunError::UserNotFound),
+            ("E2553", SrunError::WrongPassword),
+            ("E2901", SrunError::WrongPassword),
+            ("E2606", SrunError::UserDisabled),
+            ("E2616", SrunError::Arrears),
+            ("E3004", SrunError::Arrears),
+            ("E3001", SrunError::QuotaExhausted),
+            ("E2532", SrunError::TooFrequent),
+            ("E2533", SrunError::TooFrequent),
+        ];
+        for (code, expected) in cases {
+            let error_msg = format!("{}: Something went wrong.", code);
+            let parsed = SrunError::from_response("login_error", &error_msg);
+            assert_eq!(parsed, expected, "{}", code);
+            assert!(parsed.codes().contains(&code), "{}", code);
+        }
+    }
+
+    #[test]
+    fn parses_codes_as_error() {
+        assert_eq!(
+            SrunError::from_response("E2553", ""),
+            SrunError::WrongPassword
+        );
+        assert_eq!(
+            SrunError::from_response(" E3001 ", "whatever"),
+            SrunError::QuotaExhausted
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_errors() {
+        assert_eq!(
+            SrunError::from_response("login_error", "E9999: Something new."),
+            SrunError::Unknown("E9999".to_string())
+        );
+        assert_eq!(
+            SrunError::from_response("some_new_error", ""),
+            SrunError::Unknown("some_new_error".to_string())
+        );
+        // not a code, despite the leading `E`
+        assert_eq!(
+            SrunError::from_response("login_error", "Error: 12345"),
+            SrunError::Unknown("login_error".to_string())
+        );
+        assert_eq!(
+            SrunError::Unknown("E9999".to_string()).codes(),
+            vec!["E9999"]
+        );
+    }
+
+    #[test]
+    fn codes_parse_back() {
+        for code in ["ok", "logout_ok", "not_online_error", "sign_error", "E2616"] {
+            let parsed = SrunError::from_response(code, "");
+            assert!(parsed.codes().contains(&code), "{}", code);
+        }
+    }
+}
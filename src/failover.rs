@@ -0,0 +1,186 @@
+//! Failing over between shared accounts in the keep-alive daemon
+//!
+//! Accounts to fall back on are listed in the config file under `accounts`, in order, after the
+//! top-level one:
+//!
+//! ```json
+//! {
+//!   "username": "...",
+//!   "password": "...",
+//!   "accounts": [
+//!     { "username": "...", "password": "..." },
+//!     { "username": "...", "password": "..." }
+//!   ],
+//!   "billing_day": 1
+//! }
+//! ```
+//!
+//! When the portal refuses a login because the account is in arrears or out of quota, the daemon
+//! switches to the next account and logs in again. It switches back to the top-level account once
+//! a new billing cycle starts, at midnight (local time) on `billing_day` of the month, logging out
+//! the account failed over to if it is still online. The account in use is saved with the daemon
+//! state, so that a restart carries on with it.
+
+use crate::error::SrunError;
+
+use chrono::Datelike;
+use chrono::Local;
+use chrono::NaiveDate;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// A campus network account the daemon can log in with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Account {
+    pub username: String,
+    pub password: String,
+}
+
+/// Whether the account ran out of balance or quota, so that another one should take over
+pub fn runs_out(err: &SrunError) -> bool {
+    matches!(err, SrunError::Arrears | SrunError::QuotaExhausted)
+}
+
+/// The first day of the billing cycle `date` falls in, with cycles starting on `billing_day` of
+/// every month (or the last day of months too short for it)
+///
+/// ```
+/// use chrono::NaiveDate;
+/// use bitsrun::failover::cycle_start;
+///
+/// let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+/// assert_eq!(cycle_start(date(2026, 3, 15), 1), date(2026, 3, 1));
+/// assert_eq!(cycle_start(date(2026, 3, 10), 15), date(2026, 2, 15));
+/// assert_eq!(cycle_start(date(2026, 3, 10), 31), date(2026, 2, 28));
+/// assert_eq!(cycle_start(date(2026, 1, 10), 15), date(2025, 12, 15));
+/// ```
+pub fn cycle_start(date: NaiveDate, billing_day: u32) -> NaiveDate {
+    let start_in = |year: i32, month: u32| {
+        (1..=billing_day.clamp(1, 31))
+            .rev()
+            .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+            .expect("every month has a 1st")
+    };
+    let start = start_in(date.year(), date.month());
+    if start <= date {
+        return start;
+    }
+    match date.month() {
+        1 => start_in(date.year() - 1, 12),
+        month => start_in(date.year(), month - 1),
+    }
+}
+
+/// The daemon's accounts in order, and the one in use
+#[derive(Debug, Clone)]
+pub struct Failover {
+    pub accounts: Vec<Account>,
+    pub billing_day: u32,
+    /// Index into `accounts`
+    pub active: usize,
+    /// Local date of the last switch, to tell when a new billing cycle started since
+    pub switched_on: Option<NaiveDate>,
+}
+
+impl Failover {
+    /// Start with the first of `accounts`, which must not be empty
+    pub fn new(accounts: Vec<Account>, billing_day: u32) -> Self {
+        Self {
+            accounts,
+            billing_day,
+            active: 0,
+            switched_on: None,
+        }
+    }
+
+    pub fn active(&self) -> &Account {
+        &self.accounts[self.active]
+    }
+
+    /// Switch to the next account, after the active one ran out
+    ///
+    /// Returns `None` if the active account is the last one.
+    pub fn fail_over(&mut self) -> Option<&Account> {
+        if self.active + 1 >= self.accounts.len() {
+            return None;
+        }
+        self.active += 1;
+        self.switched_on = Some(Local::now().date_naive());
+        Some(self.active())
+    }
+
+    /// Switch back to the first account if a billing cycle started since the last switch
+    ///
+    /// Returns whether it switched.
+    pub fn renew(&mut self, today: NaiveDate) -> bool {
+        let Some(switched_on) = self.switched_on.filter(|_| self.active > 0) else {
+            return false;
+        };
+        if cycle_start(today, self.billing_day) <= switched_on {
+            return false;
+        }
+        self.active = 0;
+        self.switched_on = None;
+        true
+    }
+
+    /// Carry on with `username` as switched to on `switched_on`, as saved before a restart
+    ///
+    /// Returns whether it did, staying on the first account if `username` is not a later one.
+    pub fn restore(&mut self, username: &str, switched_on: Option<NaiveDate>) -> bool {
+        let Some(switched_on) = switched_on else {
+            return false;
+        };
+        match self.accounts.iter().position(|a| a.username == username) {
+            Some(index) if index > 0 => {
+                self.active = index;
+                self.switched_on = Some(switched_on);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Take the accounts of a reloaded config, staying on the active account if it is still there
+    pub fn reload(&mut self, other: Failover) {
+        let active = self.active().username.clone();
+        let switched_on = self.switched_on;
+        *self = other;
+        if let Some(index) = self.accounts.iter().position(|a| a.username == active) {
+            self.active = index;
+            self.switched_on = switched_on.filter(|_| index > 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Account;
+    use super::Failover;
+
+    use chrono::NaiveDate;
+
+    fn failover() -> Failover {
+        let account = |username: &str| Account {
+            username: username.to_string(),
+            password: "secret".to_string(),
+        };
+        Failover::new(vec![account("alice"), account("bob")], 15)
+    }
+
+    #[test]
+    fn restores_and_renews_the_account_failed_over_to() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        let mut failover = failover();
+        assert!(!failover.restore("bob", None));
+        assert!(!failover.restore("carol", Some(date(2026, 3, 16))));
+        assert!(!failover.restore("alice", Some(date(2026, 3, 16))));
+        assert_eq!(failover.active().username, "alice");
+
+        assert!(failover.restore("bob", Some(date(2026, 3, 16))));
+        assert_eq!(failover.active().username, "bob");
+        assert!(!failover.renew(date(2026, 4, 14)));
+        assert!(failover.renew(date(2026, 4, 15)));
+        assert_eq!(failover.active().username, "alice");
+    }
+}
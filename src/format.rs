@@ -0,0 +1,470 @@
+//! Output formats of `bitsrun status`, for scripts and status bars
+//!
+//! Besides the fixed formats, `--template` renders `{field}` placeholders over the fields of
+//! [`SrunLoginState`], e.g., `'{user_name} {online_ip} {sum_bytes:human}'`. The `human`
+//! modifier prints byte counts as sizes and second counts as durations. Fields the portal did not
+//! report render empty, while names that are not fields at all are an error. Write `{{` and `}}`
+//! for literal braces.
+
+use crate::client::SrunLoginState;
+
+use anyhow::bail;
+use anyhow::Result;
+use humansize::format_size;
+use humansize::BINARY;
+use serde_json::Map;
+use serde_json::Value;
+
+/// Output format of `bitsrun status`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum StatusFormat {
+    /// Status line and table, for humans
+    #[default]
+    Table,
+    Json,
+    Yaml,
+    /// Header and one row per address
+    Csv,
+    /// `BITSRUN_<FIELD>=<value>` lines to `eval` in a shell
+    Env,
+    /// Prometheus text exposition format
+    Prometheus,
+}
+
+/// Login states to render, labeled by address family (`ipv4` or `ipv6`)
+///
+/// A dual-stack portal without `url_v6` leaves the IPv6 state out as `None`.
+pub type LabeledStates<'a> = [(&'a str, Option<&'a SrunLoginState>)];
+
+/// Names of every [`SrunLoginState`] field, as serialized
+pub const FIELDS: [&str; 28] = [
+    "error",
+    "online_ip",
+    "ServerFlag",
+    "add_time",
+    "all_bytes",
+    "bytes_in",
+    "bytes_out",
+    "checkout_date",
+    "domain",
+    "group_id",
+    "keepalive_time",
+    "products_name",
+    "real_name",
+    "remain_bytes",
+    "remain_seconds",
+    "sum_bytes",
+    "sum_seconds",
+    "sysver",
+    "user_balance",
+    "user_charge",
+    "user_mac",
+    "user_name",
+    "wallet_balance",
+    "client_ip",
+    "error_msg",
+    "res",
+    "srun_ver",
+    "st",
+];
+
+/// Login state fields as a JSON object
+fn fields(state: &SrunLoginState) -> Map<String, Value> {
+    match serde_json::to_value(state) {
+        Ok(Value::Object(map)) => map,
+        _ => Map::new(),
+    }
+}
+
+/// A JSON value as plain text, without quotes around strings
+fn plain(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+/// Render login states in a machine readable format
+///
+/// A single state is rendered as is, while dual-stack states are keyed (or labeled) by family.
+/// Not for [`StatusFormat::Table`], which is printed by [`crate::tables`].
+pub fn render(states: &LabeledStates, format: StatusFormat) -> Result<String> {
+    let single = match states {
+        [(_, Some(state))] => Some(*state),
+        _ => None,
+    };
+
+    let rendered = match format {
+        StatusFormat::Table => bail!("the table format is printed, not rendered"),
+        StatusFormat::Json => match single {
+            Some(state) => serde_json::to_string(state)?,
+            None => serde_json::to_string(&keyed(states))?,
+        },
+        StatusFormat::Yaml => match single {
+            Some(state) => yaml(&Value::Object(fields(state)), 0),
+            None => yaml(&keyed(states), 0),
+        },
+        StatusFormat::Csv => csv(states),
+        StatusFormat::Env => env(states),
+        StatusFormat::Prometheus => prometheus(states),
+    };
+    Ok(rendered)
+}
+
+fn keyed(states: &LabeledStates) -> Value {
+    Value::Object(
+        states
+            .iter()
+            .map(|(family, state)| {
+                let value = state.map_or(Value::Null, |s| Value::Object(fields(s)));
+                (family.to_string(), value)
+            })
+            .collect(),
+    )
+}
+
+/// A block YAML document, with every string quoted as JSON (which is valid YAML)
+fn yaml(value: &Value, indent: usize) -> String {
+    let pad = "  ".repeat(indent);
+    match value {
+        Value::Object(map) => map
+            .iter()
+            .map(|(key, value)| match value {
+                Value::Object(inner) if !inner.is_empty() => {
+                    format!("{}{}:\n{}", pad, key, yaml(value, indent + 1))
+                }
+                Value::Object(_) => format!("{}{}: {{}}\n", pad, key),
+                value => format!("{}{}: {}\n", pad, key, value),
+            })
+            .collect(),
+        value => format!("{}{}\n", pad, value),
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+fn csv(states: &LabeledStates) -> String {
+    let rows: Vec<(&str, Map<String, Value>)> = states
+        .iter()
+        .filter_map(|(family, state)| Some((*family, fields((*state)?))))
+        .collect();
+
+    // union of the fields, as offline states have fewer
+    let mut columns: Vec<&String> = rows.iter().flat_map(|(_, map)| map.keys()).collect();
+    columns.sort();
+    columns.dedup();
+
+    let mut out = String::from("family");
+    for column in &columns {
+        out.push(',');
+        out.push_str(column);
+    }
+    out.push('\n');
+    for (family, map) in &rows {
+        out.push_str(family);
+        for column in &columns {
+            out.push(',');
+            out.push_str(&csv_field(&map.get(*column).map(plain).unwrap_or_default()));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn env(states: &LabeledStates) -> String {
+    let mut out = String::new();
+    for (family, state) in states {
+        let Some(state) = state else {
+            continue;
+        };
+        // the IPv4 state keeps the unprefixed names, as without dual-stack
+        let prefix = match *family {
+            "ipv6" => "BITSRUN_IPV6_",
+            _ => "BITSRUN_",
+        };
+        for (key, value) in fields(state) {
+            let value = plain(&value).replace('\'', r"'\''");
+            out.push_str(&format!("{}{}='{}'\n", prefix, key.to_uppercase(), value));
+        }
+    }
+    out
+}
+
+/// Numeric login state fields exported as gauges, with their help text
+pub const GAUGES: [(&str, &str); 10] = [
+    ("sum_bytes", "Traffic used in the billing period, in bytes"),
+    (
+        "sum_seconds",
+        "Online time in the billing period, in seconds",
+    ),
+    ("bytes_in", "Bytes downloaded in the current session"),
+    ("bytes_out", "Bytes uploaded in the current session"),
+    ("remain_bytes", "Traffic remaining in the package, in bytes"),
+    ("remain_seconds", "Online time remaining, in seconds"),
+    ("user_balance", "User balance"),
+    ("wallet_balance", "Wallet balance"),
+    ("add_time", "Unix time the current session started"),
+    ("keepalive_time", "Unix time of the last keepalive"),
+];
+
+/// Escape a Prometheus label value
+pub fn label_value(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+fn prometheus(states: &LabeledStates) -> String {
+    let states: Vec<(&str, &SrunLoginState)> = states
+        .iter()
+        .filter_map(|(family, state)| Some((*family, (*state)?)))
+        .collect();
+    login_state_metrics(&states)
+}
+
+/// `bitsrun_online` and the [`GAUGES`] of login states labeled by family, in the Prometheus text
+/// exposition format
+pub fn login_state_metrics(states: &[(&str, &SrunLoginState)]) -> String {
+    let labels = |family: &str, state: &SrunLoginState| {
+        format!(
+            "family=\"{}\",ip=\"{}\",user=\"{}\"",
+            family,
+            state.online_ip,
+            label_value(state.user_name.as_deref().unwrap_or_default())
+        )
+    };
+
+    let mut out = String::new();
+    out.push_str("# HELP bitsrun_online Whether the address is logged in\n");
+    out.push_str("# TYPE bitsrun_online gauge\n");
+    for (family, state) in states {
+        let online = state.srun_error() == crate::error::SrunError::Ok;
+        out.push_str(&format!(
+            "bitsrun_online{{{}}} {}\n",
+            labels(family, state),
+            online as u8
+        ));
+    }
+    for (name, help) in GAUGES {
+        let samples: Vec<String> = states
+            .iter()
+            .filter_map(|(family, state)| {
+                let value = fields(state).get(name)?.as_f64()?;
+                Some(format!(
+                    "bitsrun_{}{{{}}} {}\n",
+                    name,
+                    labels(family, state),
+                    value
+                ))
+            })
+            .collect();
+        if samples.is_empty() {
+            continue;
+        }
+        out.push_str(&format!("# HELP bitsrun_{} {}\n", name, help));
+        out.push_str(&format!("# TYPE bitsrun_{} gauge\n", name));
+        out.extend(samples);
+    }
+    out
+}
+
+/// Render a `--template` over a login state
+///
+/// ```
+/// use bitsrun::format::render_template;
+///
+/// let state = serde_json::from_str(
+///     r#"{"error": "ok", "online_ip": "10.1.2.3", "user_name": "alice", "sum_bytes": 2048}"#,
+/// )
+/// .unwrap();
+/// let line = render_template("{user_name}@{online_ip} {sum_bytes:human} {{x}}", &state).unwrap();
+/// assert_eq!(line, "alice@10.1.2.3 2 KiB {x}");
+/// ```
+pub fn render_template(template: &str, state: &SrunLoginState) -> Result<String> {
+    let fields = fields(state);
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => bail!("unclosed `{{{}` in template", placeholder),
+                    }
+                }
+                let (name, modifier) = match placeholder.split_once(':') {
+                    Some((name, modifier)) => (name.trim(), Some(modifier.trim())),
+                    None => (placeholder.trim(), None),
+                };
+                if !FIELDS.contains(&name) {
+                    bail!(
+                        "unknown field `{}` in template, expected one of {}",
+                        name,
+                        FIELDS.join(", ")
+                    )
+                }
+                let value = fields.get(name).unwrap_or(&Value::Null);
+                out.push_str(&apply_modifier(name, value, modifier)?);
+            }
+            '}' => bail!("unmatched `}}` in template, write `}}}}` for a literal brace"),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+fn apply_modifier(name: &str, value: &Value, modifier: Option<&str>) -> Result<String> {
+    match modifier {
+        None => Ok(plain(value)),
+        Some("human") => {
+            let Some(number) = value.as_f64() else {
+                return Ok(plain(value));
+            };
+            if name.contains("bytes") {
+                Ok(format_size(number.max(0.0) as u64, BINARY))
+            } else if name.contains("seconds") {
+                Ok(human_duration(number as i64))
+            } else {
+                Ok(plain(value))
+            }
+        }
+        Some(modifier) => bail!(
+            "unknown modifier `{}` for `{}` in template, expected `human`",
+            modifier,
+            name
+        ),
+    }
+}
+
+/// A duration as its two largest units, e.g., `3d 4h` or `45s`
+///
+/// ```
+/// use bitsrun::format::human_duration;
+///
+/// assert_eq!(human_duration(0), "0s");
+/// assert_eq!(human_duration(45), "45s");
+/// assert_eq!(human_duration(3600), "1h");
+/// assert_eq!(human_duration(3 * 86400 + 4 * 3600 + 59), "3d 4h");
+/// assert_eq!(human_duration(-90), "-1m 30s");
+/// ```
+pub fn human_duration(seconds: i64) -> String {
+    let sign = if seconds < 0 { "-" } else { "" };
+    let mut rest = seconds.unsigned_abs();
+    let mut parts = vec![];
+    for (unit, size) in [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+        if rest >= size {
+            parts.push(format!("{}{}", rest / size, unit));
+            rest %= size;
+        }
+    }
+    match parts.is_empty() {
+        true => String::from("0s"),
+        false => format!(
+            "{}{}",
+            sign,
+            parts.iter().take(2).cloned().collect::<Vec<_>>().join(" ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render_template;
+    use super::FIELDS;
+
+    use crate::client::SrunLoginState;
+
+    use serde_json::json;
+
+    fn state() -> SrunLoginState {
+        serde_json::from_value(json!({
+            "error": "ok",
+            "online_ip": "10.1.2.3",
+            "user_name": "alice",
+            "sum_bytes": 3 * 1024 * 1024,
+            "sum_seconds": 30,
+            "remain_seconds": 90061,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn fields_cover_the_login_state() {
+        let every: serde_json::Map<_, _> = FIELDS
+            .iter()
+            .map(|name| (name.to_string(), json!(1)))
+            .collect();
+        let mut every = serde_json::Value::Object(every);
+        let strings = [
+            "error",
+            "domain",
+            "group_id",
+            "products_name",
+            "real_name",
+            "sysver",
+            "user_mac",
+            "user_name",
+            "error_msg",
+            "res",
+            "srun_ver",
+        ];
+        for name in strings {
+            every[name] = json!("x");
+        }
+        every["online_ip"] = json!("10.1.2.3");
+        every["client_ip"] = json!("10.1.2.3");
+        let state: SrunLoginState = serde_json::from_value(every).unwrap();
+
+        let mut serialized: Vec<String> = match serde_json::to_value(state).unwrap() {
+            serde_json::Value::Object(map) => map.keys().cloned().collect(),
+            _ => unreachable!(),
+        };
+        let mut fields: Vec<String> = FIELDS.iter().map(|name| name.to_string()).collect();
+        serialized.sort();
+        fields.sort();
+        assert_eq!(serialized, fields);
+    }
+
+    #[test]
+    fn renders_missing_fields_empty() {
+        let line = render_template("[{user_name}] [{real_name}]", &state()).unwrap();
+        assert_eq!(line, "[alice] []");
+    }
+
+    #[test]
+    fn rejects_unknown_fields_and_modifiers() {
+        assert!(render_template("{username}", &state()).is_err());
+        assert!(render_template("{sum_bytes:huge}", &state()).is_err());
+        assert!(render_template("{user_name", &state()).is_err());
+        assert!(render_template("user_name}", &state()).is_err());
+    }
+
+    #[test]
+    fn human_sizes_and_durations() {
+        let line = render_template(
+            "{sum_bytes:human} {sum_seconds:human} {remain_seconds:human}",
+            &state(),
+        )
+        .unwrap();
+        assert_eq!(line, "3 MiB 30s 1d 1h");
+    }
+}
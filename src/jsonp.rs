@@ -0,0 +1,116 @@
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+
+/// A portal response that could not be decoded, along with the offending body
+#[derive(Debug, Clone)]
+pub struct JsonpError {
+    pub reason: String,
+    pub body: String,
+}
+
+impl fmt::Display for JsonpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:\n  {}", self.reason, self.body)
+    }
+}
+
+impl std::error::Error for JsonpError {}
+
+/// Strip the JSONP callback wrapper from a response body
+///
+/// Accepts any callback name (`jsonp(...)`, `jQuery1124_1700000000({...});`), surrounding
+/// whitespace and trailing semicolons, and plain JSON bodies as well.
+pub fn unwrap_jsonp(body: &str) -> Result<&str, JsonpError> {
+    let error = |reason: &str| JsonpError {
+        reason: reason.to_string(),
+        body: body.to_string(),
+    };
+
+    let trimmed = body
+        .trim()
+        .trim_end_matches(|c: char| c == ';' || c.is_whitespace());
+    if trimmed.is_empty() {
+        return Err(error("empty response"));
+    }
+
+    // plain JSON, no callback at all
+    if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        return Ok(trimmed);
+    }
+
+    let (callback, rest) = trimmed
+        .split_once('(')
+        .ok_or_else(|| error("response is neither JSON nor JSONP"))?;
+    let callback = callback.trim();
+    let is_identifier = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '$' | '.');
+    if callback.is_empty() || !callback.chars().all(is_identifier) {
+        return Err(error("invalid JSONP callback name"));
+    }
+    let inner = rest
+        .strip_suffix(')')
+        .ok_or_else(|| error("unterminated JSONP callback"))?;
+    Ok(inner.trim())
+}
+
+/// Decode a JSONP (or plain JSON) response body into `T`
+pub fn decode<T: DeserializeOwned>(body: &str) -> Result<T, JsonpError> {
+    let json = unwrap_jsonp(body)?;
+    serde_json::from_str::<T>(json).map_err(|e| JsonpError {
+        reason: format!("malformed response ({})", e),
+        body: body.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use super::unwrap_jsonp;
+
+    use serde_json::json;
+    use serde_json::Value;
+
+    #[test]
+    fn random_callback_name() {
+        let body = r#"jQuery112406951885120277062_1700000000000({"error":"ok"})"#;
+        assert_eq!(unwrap_jsonp(body).unwrap(), r#"{"error":"ok"}"#);
+        assert_eq!(decode::<Value>(body).unwrap(), json!({ "error": "ok" }));
+    }
+
+    #[test]
+    fn trailing_semicolon_and_whitespace() {
+        let body = "\n  jsonp( {\"error\":\"ok\"} );\r\n ; \n";
+        assert_eq!(unwrap_jsonp(body).unwrap(), r#"{"error":"ok"}"#);
+    }
+
+    #[test]
+    fn plain_json() {
+        assert_eq!(
+            decode::<Value>(" {\"error\":\"ok\"}\n").unwrap(),
+            json!({ "error": "ok" })
+        );
+        assert_eq!(decode::<Value>("[1, 2]").unwrap(), json!([1, 2]));
+    }
+
+    #[test]
+    fn error_bodies() {
+        for body in [
+            "",
+            "  \n",
+            "<html><body>502 Bad Gateway</body></html>",
+            "not found",
+            "jsonp({\"error\":",
+            "alert('hi'); jsonp({})",
+        ] {
+            let err = decode::<Value>(body).unwrap_err();
+            assert_eq!(err.body, body);
+        }
+    }
+
+    #[test]
+    fn malformed_json_inside_callback() {
+        let err = decode::<Value>("jsonp({\"error\":})").unwrap_err();
+        assert!(err.reason.starts_with("malformed response"), "{}", err);
+        assert_eq!(err.body, "jsonp({\"error\":})");
+    }
+}
@@ -1,8 +1,28 @@
+pub mod alert;
+pub mod bind;
 pub mod cli;
 pub mod client;
 pub mod config;
+pub mod control;
 pub mod daemon;
+pub mod debug;
+pub mod encoder;
+pub mod error;
+pub mod failover;
+pub mod format;
+pub mod jsonp;
+pub mod lock;
+pub mod metrics;
+pub mod mock;
 pub mod monitor;
+pub mod portal;
+pub mod probe;
+pub mod retry;
+pub mod router;
+pub mod service;
+pub mod state;
+pub mod systemd;
 pub mod tables;
+pub mod usage;
 pub mod user;
 pub mod xencode;
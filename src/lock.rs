@@ -0,0 +1,187 @@
+//! Advisory lock files in the runtime dir, shared by every bitsrun process of a user
+//!
+//! While the system unit runs, its runtime dir (`/run/bitsrun`) is used instead, so that the
+//! processes of every user and the system daemon take turns on the same locks. Users without
+//! write access there lock the files read-only.
+//!
+//! `daemon.lock` is held by a running `keep-alive` daemon, so that a second one refuses to start.
+//! `portal.lock` is held around every login and logout, so that the daemon and the CLI do not
+//! interleave their `get_challenge` and `srun_portal` requests. Both are released by the OS when
+//! their process exits, however it exits.
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::service::SYSTEM_RUNTIME_DIR;
+use crate::state::runtime_dir;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use log::{debug, info, warn};
+
+/// How long to wait for another process logging in or out before going ahead anyway
+const PORTAL_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn lock_path(name: &str) -> Option<PathBuf> {
+    let system_dir = Path::new(SYSTEM_RUNTIME_DIR);
+    match system_dir.is_dir() {
+        true => Some(system_dir.join(name)),
+        false => Some(runtime_dir()?.join(name)),
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create `{}`", dir.display()))?;
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path);
+    or_read_only(file, path)
+        .with_context(|| format!("failed to open lock file `{}`", path.display()))
+}
+
+/// Open `path` read-only instead if it exists but may not be written, as locking works on a
+/// read-only file all the same
+fn or_read_only(file: io::Result<File>, path: &Path) -> io::Result<File> {
+    match file {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied && path.exists() => File::open(path),
+        file => file,
+    }
+}
+
+/// Held by the running `keep-alive` daemon for its whole life
+#[derive(Debug)]
+pub struct DaemonLock {
+    pub path: PathBuf,
+    _file: File,
+}
+
+impl DaemonLock {
+    /// Take the lock, failing if another daemon holds it
+    ///
+    /// Returns `None` if there is no dir to keep the lock in.
+    pub fn acquire() -> Result<Option<Self>> {
+        let Some(path) = lock_path("daemon.lock") else {
+            warn!("No runtime dir for `daemon.lock`, not guarding against a second daemon");
+            return Ok(None);
+        };
+        let mut file = open(&path)?;
+        if file.try_lock().is_err() {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            match pid.trim() {
+                "" => bail!("another keep-alive daemon is running already"),
+                pid => bail!("another keep-alive daemon (pid {}) is running already", pid),
+            }
+        }
+        // record who holds it, for the error above, unless the file is read-only
+        if let Err(e) = file
+            .set_len(0)
+            .and_then(|()| write!(file, "{}", std::process::id()))
+        {
+            debug!("Not recording the pid in `{}`: {}", path.display(), e);
+        }
+        debug!("Acquired `{}`", path.display());
+        Ok(Some(Self { path, _file: file }))
+    }
+}
+
+/// Whether a `keep-alive` daemon of this user, or the system one, is running
+pub fn daemon_running() -> bool {
+    let Some(path) = lock_path("daemon.lock").filter(|path| path.exists()) else {
+        return false;
+    };
+    match File::open(&path) {
+        Ok(file) => file.try_lock_shared().is_err(),
+        Err(_) => false,
+    }
+}
+
+/// Held around a login or logout, released when dropped
+#[derive(Debug)]
+pub struct PortalLock {
+    _file: File,
+}
+
+impl PortalLock {
+    /// Wait until no other bitsrun process is logging in or out, then take the lock
+    ///
+    /// The lock is kept in `dir`, or the shared lock dir if unset. Returns `None` if the lock is
+    /// unavailable, or still held after a minute, in which case the caller goes ahead without it.
+    pub async fn acquire(dir: Option<&Path>) -> Option<Self> {
+        let path = match dir {
+            Some(dir) => dir.join("portal.lock"),
+            None => lock_path("portal.lock")?,
+        };
+        let file = match open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                debug!("Not locking the portal: {:#}", e);
+                return None;
+            }
+        };
+
+        let started = Instant::now();
+        let mut waiting = false;
+        while file.try_lock().is_err() {
+            if started.elapsed() > PORTAL_LOCK_TIMEOUT {
+                warn!(
+                    "Still waiting on `{}` after {:?}, going ahead",
+                    path.display(),
+                    PORTAL_LOCK_TIMEOUT
+                );
+                return None;
+            }
+            if !waiting {
+                info!("Waiting for another bitsrun process to finish logging in or out...");
+                waiting = true;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Some(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::io::ErrorKind;
+    use std::io::Write;
+
+    use super::open;
+    use super::or_read_only;
+
+    #[test]
+    fn locks_files_opened_read_only() {
+        let path = std::env::temp_dir().join(format!("bitsrun-lock-{}", std::process::id()));
+        drop(open(&path).unwrap());
+        // as if opening for writing was denied, which root never is
+        let denied = || Err(io::Error::from(ErrorKind::PermissionDenied));
+
+        let mut held = or_read_only(denied(), &path).unwrap();
+        assert!(held.write_all(b"1").is_err());
+        held.try_lock().unwrap();
+        assert!(or_read_only(denied(), &path).unwrap().try_lock().is_err());
+        assert!(open(&path).unwrap().try_lock().is_err());
+        drop(held);
+        assert!(or_read_only(denied(), &path).unwrap().try_lock().is_ok());
+        let _ = fs::remove_file(&path);
+
+        assert!(or_read_only(denied(), &path).is_err());
+    }
+}
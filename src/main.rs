@@ -1,30 +1,69 @@
+use std::fs;
 use std::io::Write;
+use std::time::Duration;
+use std::time::Instant;
 
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use chrono::Local;
+use chrono::Utc;
 use clap::Parser;
 use enable_ansi_support::enable_ansi_support;
 use owo_colors::OwoColorize;
 use owo_colors::Stream::Stderr;
 use owo_colors::Stream::Stdout;
 
+use bitsrun::bind;
 use bitsrun::cli;
 use bitsrun::client;
+use bitsrun::config;
+use bitsrun::control;
 use bitsrun::daemon;
+use bitsrun::debug;
+use bitsrun::error;
+use bitsrun::format;
+use bitsrun::lock;
+use bitsrun::mock;
+use bitsrun::portal;
+use bitsrun::probe;
+use bitsrun::router;
+use bitsrun::service;
+use bitsrun::state;
 use bitsrun::tables;
+use bitsrun::usage;
 use bitsrun::user;
 
-use cli::{Arguments, ClientArgs, Commands, StatusArgs};
-use client::{get_login_state, SrunClient};
+use bind::BindOptions;
+use cli::{
+    Arguments, ClientArgs, Commands, CtlArgs, DaemonStatusArgs, DebugCommands, DecodeArgs,
+    MockPortalArgs, ProbeArgs, ServiceCommands, ServiceInstallArgs, ServiceUninstallArgs,
+    StatusArgs, UsageArgs,
+};
+use client::{
+    get_login_state, get_login_state_v6, SrunClient, SrunLoginState, SrunPortalResponse,
+    TransportOptions,
+};
 use daemon::SrunDaemon;
-use tables::{print_config_paths, print_login_state};
+use error::SrunError;
+use format::{render_template, StatusFormat};
+use mock::MockPortal;
+use portal::{PortalOverrides, PortalProfile};
+use probe::{probe_connectivity, Connectivity};
+use router::{DeviceState, Router};
+use state::{AcIdCache, DaemonPhase, DaemonState};
+use tables::{
+    print_config_paths, print_daemon_history, print_daemon_state, print_daemon_status,
+    print_device_statuses, print_device_summaries, print_field_checks, print_live_state,
+    print_login_state, print_probe_votes, print_usage,
+};
+use usage::{UsageFormat, UsageSample, UsageStore};
 
 #[tokio::main]
 async fn main() {
     if let Err(err) = cli().await {
         eprintln!(
-            "{} {}: {}",
+            "{} {}: {:#}",
             "bitsrun".if_supports_color(Stderr, |t| t.bright_red()),
             "(error)".if_supports_color(Stderr, |t| t.dimmed()),
             err
@@ -64,18 +103,23 @@ async fn cli() -> Result<()> {
 
     let args = Arguments::parse();
 
-    // reusable http client without proxy and with timeout
-    let http_client = reqwest::Client::builder()
-        .no_proxy()
-        .connect_timeout(std::time::Duration::from_millis(400))
-        .timeout(std::time::Duration::from_millis(400))
-        .build()?;
-
     // commands
     match &args.command {
         // check login status
         Some(Commands::Status(status_args)) => {
-            srun_status(http_client, status_args, args.verbose).await?
+            let overrides = args.portal_overrides();
+            let bind = user::finalize_bind_options(&status_args.config, &args.bind_overrides())?;
+            let http_client = transport(bind).build_client()?;
+            srun_status(http_client, status_args, &overrides, args.verbose).await?
+        }
+
+        // race connectivity probes
+        Some(Commands::Probe(probe_args)) => {
+            let portal =
+                user::finalize_portal_profile(&probe_args.config, &args.portal_overrides())?;
+            let bind = user::finalize_bind_options(&probe_args.config, &args.bind_overrides())?;
+            let http_client = transport(bind).build_client()?;
+            srun_probe(http_client, &portal, probe_args).await?
         }
 
         // login or logout
@@ -85,18 +129,30 @@ async fn cli() -> Result<()> {
                 &client_args.password,
                 client_args.dm,
                 &client_args.config,
+                &args.portal_overrides(),
                 matches!(args.command, Some(Commands::Login(_))),
             )
             .with_context(|| "unable to parse user credentials")?;
+            let bind = user::finalize_bind_options(&client_args.config, &args.bind_overrides())?;
 
-            let srun_client = SrunClient::new(
-                bit_user.username,
-                bit_user.password,
-                Some(http_client),
-                client_args.ip,
-                Some(bit_user.dm),
-            )
-            .await?;
+            let mut builder = SrunClient::builder(bit_user.username, bit_user.password)
+                .transport(transport(bind))
+                .portal(bit_user.portal)
+                .retry(bit_user.retry)
+                .dm(bit_user.dm);
+            if let Some(ac_id) = client_args.ac_id.clone().or(bit_user.ac_id) {
+                builder = builder.ac_id(ac_id);
+            }
+            if let Some(cache) = AcIdCache::load() {
+                builder = builder.ac_id_cache(cache);
+            }
+            if let Some(ip) = client_args.ip {
+                builder = builder.ip(ip);
+            }
+            if let Some(ipv6) = client_args.ipv6 {
+                builder = builder.ipv6(ipv6);
+            }
+            let srun_client = builder.build().await?;
 
             match &args.command {
                 Some(Commands::Login(_)) => {
@@ -111,60 +167,422 @@ async fn cli() -> Result<()> {
 
         Some(Commands::KeepAlive(daemon_args)) => {
             let config_path = daemon_args.config.to_owned();
-            SrunDaemon::run(config_path).await?;
+            SrunDaemon::run(config_path, args.portal_overrides(), args.bind_overrides()).await?;
         }
 
+        Some(Commands::Ctl(ctl_args)) => srun_ctl(ctl_args).await?,
+
+        Some(Commands::DaemonStatus(daemon_status_args)) => srun_daemon_status(daemon_status_args)?,
+
+        Some(Commands::Service(service_args)) => match &service_args.command {
+            ServiceCommands::Install(install_args) => srun_service_install(install_args)?,
+            ServiceCommands::Uninstall(uninstall_args) => srun_service_uninstall(uninstall_args)?,
+        },
+
         Some(Commands::ConfigPaths) => print_config_paths(),
 
+        Some(Commands::Usage(usage_args)) => srun_usage(usage_args)?,
+
+        Some(Commands::MockPortal(mock_args)) => srun_mock_portal(mock_args).await?,
+
+        Some(Commands::Debug(debug_args)) => match &debug_args.command {
+            DebugCommands::Decode(decode_args) => srun_debug_decode(decode_args)?,
+        },
+
         None => {}
     }
 
     Ok(())
 }
 
+/// Transport for portal requests, the same for every command
+fn transport(bind: BindOptions) -> TransportOptions {
+    TransportOptions {
+        bind,
+        ..Default::default()
+    }
+}
+
 async fn srun_status(
     http_client: reqwest::Client,
     status_args: &StatusArgs,
+    overrides: &PortalOverrides,
     verbose: bool,
 ) -> Result<()> {
-    // only verbose on args.verbose = true and not outputting json
-    let login_state = get_login_state(&http_client, verbose).await?;
+    let portal = user::finalize_portal_profile(&status_args.config, overrides)?;
+    if status_args.devices {
+        return srun_devices_status(http_client, portal, status_args).await;
+    }
+    if status_args.watch {
+        return srun_status_watch(http_client, portal, status_args.interval).await;
+    }
 
-    // output json
-    if status_args.json & !verbose {
-        let raw_json = serde_json::to_string(&login_state)?;
-        println!("{}", raw_json);
+    let login_state = get_login_state(&http_client, &portal, verbose).await?;
+    let login_state_v6 = match portal.double_stack {
+        true => get_login_state_v6(&http_client, &portal, verbose).await?,
+        false => None,
+    };
+
+    // usage is kept per account, which the portal reports when logged in
+    if let Some(username) = login_state
+        .user_name
+        .as_deref()
+        .filter(|_| status_args.record)
+    {
+        if let Some(store) = UsageStore::open() {
+            store.append(&UsageSample::new(username, &login_state))?;
+        }
+    }
+
+    // both addresses labeled by family in dual-stack mode
+    let states = match portal.double_stack {
+        true => vec![
+            ("ipv4", Some(&login_state)),
+            ("ipv6", login_state_v6.as_ref()),
+        ],
+        false => vec![("ipv4", Some(&login_state))],
+    };
+    if let Some(template) = &status_args.template {
+        for state in states.iter().filter_map(|(_, state)| *state) {
+            println!("{}", render_template(template, state)?);
+        }
         return Ok(());
     }
+    match status_args.format() {
+        StatusFormat::Table => {}
+        StatusFormat::Json => {
+            println!("{}", format::render(&states, StatusFormat::Json)?);
+            return Ok(());
+        }
+        format => {
+            print!("{}", format::render(&states, format)?);
+            return Ok(());
+        }
+    }
 
     // output human readable
-    match login_state.error.as_str() {
-        "ok" => {
-            println!(
-                "{} {} {} is online",
-                "bitsrun:".if_supports_color(Stdout, |t| t.bright_green()),
-                login_state
-                    .online_ip
-                    .to_string()
-                    .if_supports_color(Stdout, |t| t.underline()),
-                format!("({})", login_state.user_name.clone().unwrap_or_default())
-                    .if_supports_color(Stdout, |t| t.dimmed())
-            );
+    print_status_line(&login_state);
+    if portal.double_stack {
+        match &login_state_v6 {
+            Some(state) => print_status_line(state),
+            None => println!(
+                "{} portal has no `{}`, unable to check the IPv6 address",
+                "warning:".if_supports_color(Stdout, |t| t.yellow()),
+                "url_v6".if_supports_color(Stdout, |t| t.underline())
+            ),
+        }
+    }
+    if login_state.srun_error() == SrunError::Ok {
+        // print status table
+        print_login_state(login_state);
+    }
+    Ok(())
+}
+
+/// Redraw the login state every `interval` seconds, with throughput from the byte counters
+async fn srun_status_watch(
+    http_client: reqwest::Client,
+    portal: PortalProfile,
+    interval: u64,
+) -> Result<()> {
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    let mut prev: Option<(Instant, SrunLoginState)> = None;
+    let mut dropped_at = None;
 
-            // print status table
-            print_login_state(login_state);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = tokio::signal::ctrl_c() => return Ok(()),
         }
-        _ => {
+
+        let state = get_login_state(&http_client, &portal, false).await;
+        let now = Instant::now();
+
+        // clear the screen and move the cursor home, then redraw
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "{} every {}s, press Ctrl-C to exit {}",
+            "bitsrun: watching".if_supports_color(Stdout, |t| t.blue()),
+            interval.max(1),
+            format!("(updated {})", Local::now().format("%H:%M:%S"))
+                .if_supports_color(Stdout, |t| t.dimmed())
+        );
+
+        let state = match state {
+            Ok(state) => state,
+            Err(e) => {
+                println!(
+                    "{} {:#}",
+                    "warning:".if_supports_color(Stdout, |t| t.yellow()),
+                    e
+                );
+                continue;
+            }
+        };
+        let online = state.srun_error() == SrunError::Ok;
+        let was_online = prev
+            .as_ref()
+            .is_some_and(|(_, state)| state.srun_error() == SrunError::Ok);
+        match (was_online, online) {
+            (true, false) => dropped_at = Some(Local::now()),
+            (_, true) => dropped_at = None,
+            _ => {}
+        }
+
+        print_status_line(&state);
+        if let Some(at) = dropped_at {
             println!(
-                "{} {} is offline",
-                "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
-                login_state
-                    .online_ip
-                    .to_string()
+                "{} session dropped at {}",
+                "bitsrun:".if_supports_color(Stdout, |t| t.red()),
+                at.format("%Y-%m-%d %H:%M:%S")
                     .if_supports_color(Stdout, |t| t.underline())
             );
         }
+        if online {
+            let rates = prev.as_ref().and_then(|(at, prev)| {
+                let secs = now.duration_since(*at).as_secs_f64();
+                let rate = |prev: Option<u64>, cur: Option<u64>| {
+                    Some((cur?.saturating_sub(prev?) as f64 / secs) as u64)
+                };
+                Some((
+                    rate(prev.bytes_in, state.bytes_in)?,
+                    rate(prev.bytes_out, state.bytes_out)?,
+                ))
+            });
+            print_live_state(&state, rates);
+        }
+        std::io::stdout().flush()?;
+        prev = Some((now, state));
+    }
+}
+
+async fn srun_ctl(ctl_args: &CtlArgs) -> Result<()> {
+    let socket = match &ctl_args.socket {
+        Some(socket) => socket.clone(),
+        None => {
+            let user_socket = control::default_socket_path();
+            let system_socket = std::path::Path::new(service::SYSTEM_CONTROL_SOCKET);
+            match user_socket {
+                // a system service listens on the system socket, not on this user's
+                Some(socket) if socket.exists() => socket,
+                _ if system_socket.exists() => system_socket.to_path_buf(),
+                user_socket => user_socket.context("unable to locate the control socket")?,
+            }
+        }
+    };
+    let resp = control::send_command(&socket, ctl_args.command).await?;
+
+    if ctl_args.json {
+        println!("{}", serde_json::to_string(&resp)?);
+    }
+    if !resp.ok {
+        bail!("daemon refused `{:?}`: {}", ctl_args.command, resp.message)
+    }
+    if ctl_args.json {
+        return Ok(());
+    }
+
+    println!(
+        "{} {}",
+        "bitsrun:".if_supports_color(Stdout, |t| t.green()),
+        resp.message
+    );
+    if let Some(status) = &resp.status {
+        print_daemon_status(status);
+        if let Some(login_state) = &status.login_state {
+            print_status_line(login_state);
+        }
+        if !status.devices.is_empty() {
+            print_device_summaries(&status.devices);
+        }
+    }
+    Ok(())
+}
+
+fn srun_daemon_status(daemon_status_args: &DaemonStatusArgs) -> Result<()> {
+    let user_state = DaemonState::path();
+    let system_state = std::path::Path::new(service::SYSTEM_DAEMON_STATE);
+    let path = match user_state {
+        // the system service keeps its state in its own state dir, not in this user's
+        Some(path) if path.exists() => path,
+        _ if system_state.exists() => system_state.to_path_buf(),
+        user_state => user_state.context("unable to locate the state dir")?,
+    };
+    let Some(mut state) = DaemonState::load_from(path.clone())? else {
+        println!(
+            "{} no daemon state found in `{}`, has `{}` run yet?",
+            "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+            path.display().if_supports_color(Stdout, |t| t.underline()),
+            "bitsrun keep-alive".if_supports_color(Stdout, |t| t.underline())
+        );
+        return Ok(());
+    };
+    // the state file outlives a crashed or killed daemon
+    let running = lock::daemon_running();
+    let history = state
+        .history
+        .len()
+        .saturating_sub(daemon_status_args.history);
+    state.history.drain(..history);
+
+    if daemon_status_args.json {
+        let mut json = serde_json::to_value(&state)?;
+        json["running"] = running.into();
+        println!("{}", serde_json::to_string(&json)?);
+        return Ok(());
+    }
+
+    if !running && state.phase != DaemonPhase::Stopped {
+        println!(
+            "{} the daemon is not running, but did not stop cleanly (crashed or killed?)",
+            "warning:".if_supports_color(Stdout, |t| t.yellow()),
+        );
+    }
+    print_daemon_state(&state, running);
+    if !state.history.is_empty() {
+        print_daemon_history(&state.history);
+    }
+    Ok(())
+}
+
+fn srun_usage(usage_args: &UsageArgs) -> Result<()> {
+    let store = UsageStore::open().context("unable to locate the data dir")?;
+    let samples = store.load()?;
+    let rows = usage::summarize(
+        &samples,
+        usage_args.period,
+        usage_args.username.as_deref(),
+        Utc::now().timestamp(),
+    );
+    let rows = &rows[rows.len().saturating_sub(usage_args.last)..];
+
+    match usage_args.format {
+        UsageFormat::Json => println!("{}", serde_json::to_string(rows)?),
+        UsageFormat::Csv => print!("{}", usage::to_csv(rows)),
+        UsageFormat::Table if rows.is_empty() => println!(
+            "{} no usage found in `{}`, run `{}` or `{}` to record some",
+            "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+            store
+                .path
+                .display()
+                .if_supports_color(Stdout, |t| t.underline()),
+            "bitsrun keep-alive".if_supports_color(Stdout, |t| t.underline()),
+            "bitsrun status --record".if_supports_color(Stdout, |t| t.underline())
+        ),
+        UsageFormat::Table => print_usage(rows),
+    }
+    Ok(())
+}
+
+async fn srun_devices_status(
+    http_client: reqwest::Client,
+    portal: PortalProfile,
+    status_args: &StatusArgs,
+) -> Result<()> {
+    let devices = user::finalize_devices(&status_args.config)?;
+    let router = Router::new(http_client, portal, devices, String::new(), String::new());
+    let statuses = router.check().await;
+
+    let format = status_args.format();
+    if !matches!(format, StatusFormat::Table | StatusFormat::Json) {
+        bail!("`status --devices` only supports the table and json formats")
+    }
+    if format == StatusFormat::Json {
+        let raw_json: Vec<_> = statuses
+            .iter()
+            .map(|status| {
+                serde_json::json!({
+                    "name": status.device.name(),
+                    "ip": status.device.ip,
+                    "state": status.state.to_string(),
+                    "login_state": match &status.state {
+                        DeviceState::Online(state) => Some(state),
+                        _ => None,
+                    },
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string(&raw_json)?);
+        return Ok(());
+    }
+
+    print_device_statuses(&statuses);
+    Ok(())
+}
+
+fn print_status_line(login_state: &SrunLoginState) {
+    match login_state.srun_error() {
+        SrunError::Ok => println!(
+            "{} {} {} is online",
+            "bitsrun:".if_supports_color(Stdout, |t| t.bright_green()),
+            login_state
+                .online_ip
+                .to_string()
+                .if_supports_color(Stdout, |t| t.underline()),
+            format!("({})", login_state.user_name.clone().unwrap_or_default())
+                .if_supports_color(Stdout, |t| t.dimmed())
+        ),
+        _ => println!(
+            "{} {} is offline",
+            "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+            login_state
+                .online_ip
+                .to_string()
+                .if_supports_color(Stdout, |t| t.underline())
+        ),
     };
+}
+
+async fn srun_probe(
+    http_client: reqwest::Client,
+    portal: &PortalProfile,
+    probe_args: &ProbeArgs,
+) -> Result<()> {
+    let report = probe_connectivity(&http_client, &portal.connectivity, &portal.ac_id).await;
+
+    if probe_args.json {
+        let raw_json = serde_json::json!({
+            "connectivity": match &report.connectivity {
+                Connectivity::Online => "online",
+                Connectivity::Intercepted(_) => "intercepted",
+                Connectivity::Unreachable => "unreachable",
+            },
+            "ac_id": match &report.connectivity {
+                Connectivity::Intercepted(ac_id) => ac_id.clone(),
+                _ => None,
+            },
+            "decided_by": report.decided_by,
+            "votes": report
+                .votes
+                .iter()
+                .map(|(url, vote)| serde_json::json!({ "url": url, "vote": vote.to_string() }))
+                .collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string(&raw_json)?);
+        return Ok(());
+    }
+
+    let decided_by = match &report.decided_by {
+        Some(url) => format!("(decided by {})", url),
+        None => String::from("(no verdict reached the quorum)"),
+    };
+    println!(
+        "{} {} {}",
+        match report.connectivity {
+            Connectivity::Online => "bitsrun:"
+                .if_supports_color(Stdout, |t| t.bright_green())
+                .to_string(),
+            Connectivity::Intercepted(_) => "bitsrun:"
+                .if_supports_color(Stdout, |t| t.blue())
+                .to_string(),
+            Connectivity::Unreachable => "bitsrun:"
+                .if_supports_color(Stdout, |t| t.red())
+                .to_string(),
+        },
+        report.connectivity,
+        decided_by.if_supports_color(Stdout, |t| t.dimmed())
+    );
+    print_probe_votes(&report.votes);
     Ok(())
 }
 
@@ -173,9 +591,35 @@ async fn srun_login(
     client_args: &ClientArgs,
     verbose: bool,
 ) -> Result<()> {
-    let resp = srun_client.login(client_args.force, verbose).await?;
-    match resp.error.as_str() {
-        "ok" => println!(
+    let resp = srun_client
+        .login_with_retry(client_args.force, verbose)
+        .await;
+    if let Ok(resp) = &resp {
+        print_login_response(resp);
+    }
+
+    // in dual-stack mode, the IPv6 address is logged in regardless of the IPv4 outcome
+    if srun_client.portal.double_stack {
+        match srun_client.login_v6(client_args.force, verbose).await {
+            Ok(Some(resp_v6)) => print_login_response(&resp_v6),
+            Ok(None) => println!(
+                "{} no IPv6 address found, specify one with `{}`",
+                "warning:".if_supports_color(Stdout, |t| t.yellow()),
+                "--ipv6".if_supports_color(Stdout, |t| t.underline())
+            ),
+            Err(e) => println!(
+                "{} {}",
+                "warning:".if_supports_color(Stdout, |t| t.yellow()),
+                e
+            ),
+        }
+    }
+    resp.map(|_| ())
+}
+
+fn print_login_response(resp: &SrunPortalResponse) {
+    match resp.srun_error() {
+        SrunError::Ok => println!(
             "{} {} {} logged in",
             "bitsrun:".if_supports_color(Stdout, |t| t.bright_green()),
             resp.online_ip
@@ -184,14 +628,13 @@ async fn srun_login(
             format!("({})", resp.username.clone().unwrap_or_default())
                 .if_supports_color(Stdout, |t| t.dimmed())
         ),
-        _ => println!(
+        err => println!(
             "{} failed to login, {} {}",
             "bitsrun:".if_supports_color(Stdout, |t| t.red()),
-            resp.error,
+            err,
             format!("({})", resp.error_msg).if_supports_color(Stdout, |t| t.dimmed())
         ),
     };
-    Ok(())
 }
 
 async fn srun_logout(
@@ -199,21 +642,223 @@ async fn srun_logout(
     client_args: &ClientArgs,
     verbose: bool,
 ) -> Result<()> {
-    let resp = srun_client.logout(client_args.force, verbose).await?;
-    match resp.error.as_str() {
-        "ok" | "logout_ok" => println!(
-            "{} {} logged out",
-            "bitsrun:".if_supports_color(Stdout, |t| t.green()),
-            resp.online_ip
-                .to_string()
-                .if_supports_color(Stdout, |t| t.underline())
+    let resp = srun_client.logout(client_args.force, verbose).await;
+    let logged_out = match &resp {
+        Ok(resp) => print_logout_response(resp),
+        Err(_) => false,
+    };
+
+    // in dual-stack mode, the IPv6 address is logged out regardless of the IPv4 outcome
+    if srun_client.portal.double_stack {
+        match srun_client.logout_v6(client_args.force, verbose).await {
+            Ok(Some(resp_v6)) => {
+                print_logout_response(&resp_v6);
+            }
+            Ok(None) => println!(
+                "{} no IPv6 address found, specify one with `{}`",
+                "warning:".if_supports_color(Stdout, |t| t.yellow()),
+                "--ipv6".if_supports_color(Stdout, |t| t.underline())
+            ),
+            Err(e) => println!(
+                "{} {}",
+                "warning:".if_supports_color(Stdout, |t| t.yellow()),
+                e
+            ),
+        }
+    }
+
+    // it would log back in on its next check
+    if logged_out && lock::daemon_running() {
+        println!(
+            "{} a keep-alive daemon is running and will log in again, use `{}` to keep it logged out",
+            "warning:".if_supports_color(Stdout, |t| t.yellow()),
+            "bitsrun ctl logout".if_supports_color(Stdout, |t| t.underline())
+        );
+    }
+    resp.map(|_| ())
+}
+
+/// Print the outcome of a logout, returning whether it succeeded
+fn print_logout_response(resp: &SrunPortalResponse) -> bool {
+    match resp.srun_error() {
+        SrunError::Ok | SrunError::LogoutOk => {
+            println!(
+                "{} {} logged out",
+                "bitsrun:".if_supports_color(Stdout, |t| t.green()),
+                resp.online_ip
+                    .to_string()
+                    .if_supports_color(Stdout, |t| t.underline())
+            );
+            true
+        }
+        err => {
+            println!(
+                "{} failed to logout, {} {}",
+                "bitsrun:".if_supports_color(Stdout, |t| t.red()),
+                err,
+                format!("({})", resp.error_msg).if_supports_color(Stdout, |t| t.dimmed())
+            );
+            false
+        }
+    }
+}
+
+fn srun_service_install(install_args: &ServiceInstallArgs) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        bail!("`bitsrun service` only supports systemd on Linux")
+    }
+    // the unit runs from another working dir (and maybe as another user), use absolute paths
+    let config = config::validate_config_file(&install_args.config)?;
+    let config = fs::canonicalize(&config)
+        .with_context(|| format!("failed to resolve config file `{}`", config))?;
+    let exe = std::env::current_exe().and_then(fs::canonicalize)?;
+    let unit = service::render_unit(&exe, &config, install_args.user);
+    if install_args.print {
+        print!("{}", unit);
+        return Ok(());
+    }
+
+    let path = service::unit_path(install_args.user)?;
+    service::write_unit(&path, &unit).with_context(|| match install_args.user {
+        true => "failed to install the user unit".to_string(),
+        false => "failed to install the system unit, run with `sudo` or pass `--user`".to_string(),
+    })?;
+    println!(
+        "{} wrote {} (with config `{}`)",
+        "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+        path.display().if_supports_color(Stdout, |t| t.underline()),
+        config.display()
+    );
+    service::systemctl(install_args.user, &["daemon-reload"])?;
+
+    let systemctl = match install_args.user {
+        true => "systemctl --user",
+        false => "systemctl",
+    };
+    if install_args.no_enable {
+        println!(
+            "{} start it with `{} enable --now {}`",
+            "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+            systemctl,
+            service::UNIT_NAME
+        );
+        return Ok(());
+    }
+    service::systemctl(install_args.user, &["enable", "--now", service::UNIT_NAME])?;
+    println!(
+        "{} enabled and started {}, see `{} status {}`",
+        "bitsrun:".if_supports_color(Stdout, |t| t.green()),
+        service::UNIT_NAME,
+        systemctl,
+        service::UNIT_NAME
+    );
+    match install_args.user {
+        true => println!(
+            "{} run `loginctl enable-linger` to keep it running while logged out",
+            "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
         ),
-        _ => println!(
-            "{} failed to logout, {} {}",
-            "bitsrun:".if_supports_color(Stdout, |t| t.red()),
-            resp.error,
-            format!("({})", resp.error_msg).if_supports_color(Stdout, |t| t.dimmed())
+        false => println!(
+            "{} control it with `sudo bitsrun ctl --socket {} status`",
+            "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+            service::SYSTEM_CONTROL_SOCKET
         ),
+    }
+    Ok(())
+}
+
+fn srun_service_uninstall(uninstall_args: &ServiceUninstallArgs) -> Result<()> {
+    let path = service::unit_path(uninstall_args.user)?;
+    if !path.exists() {
+        bail!(
+            "no unit installed at `{}`",
+            path.display().if_supports_color(Stdout, |t| t.underline())
+        )
+    }
+
+    // the unit may be installed but never enabled or started
+    if let Err(e) = service::systemctl(
+        uninstall_args.user,
+        &["disable", "--now", service::UNIT_NAME],
+    ) {
+        println!(
+            "{} {:#}, removing the unit anyway",
+            "warning:".if_supports_color(Stdout, |t| t.yellow()),
+            e
+        );
+    }
+    fs::remove_file(&path).with_context(|| format!("failed to remove `{}`", path.display()))?;
+    service::systemctl(uninstall_args.user, &["daemon-reload"])?;
+    println!(
+        "{} removed {}",
+        "bitsrun:".if_supports_color(Stdout, |t| t.green()),
+        path.display().if_supports_color(Stdout, |t| t.underline())
+    );
+    Ok(())
+}
+
+async fn srun_mock_portal(mock_args: &MockPortalArgs) -> Result<()> {
+    let portal = MockPortal::new(&mock_args.ac_id).with_encoder(mock_args.encoder);
+    for user in &mock_args.users {
+        let (username, password) = user.split_once(':').with_context(|| {
+            format!("invalid user `{}`, expected `<username>:<password>`", user)
+        })?;
+        portal.add_user(username, password);
+    }
+    for fault in &mock_args.faults {
+        portal.inject(fault.clone());
+    }
+
+    let listener = tokio::net::TcpListener::bind(mock_args.listen)
+        .await
+        .with_context(|| format!("failed to listen on `{}`", mock_args.listen))?;
+    println!(
+        "{} mock portal listening, use it with `{}`",
+        "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+        format!("--portal http://{}", mock_args.listen)
+            .if_supports_color(Stdout, |t| t.underline())
+    );
+
+    tokio::select! {
+        res = portal.serve(listener) => res,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+fn srun_debug_decode(decode_args: &DecodeArgs) -> Result<()> {
+    let expected = debug::Expected {
+        username: decode_args.username.clone(),
+        password: decode_args.password.clone(),
+        ip: decode_args.ip.clone(),
+        ac_id: decode_args.ac_id.clone(),
     };
+    let decoded = debug::decode_login(
+        &decode_args.request,
+        &decode_args.token,
+        &expected,
+        decode_args.encoder,
+    )?;
+
+    println!(
+        "{} decoded `{}`",
+        "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+        "info".if_supports_color(Stdout, |t| t.underline())
+    );
+    println!("{}", serde_json::to_string_pretty(&decoded.info)?);
+    print_field_checks(&decoded.checks);
+
+    let mismatches = decoded.mismatches();
+    match mismatches.is_empty() {
+        true => println!(
+            "{} all checked fields match",
+            "bitsrun:".if_supports_color(Stdout, |t| t.bright_green())
+        ),
+        false => println!(
+            "{} mismatched {}",
+            "bitsrun:".if_supports_color(Stdout, |t| t.red()),
+            mismatches
+                .join(", ")
+                .if_supports_color(Stdout, |t| t.underline())
+        ),
+    }
     Ok(())
 }
@@ -0,0 +1,307 @@
+//! Prometheus metrics of the keep-alive daemon, served on `/metrics` when `metrics` is set
+//!
+//! ```json
+//! {
+//!   "metrics": "127.0.0.1:9477"
+//! }
+//! ```
+//!
+//! Login attempts, login states and portal request latencies are recorded process-wide as they
+//! happen, whichever command made the request.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::client::SrunLoginState;
+use crate::client::SrunPortalResponse;
+use crate::error::SrunError;
+use crate::format::label_value;
+use crate::format::login_state_metrics;
+
+use anyhow::Context;
+use anyhow::Result;
+use chrono::Utc;
+use log::{debug, info, warn};
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+
+/// Upper bounds of the portal request latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Cumulative count of each of [`LATENCY_BUCKETS`]
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Whose login state is observed: this host's address of a family, or a LAN device's
+type StateKey = (&'static str, Option<IpAddr>);
+
+#[derive(Debug)]
+struct Metrics {
+    /// Last observed login state of each family of this host, and of each device
+    states: BTreeMap<StateKey, SrunLoginState>,
+    /// Unix timestamp of the last successful login
+    last_login: Option<i64>,
+    login_attempts: u64,
+    /// Failed logins by portal error code, or `unreachable` if the portal was not reached
+    login_failures: BTreeMap<String, u64>,
+    /// Portal request latency by endpoint
+    latency: BTreeMap<&'static str, Histogram>,
+}
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    states: BTreeMap::new(),
+    last_login: None,
+    login_attempts: 0,
+    login_failures: BTreeMap::new(),
+    latency: BTreeMap::new(),
+});
+
+fn metrics() -> MutexGuard<'static, Metrics> {
+    METRICS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Record the outcome of a login attempt
+pub fn record_login(result: &Result<SrunPortalResponse>) {
+    let mut metrics = metrics();
+    metrics.login_attempts += 1;
+    let code = match result {
+        Ok(resp) => match resp.srun_error() {
+            SrunError::Ok | SrunError::IpAlreadyOnline => {
+                metrics.last_login = Some(Utc::now().timestamp());
+                return;
+            }
+            err => err
+                .codes()
+                .first()
+                .map_or_else(|| err.to_string(), |code| code.to_string()),
+        },
+        Err(_) => "unreachable".to_string(),
+    };
+    *metrics.login_failures.entry(code).or_default() += 1;
+}
+
+/// Record the login state of this host, or of a LAN `device`
+///
+/// Only the latest state is kept, so that a changed address replaces the old one.
+pub fn record_login_state(state: &SrunLoginState, device: Option<IpAddr>) {
+    let family = match state.online_ip {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    };
+    metrics().states.insert((family, device), state.clone());
+}
+
+/// Unix timestamp of the last successful login by this process
+pub fn last_login() -> Option<i64> {
+    metrics().last_login
+}
+
+/// Await a portal request, recording its latency under `endpoint`
+pub async fn timed<T>(endpoint: &'static str, request: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let output = request.await;
+    metrics()
+        .latency
+        .entry(endpoint)
+        .or_default()
+        .observe(started.elapsed().as_secs_f64());
+    output
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let metrics = metrics();
+    let states: Vec<(&str, &SrunLoginState)> = metrics
+        .states
+        .iter()
+        .map(|((family, _), state)| (*family, state))
+        .collect();
+    let mut out = login_state_metrics(&states);
+
+    if let Some(last_login) = metrics.last_login {
+        out.push_str(
+            "# HELP bitsrun_seconds_since_last_login Seconds since the last successful login\n",
+        );
+        out.push_str("# TYPE bitsrun_seconds_since_last_login gauge\n");
+        out.push_str(&format!(
+            "bitsrun_seconds_since_last_login {}\n",
+            Utc::now().timestamp() - last_login
+        ));
+    }
+
+    out.push_str("# HELP bitsrun_login_attempts_total Login attempts\n");
+    out.push_str("# TYPE bitsrun_login_attempts_total counter\n");
+    out.push_str(&format!(
+        "bitsrun_login_attempts_total {}\n",
+        metrics.login_attempts
+    ));
+    out.push_str("# HELP bitsrun_login_failures_total Failed login attempts by error code\n");
+    out.push_str("# TYPE bitsrun_login_failures_total counter\n");
+    for (code, count) in &metrics.login_failures {
+        out.push_str(&format!(
+            "bitsrun_login_failures_total{{code=\"{}\"}} {}\n",
+            label_value(code),
+            count
+        ));
+    }
+
+    out.push_str("# HELP bitsrun_portal_request_duration_seconds Portal request latency\n");
+    out.push_str("# TYPE bitsrun_portal_request_duration_seconds histogram\n");
+    for (endpoint, histogram) in &metrics.latency {
+        for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            out.push_str(&format!(
+                "bitsrun_portal_request_duration_seconds_bucket{{endpoint=\"{}\",le=\"{}\"}} {}\n",
+                endpoint, bound, count
+            ));
+        }
+        out.push_str(&format!(
+            "bitsrun_portal_request_duration_seconds_bucket{{endpoint=\"{}\",le=\"+Inf\"}} {}\n",
+            endpoint, histogram.count
+        ));
+        out.push_str(&format!(
+            "bitsrun_portal_request_duration_seconds_sum{{endpoint=\"{}\"}} {}\n",
+            endpoint, histogram.sum
+        ));
+        out.push_str(&format!(
+            "bitsrun_portal_request_duration_seconds_count{{endpoint=\"{}\"}} {}\n",
+            endpoint, histogram.count
+        ));
+    }
+    out
+}
+
+/// Bind the metrics endpoint, failing early on a taken port
+pub async fn bind(listen: SocketAddr) -> Result<TcpListener> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("failed to serve metrics on `{}`", listen))?;
+    info!("Serving metrics on http://{}/metrics", listen);
+    Ok(listener)
+}
+
+/// Serve `/metrics` on `listener` forever
+pub async fn serve(listener: TcpListener) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // e.g., out of file descriptors, which may well pass
+                warn!("Failed to accept a metrics connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream).await {
+                debug!("Metrics connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream) -> Result<()> {
+    // only the request line matters, read until the end of the headers
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < 16 * 1024 {
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut chunk)).await??;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let request = String::from_utf8_lossy(&buf);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let (method, target) = (request_line.next(), request_line.next());
+
+    let (status, body) = match (method, target.map(|t| t.split('?').next().unwrap_or(t))) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        _ => ("404 Not Found", "not found, try /metrics\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::record_login_state;
+    use super::render;
+    use super::serve;
+
+    use crate::client::SrunLoginState;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+
+    fn state(ip: &str) -> SrunLoginState {
+        serde_json::from_value(serde_json::json!({
+            "error": "ok",
+            "online_ip": ip,
+            "user_name": "alice",
+            "sum_bytes": 1024,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn keeps_the_latest_state_and_serves_it() {
+        record_login_state(&state("10.1.2.3"), None);
+        record_login_state(&state("10.1.2.4"), None);
+        record_login_state(&state("10.9.0.1"), Some("10.9.0.1".parse().unwrap()));
+        let rendered = render();
+        assert!(!rendered.contains("10.1.2.3"));
+        assert!(rendered.contains(r#"bitsrun_online{family="ipv4",ip="10.1.2.4",user="alice"} 1"#));
+        assert!(rendered
+            .contains(r#"bitsrun_sum_bytes{family="ipv4",ip="10.9.0.1",user="alice"} 1024"#));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("bitsrun_login_attempts_total"));
+    }
+}
@@ -0,0 +1,560 @@
+//! A mock SRUN portal for offline testing
+//!
+//! Serves `/cgi-bin/get_challenge`, `/cgi-bin/srun_portal`, `/cgi-bin/rad_user_info` and
+//! `/cgi-bin/rad_user_dm` with JSONP responses over plain HTTP/1.1, verifying `chksum`, `info`
+//! and the `{MD5}` password the same way the client computes them. Online state is kept per IP.
+//!
+//! Connectivity probes are served as well: `/generate_204` answers `204` to IPs logged in and
+//! redirects the others to the portal with the `ac_id`, `/probe/online` always answers `204`,
+//! `/probe/page` and `/probe/blocked` are intercepted pages with and without an `ac_id` link, and
+//! `/probe/slow` answers `204` only after 10 seconds.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use bitsrun::mock::{MockFault, MockPortal};
+//!
+//! let portal = MockPortal::new("1");
+//! portal.add_user("1120230000", "password");
+//! portal.inject(MockFault::Error("ip_already_online_error".into()));
+//!
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:8055").await?;
+//! portal.serve(listener).await
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::client::dm_logout_sign;
+use crate::encoder::EncoderKind;
+use crate::portal::PortalProfile;
+use crate::xencode::fkbase64_decode;
+use crate::xencode::xdecode;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use chrono::Utc;
+use hmac::Hmac;
+use hmac::Mac;
+use log::debug;
+use md5::Md5;
+use serde_json::json;
+use serde_json::Value;
+use sha1::Digest;
+use sha1::Sha1;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+
+const MOCK_SRUN_VER: &str = "SRunCGIAuthIntfSvr V1.18 B20200101";
+const MOCK_SYSVER: &str = "1.01.20200101";
+
+/// Faults that can be injected into the mock portal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockFault {
+    /// Delay every response
+    Latency(Duration),
+    /// Pretend the gateway sees requests coming from another IP
+    IpMismatch(IpAddr),
+    /// Respond with truncated JSONP on every `/cgi-bin/` endpoint
+    MalformedJsonp,
+    /// Fail login and logout with the given error code (e.g. `ip_already_online_error`, `E2616`)
+    Error(String),
+}
+
+impl FromStr for MockFault {
+    type Err = anyhow::Error;
+
+    /// Parse faults as `latency:<ms>`, `ip-mismatch:<ip>`, `malformed-jsonp` or `error:<code>`
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, value) = s.split_once(':').unwrap_or((s, ""));
+        match kind {
+            "latency" => Ok(MockFault::Latency(Duration::from_millis(value.parse()?))),
+            "ip-mismatch" => Ok(MockFault::IpMismatch(value.parse()?)),
+            "malformed-jsonp" => Ok(MockFault::MalformedJsonp),
+            "error" if !value.is_empty() => Ok(MockFault::Error(value.to_string())),
+            _ => Err(anyhow!(
+                "unknown fault `{}`, expected `latency:<ms>`, `ip-mismatch:<ip>`, \
+                `malformed-jsonp` or `error:<code>`",
+                s
+            )),
+        }
+    }
+}
+
+/// A device that is logged in to the mock portal
+#[derive(Debug, Clone)]
+struct MockSession {
+    username: String,
+    add_time: i64,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    users: HashMap<String, String>,
+    challenges: HashMap<String, String>,
+    online: HashMap<String, MockSession>,
+    faults: Vec<MockFault>,
+    issued: u64,
+    login_attempts: u64,
+}
+
+/// The mock portal, cheaply cloneable and shared between connections
+#[derive(Debug, Clone)]
+pub struct MockPortal {
+    ac_id: String,
+    profile: PortalProfile,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockPortal {
+    /// Create a mock portal that redirects captive portal requests with the given `ac_id`
+    pub fn new(ac_id: &str) -> Self {
+        Self {
+            ac_id: ac_id.to_string(),
+            profile: PortalProfile::default(),
+            state: Arc::new(Mutex::new(MockState::default())),
+        }
+    }
+
+    /// Verify logins with another encoder than `srun_bx1`
+    pub fn with_encoder(mut self, encoder: EncoderKind) -> Self {
+        self.profile.encoder = encoder;
+        self
+    }
+
+    /// Register an account that is allowed to login
+    pub fn add_user(&self, username: &str, password: &str) {
+        self.lock()
+            .users
+            .insert(username.to_string(), password.to_string());
+    }
+
+    /// Inject a fault, which stays active until [`MockPortal::clear_faults`]
+    pub fn inject(&self, fault: MockFault) {
+        self.lock().faults.push(fault);
+    }
+
+    pub fn clear_faults(&self) {
+        self.lock().faults.clear();
+    }
+
+    /// Login requests received so far, whether they succeeded or not
+    pub fn login_attempts(&self) -> u64 {
+        self.lock().login_attempts
+    }
+
+    /// Whether `ip` is currently logged in
+    pub fn is_online(&self, ip: &IpAddr) -> bool {
+        self.lock().online.contains_key(&ip.to_string())
+    }
+
+    /// Accept connections on `listener` forever
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let portal = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = portal.handle_connection(stream, peer.ip()).await {
+                    debug!("[Mock] connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream, peer: IpAddr) -> Result<()> {
+        // only the request line and headers are needed, all parameters are in the query string
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < 16 * 1024 {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let request = String::from_utf8_lossy(&buf);
+        let target = request
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .ok_or_else(|| anyhow!("malformed request line"))?;
+        let url = url::Url::parse(&format!("http://mock{}", target))?;
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        debug!("[Mock] {} {}?{:?}", peer, url.path(), params);
+
+        let (latency, seen_ip, malformed) = {
+            let state = self.lock();
+            let mut latency = Duration::ZERO;
+            let mut seen_ip = peer;
+            let mut malformed = false;
+            for fault in &state.faults {
+                match fault {
+                    MockFault::Latency(d) => latency += *d,
+                    MockFault::IpMismatch(ip) => seen_ip = *ip,
+                    MockFault::MalformedJsonp => malformed = true,
+                    MockFault::Error(_) => {}
+                }
+            }
+            (latency, seen_ip, malformed)
+        };
+        tokio::time::sleep(latency).await;
+
+        let callback = params.get("callback").map_or("jsonp", |c| c.as_str());
+        let response = match url.path() {
+            "/" => redirect(&format!("/srun_portal_pc?ac_id={}&theme=bit", self.ac_id)),
+            "/srun_portal_pc" => respond(200, "text/html", "<html>mock srun portal</html>"),
+            "/generate_204" => match self.is_online(&seen_ip) {
+                true => respond(204, "text/plain", ""),
+                false => redirect(&format!("/srun_portal_pc?ac_id={}&theme=bit", self.ac_id)),
+            },
+            "/probe/online" => respond(204, "text/plain", ""),
+            "/probe/page" => respond(
+                200,
+                "text/html",
+                &format!("<a href=\"index_{0}.html?ac_id={0}\">login</a>", self.ac_id),
+            ),
+            "/probe/blocked" => respond(200, "text/html", "<html>blocked</html>"),
+            "/probe/slow" => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                respond(204, "text/plain", "")
+            }
+            path if path.starts_with("/cgi-bin/") && malformed => respond(
+                200,
+                "text/javascript",
+                &format!("{}({{\"error\":", callback),
+            ),
+            path => {
+                let body = match path {
+                    "/cgi-bin/get_challenge" => Some(self.get_challenge(&params, seen_ip)),
+                    "/cgi-bin/rad_user_info" => Some(self.rad_user_info(&params, seen_ip)),
+                    "/cgi-bin/srun_portal" => Some(self.srun_portal(&params, seen_ip)),
+                    "/cgi-bin/rad_user_dm" => Some(self.rad_user_dm(&params, seen_ip)),
+                    _ => None,
+                };
+                match body {
+                    Some(body) => {
+                        respond(200, "text/javascript", &format!("{}({})", callback, body))
+                    }
+                    None => respond(404, "text/plain", "not found"),
+                }
+            }
+        };
+
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn get_challenge(&self, params: &HashMap<String, String>, seen_ip: IpAddr) -> Value {
+        let ip = params
+            .get("ip")
+            .cloned()
+            .unwrap_or_else(|| seen_ip.to_string());
+
+        let mut state = self.lock();
+        state.issued += 1;
+        let token = {
+            let mut hasher = Sha1::new();
+            hasher.update(format!(
+                "{}{}{}",
+                state.issued,
+                ip,
+                Utc::now().timestamp_nanos_opt().unwrap_or_default()
+            ));
+            format!("{:x}", hasher.finalize())
+        };
+        state.challenges.insert(ip, token.clone());
+
+        json!({
+            "challenge": token,
+            "client_ip": seen_ip,
+            "ecode": 0,
+            "error": "ok",
+            "error_msg": "",
+            "expire": "60",
+            "online_ip": seen_ip,
+            "res": "ok",
+            "srun_ver": MOCK_SRUN_VER,
+            "st": Utc::now().timestamp(),
+        })
+    }
+
+    fn rad_user_info(&self, params: &HashMap<String, String>, seen_ip: IpAddr) -> Value {
+        let ip = params
+            .get("ip")
+            .cloned()
+            .unwrap_or_else(|| seen_ip.to_string());
+
+        let state = self.lock();
+        match state.online.get(&ip) {
+            Some(session) => {
+                // pretend the device transfers 64 KiB every second while online
+                let now = Utc::now().timestamp();
+                let seconds = (now - session.add_time).max(0);
+                let bytes = seconds as u64 * 64 * 1024;
+                json!({
+                    "ServerFlag": 0,
+                    "add_time": session.add_time,
+                    "all_bytes": bytes,
+                    "bytes_in": bytes / 4 * 3,
+                    "bytes_out": bytes / 4,
+                    "checkout_date": 0,
+                    "domain": "",
+                    "error": "ok",
+                    "group_id": "1",
+                    "keepalive_time": now,
+                    "online_ip": ip,
+                    "products_name": "mock",
+                    "real_name": "",
+                    "remain_bytes": 0,
+                    "remain_seconds": 0,
+                    "sum_bytes": bytes,
+                    "sum_seconds": seconds,
+                    "sysver": MOCK_SYSVER,
+                    "user_balance": 10.0,
+                    "user_charge": 0,
+                    "user_mac": "",
+                    "user_name": session.username,
+                    "wallet_balance": 0.0,
+                })
+            }
+            None => json!({
+                "client_ip": seen_ip,
+                "ecode": 0,
+                "error": "not_online_error",
+                "error_msg": "",
+                "online_ip": ip,
+                "res": "not_online_error",
+                "srun_ver": MOCK_SRUN_VER,
+                "st": Utc::now().timestamp(),
+            }),
+        }
+    }
+
+    fn srun_portal(&self, params: &HashMap<String, String>, seen_ip: IpAddr) -> Value {
+        let param = |key: &str| params.get(key).map_or("", |v| v.as_str());
+        let ip = match param("ip") {
+            "" => seen_ip.to_string(),
+            ip => ip.to_string(),
+        };
+
+        let mut state = self.lock();
+        if param("action") == "login" {
+            state.login_attempts += 1;
+        }
+        if let Some(code) = injected_error(&state.faults) {
+            return portal_error(&code, seen_ip, &ip);
+        }
+
+        match param("action") {
+            "login" => {
+                let required = [
+                    "username", "password", "chksum", "info", "ac_id", "n", "type",
+                ];
+                if required.iter().any(|key| param(key).is_empty()) {
+                    return portal_error("missing_required_parameters_error", seen_ip, &ip);
+                }
+                let Some(token) = state.challenges.remove(&ip) else {
+                    return portal_error("challenge_expire_error", seen_ip, &ip);
+                };
+                let Some(password) = state.users.get(param("username")) else {
+                    return portal_error("E2531", seen_ip, &ip);
+                };
+
+                if let Err(code) = self.verify_login(&token, params, password, &ip) {
+                    return portal_error(code, seen_ip, &ip);
+                }
+
+                let suc_msg = match state.online.contains_key(&ip) {
+                    true => "ip_already_online_error",
+                    false => "login_ok",
+                };
+                state
+                    .online
+                    .entry(ip.clone())
+                    .or_insert_with(|| MockSession {
+                        username: param("username").to_string(),
+                        add_time: Utc::now().timestamp(),
+                    });
+
+                json!({
+                    "ServerFlag": 0,
+                    "access_token": token,
+                    "client_ip": seen_ip,
+                    "ecode": 0,
+                    "error": "ok",
+                    "error_msg": "",
+                    "online_ip": ip,
+                    "ploy_msg": "E0000: Login is successful.",
+                    "real_name": "",
+                    "res": "ok",
+                    "srun_ver": MOCK_SRUN_VER,
+                    "suc_msg": suc_msg,
+                    "sysver": MOCK_SYSVER,
+                    "username": param("username"),
+                })
+            }
+            "logout" => match state.online.remove(&ip) {
+                Some(_) => portal_ok("ok", seen_ip, &ip),
+                None => portal_error("not_online_error", seen_ip, &ip),
+            },
+            _ => portal_error("missing_required_parameters_error", seen_ip, &ip),
+        }
+    }
+
+    /// Check the `info`, `{MD5}` password and `chksum` of a login the way the portal does
+    ///
+    /// Deliberately computed from scratch rather than with the client's [`crate::encoder`], so
+    /// that an encoding bug in the client can not verify itself. Returns the error code to
+    /// respond with.
+    fn verify_login(
+        &self,
+        token: &str,
+        params: &HashMap<String, String>,
+        password: &str,
+        ip: &str,
+    ) -> std::result::Result<(), &'static str> {
+        let param = |key: &str| params.get(key).map_or("", |v| v.as_str());
+
+        let info = param("info")
+            .strip_prefix("{SRBX1}")
+            .and_then(|encoded| fkbase64_decode(encoded).ok())
+            .map(|encoded| xdecode(&encoded, token))
+            .and_then(|decoded| serde_json::from_slice::<Value>(&decoded).ok())
+            .ok_or("sign_error")?;
+        let field = |key: &str| info.get(key).and_then(Value::as_str).unwrap_or_default();
+        if field("username") != param("username")
+            || field("ip") != ip
+            || field("acid") != param("ac_id")
+            || field("enc_ver") != "srun_bx1"
+        {
+            return Err("sign_error");
+        }
+        // the password travels inside `info` in every variant
+        if field("password") != password {
+            return Err("E2553");
+        }
+
+        let mut mac = Hmac::<Md5>::new_from_slice(token.as_bytes()).map_err(|_| "sign_error")?;
+        if self.profile.encoder == EncoderKind::SrunBx1Hmac {
+            mac.update(password.as_bytes());
+        }
+        let hmd5 = format!("{:x}", mac.finalize().into_bytes());
+        if param("password") != format!("{{MD5}}{}", hmd5) {
+            return Err("sign_error");
+        }
+
+        let mut hasher = Sha1::new();
+        let fields = [
+            param("username"),
+            &hmd5,
+            param("ac_id"),
+            ip,
+            param("n"),
+            param("type"),
+            param("info"),
+        ];
+        for field in fields {
+            hasher.update(token);
+            hasher.update(field);
+        }
+        if param("chksum") != format!("{:x}", hasher.finalize()) {
+            return Err("sign_error");
+        }
+        Ok(())
+    }
+
+    fn rad_user_dm(&self, params: &HashMap<String, String>, seen_ip: IpAddr) -> Value {
+        let param = |key: &str| params.get(key).map_or("", |v| v.as_str());
+        let ip = param("ip").to_string();
+
+        let mut state = self.lock();
+        if let Some(code) = injected_error(&state.faults) {
+            return portal_error(&code, seen_ip, &ip);
+        }
+
+        let sign = dm_logout_sign(param("time"), param("username"), &ip, param("unbind"));
+        if param("sign") != sign {
+            return portal_error("sign_error", seen_ip, &ip);
+        }
+        match state.online.remove(&ip) {
+            Some(_) => portal_ok("logout_ok", seen_ip, &ip),
+            None => portal_error("not_online_error", seen_ip, &ip),
+        }
+    }
+}
+
+fn injected_error(faults: &[MockFault]) -> Option<String> {
+    faults.iter().find_map(|fault| match fault {
+        MockFault::Error(code) => Some(code.clone()),
+        _ => None,
+    })
+}
+
+fn portal_ok(error: &str, client_ip: IpAddr, online_ip: &str) -> Value {
+    json!({
+        "client_ip": client_ip,
+        "ecode": 0,
+        "error": error,
+        "error_msg": "",
+        "online_ip": online_ip,
+        "res": "ok",
+        "srun_ver": MOCK_SRUN_VER,
+        "st": Utc::now().timestamp(),
+    })
+}
+
+/// Portal error codes are either plain strings or `E####` codes reported as `login_error`
+fn portal_error(code: &str, client_ip: IpAddr, online_ip: &str) -> Value {
+    let (error, error_msg) = match code {
+        "E2531" => ("login_error", "E2531: User not found.".to_string()),
+        "E2553" => ("login_error", "E2553: Password is error.".to_string()),
+        c if c.starts_with('E') && c[1..].chars().all(|c| c.is_ascii_digit()) => {
+            ("login_error", format!("{}: Injected by mock portal.", c))
+        }
+        c => (c, String::new()),
+    };
+    json!({
+        "client_ip": client_ip,
+        "ecode": code,
+        "error": error,
+        "error_msg": error_msg,
+        "online_ip": online_ip,
+        "res": error,
+        "srun_ver": MOCK_SRUN_VER,
+        "st": Utc::now().timestamp(),
+    })
+}
+
+fn respond(status: u16, content_type: &str, body: &str) -> String {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        _ => "Not Found",
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+fn redirect(location: &str) -> String {
+    format!(
+        "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        location
+    )
+}
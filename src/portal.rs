@@ -0,0 +1,119 @@
+use crate::encoder::EncoderKind;
+use crate::probe::ConnectivityConfig;
+
+use anyhow::bail;
+use anyhow::Result;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Default constants used for the /srun_portal endpoint (BIT, 10.0.0.55)
+pub const SRUN_PORTAL: &str = "http://10.0.0.55";
+pub const SRUN_TYPE: &str = "1";
+pub const SRUN_N: &str = "200";
+
+/// An arbitrary HTTP URL for srun to redirect
+pub const CAPTIVE_PORTAL_TEST: &str = "http://www.bit.edu.cn";
+
+/// The ac_id assumed when intercepted but the portal does not tell us which one
+pub const SRUN_AC_ID: &str = "43";
+
+/// A SRUN portal deployment that the client talks to
+///
+/// Defaults to the BIT gateway at 10.0.0.55. Any field can be overridden in the config file:
+///
+/// ```json
+/// {
+///   "username": "...",
+///   "password": "...",
+///   "portal": { "url": "http://10.0.0.56", "ac_id": "1" }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PortalProfile {
+    /// Base URL of the portal, e.g., `http://10.0.0.55`
+    pub url: String,
+    /// The `n` parameter sent to `/srun_portal`
+    pub n: String,
+    /// The `type` parameter sent to `/srun_portal`
+    #[serde(rename = "type")]
+    pub srun_type: String,
+    /// An arbitrary HTTP URL that the portal intercepts and redirects (for `ac_id` discovery)
+    pub captive_portal_test: String,
+    /// The `ac_id` assumed when it can not be discovered (unless `guess_ac_id` is off)
+    pub ac_id: String,
+    /// Whether to authenticate the IPv6 address as well (srun's `double_stack`)
+    pub double_stack: bool,
+    /// Base URL of the portal over IPv6, used to discover and authenticate the IPv6 address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url_v6: Option<String>,
+    /// How login payloads are encoded, `srun_bx1` by default
+    pub encoder: EncoderKind,
+    /// Probes telling if the device is online, intercepted or unreachable
+    pub connectivity: ConnectivityConfig,
+}
+
+impl Default for PortalProfile {
+    fn default() -> Self {
+        Self {
+            url: SRUN_PORTAL.to_string(),
+            n: SRUN_N.to_string(),
+            srun_type: SRUN_TYPE.to_string(),
+            captive_portal_test: CAPTIVE_PORTAL_TEST.to_string(),
+            ac_id: SRUN_AC_ID.to_string(),
+            double_stack: false,
+            url_v6: None,
+            encoder: EncoderKind::default(),
+            connectivity: ConnectivityConfig::default(),
+        }
+    }
+}
+
+/// Command line overrides of the portal profile from the config file
+#[derive(Debug, Clone, Default)]
+pub struct PortalOverrides {
+    /// `--portal`
+    pub url: Option<String>,
+    /// `--dual-stack`
+    pub dual_stack: bool,
+}
+
+impl PortalProfile {
+    /// Finalize the profile from the config file, with command line overrides taking precedence
+    pub fn finalize(profile: Option<PortalProfile>, overrides: &PortalOverrides) -> Result<Self> {
+        let mut profile = profile.unwrap_or_default();
+        if let Some(url) = &overrides.url {
+            profile.url = url.to_owned();
+        }
+        profile.double_stack |= overrides.dual_stack;
+
+        for url in std::iter::once(&mut profile.url).chain(profile.url_v6.as_mut()) {
+            let scheme = url::Url::parse(url).map(|u| u.scheme().to_string());
+            if !matches!(scheme.as_deref(), Ok("http") | Ok("https")) {
+                bail!("invalid portal url `{}`, expected `http(s)://...`", url)
+            }
+            *url = url.trim_end_matches('/').to_string();
+        }
+        if profile.double_stack && profile.url_v6.is_none() {
+            warn!(
+                "Dual-stack without `url_v6` in the portal profile can not discover the IPv6 \
+                address, only one given with `--ipv6` is logged in"
+            );
+        }
+        Ok(profile)
+    }
+
+    /// Full URL of a `/cgi-bin/` endpoint on this portal, e.g., `rad_user_info`
+    pub fn endpoint(&self, name: &str) -> String {
+        format!("{}/cgi-bin/{}", self.url.trim_end_matches('/'), name)
+    }
+
+    /// Full URL of a `/cgi-bin/` endpoint reached over IPv6, falling back to [`Self::endpoint`]
+    pub fn endpoint_v6(&self, name: &str) -> String {
+        match &self.url_v6 {
+            Some(url) => format!("{}/cgi-bin/{}", url.trim_end_matches('/'), name),
+            None => self.endpoint(name),
+        }
+    }
+}
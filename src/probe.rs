@@ -0,0 +1,271 @@
+//! Connectivity probes, to tell if this device is online, intercepted by the portal, or neither
+//!
+//! Every probe is an HTTP URL with what it returns when truly online. Probes are raced
+//! concurrently, and the first verdict reaching the quorum decides. Configure them in the portal
+//! profile:
+//!
+//! ```json
+//! {
+//!   "portal": {
+//!     "connectivity": {
+//!       "probes": [
+//!         { "url": "http://connect.rom.miui.com/generate_204" },
+//!         { "url": "http://example.com/ok.txt", "status": 200, "body": "ok" }
+//!       ],
+//!       "quorum": 2,
+//!       "guess_ac_id": false
+//!     }
+//!   }
+//! }
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+
+use log::debug;
+use reqwest::Client;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::task::JoinSet;
+
+/// An HTTP URL and what it returns when the device is truly online
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Probe {
+    pub url: String,
+    /// Expected status code, `204` by default (or `200` if a `body` is expected)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Text that the response body must contain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+}
+
+impl Probe {
+    /// A probe expecting `204 No Content`, the usual `generate_204` endpoint
+    pub fn generate_204(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            status: None,
+            body: None,
+        }
+    }
+
+    fn expected_status(&self) -> u16 {
+        match (self.status, &self.body) {
+            (Some(status), _) => status,
+            (None, Some(_)) => 200,
+            (None, None) => 204,
+        }
+    }
+}
+
+/// How connectivity is probed, part of the portal profile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectivityConfig {
+    pub probes: Vec<Probe>,
+    /// Number of probes that must agree on a verdict, clamped to the number of probes
+    pub quorum: usize,
+    /// Timeout of each probe in milliseconds
+    pub timeout_ms: u64,
+    /// Assume the profile's `ac_id` when intercepted but no probe found the actual one
+    pub guess_ac_id: bool,
+}
+
+impl Default for ConnectivityConfig {
+    fn default() -> Self {
+        Self {
+            // domestic endpoints, for better stability in China
+            probes: vec![
+                Probe::generate_204("http://connect.rom.miui.com/generate_204"),
+                Probe::generate_204("http://connectivitycheck.platform.hicloud.com/generate_204"),
+            ],
+            quorum: 1,
+            timeout_ms: 3000,
+            guess_ac_id: true,
+        }
+    }
+}
+
+/// The outcome of a single probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeVote {
+    Online,
+    /// Got something other than expected, with the `ac_id` if the response carried one
+    Intercepted(Option<String>),
+    Unreachable(String),
+}
+
+impl fmt::Display for ProbeVote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeVote::Online => write!(f, "online"),
+            ProbeVote::Intercepted(Some(ac_id)) => write!(f, "intercepted (ac_id={})", ac_id),
+            ProbeVote::Intercepted(None) => write!(f, "intercepted"),
+            ProbeVote::Unreachable(reason) => write!(f, "unreachable ({})", reason),
+        }
+    }
+}
+
+/// The decided connectivity of this device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Connectivity {
+    Online,
+    /// Intercepted by the captive portal, with the `ac_id` if known (or guessed)
+    Intercepted(Option<String>),
+    /// No verdict reached the quorum, e.g., the network is down
+    Unreachable,
+}
+
+impl fmt::Display for Connectivity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Connectivity::Online => write!(f, "online"),
+            Connectivity::Intercepted(Some(ac_id)) => write!(f, "intercepted (ac_id={})", ac_id),
+            Connectivity::Intercepted(None) => write!(f, "intercepted (ac_id unknown)"),
+            Connectivity::Unreachable => write!(f, "unreachable"),
+        }
+    }
+}
+
+/// The decision, which probe made it, and every vote collected until then
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    pub connectivity: Connectivity,
+    /// URL of the probe whose vote reached the quorum
+    pub decided_by: Option<String>,
+    /// Votes in the order they arrived, probes still running when decided are left out
+    pub votes: Vec<(String, ProbeVote)>,
+}
+
+/// Race all probes, returning as soon as a verdict reaches the quorum
+///
+/// `fallback_ac_id` is assumed when intercepted without a known `ac_id`, if `guess_ac_id` is on.
+pub async fn probe_connectivity(
+    client: &Client,
+    config: &ConnectivityConfig,
+    fallback_ac_id: &str,
+) -> ProbeReport {
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let quorum = config.quorum.clamp(1, config.probes.len().max(1));
+
+    let mut probes = JoinSet::new();
+    for probe in config.probes.iter().cloned() {
+        let client = client.clone();
+        probes.spawn(async move {
+            let vote = run_probe(&client, &probe, timeout).await;
+            (probe.url, vote)
+        });
+    }
+
+    let mut votes: Vec<(String, ProbeVote)> = vec![];
+    let mut decided = None;
+    while let Some(joined) = probes.join_next().await {
+        let Ok((url, vote)) = joined else {
+            continue;
+        };
+        debug!("Probe {}: {}", url, vote);
+        votes.push((url.clone(), vote.clone()));
+
+        let agreeing = votes
+            .iter()
+            .filter(|(_, v)| {
+                matches!(
+                    (v, &vote),
+                    (ProbeVote::Online, ProbeVote::Online)
+                        | (ProbeVote::Intercepted(_), ProbeVote::Intercepted(_))
+                )
+            })
+            .count();
+        if agreeing >= quorum {
+            decided = Some((url, vote));
+            break;
+        }
+    }
+    probes.abort_all();
+
+    let connectivity = match decided.as_ref().map(|(_, vote)| vote) {
+        Some(ProbeVote::Online) => Connectivity::Online,
+        Some(ProbeVote::Intercepted(_)) => {
+            // any probe that found the ac_id, not only the deciding one
+            let ac_id = votes.iter().find_map(|(_, v)| match v {
+                ProbeVote::Intercepted(ac_id) => ac_id.clone(),
+                _ => None,
+            });
+            let ac_id = match (ac_id, config.guess_ac_id) {
+                (Some(ac_id), _) => Some(ac_id),
+                (None, true) => Some(fallback_ac_id.to_string()),
+                (None, false) => None,
+            };
+            Connectivity::Intercepted(ac_id)
+        }
+        _ => Connectivity::Unreachable,
+    };
+
+    ProbeReport {
+        connectivity,
+        decided_by: decided.map(|(url, _)| url),
+        votes,
+    }
+}
+
+async fn run_probe(client: &Client, probe: &Probe, timeout: Duration) -> ProbeVote {
+    let resp = match client.get(&probe.url).timeout(timeout).send().await {
+        Ok(resp) => resp,
+        Err(e) => return ProbeVote::Unreachable(e.without_url().to_string()),
+    };
+
+    let status = resp.status().as_u16();
+    let final_url = resp.url().clone();
+    let body = match probe.body.is_some() || status != probe.expected_status() {
+        true => resp.text().await.unwrap_or_default(),
+        false => String::new(),
+    };
+
+    let body_matches = probe.body.as_ref().is_none_or(|text| body.contains(text));
+    if status == probe.expected_status() && body_matches {
+        return ProbeVote::Online;
+    }
+    ProbeVote::Intercepted(find_ac_id(&final_url, &body))
+}
+
+/// Find the `ac_id` in the URL the probe was redirected to, or in the response body
+fn find_ac_id(url: &url::Url, body: &str) -> Option<String> {
+    if let Some((_, ac_id)) = url.query_pairs().find(|(k, _)| k == "ac_id") {
+        return Some(ac_id.into_owned());
+    }
+
+    // WISPr XML like responses, or a page linking to `index_<ac_id>.html?ac_id=<ac_id>`
+    let pos = body.find("index_")?;
+    let sub = &body[pos..];
+    let ac_pos = sub.find("ac_id=")?;
+    let ac_id: String = sub[ac_pos + 6..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    (!ac_id.is_empty()).then_some(ac_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find_ac_id;
+
+    fn url(url: &str) -> url::Url {
+        url::Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn finds_ac_id_in_url_or_body() {
+        let redirect = url("http://10.0.0.55/srun_portal_pc?ac_id=8&theme=bit");
+        assert_eq!(find_ac_id(&redirect, ""), Some("8".into()));
+
+        let body = "<a href='index_12.html?ac_id=12'>";
+        assert_eq!(
+            find_ac_id(&url("http://10.0.0.55/"), body),
+            Some("12".into())
+        );
+
+        // only after an `index_` link
+        assert_eq!(find_ac_id(&url("http://10.0.0.55/"), "ac_id=12"), None);
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::Duration;
+
+use crate::error::SrunError;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// How login attempts are retried, set in the config file under `retry`
+///
+/// ```json
+/// {
+///   "retry": {
+///     "max_attempts": 5,
+///     "initial_delay_ms": 1000,
+///     "give_up_on": ["E2553", "E2531", "E3004"]
+///   }
+/// }
+/// ```
+///
+/// Attempt `n` waits `initial_delay_ms * multiplier^(n - 1)`, capped at `max_delay_ms`, then
+/// randomized by up to `jitter` (a fraction) either way.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub multiplier: f64,
+    pub jitter: f64,
+    /// Portal errors (`E####` codes or raw `error` values) that retrying can not fix
+    pub give_up_on: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_delay_ms: 500,
+            max_delay_ms: 8000,
+            multiplier: 2.0,
+            jitter: 0.2,
+            // wrong password, user not found, user disabled, arrears and quota exhausted
+            give_up_on: [
+                "E2553", "E2901", "E2531", "E2606", "E2616", "E3004", "E3001",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether the portal error is permanent, and retrying should stop immediately
+    pub fn gives_up_on(&self, err: &SrunError) -> bool {
+        err.codes()
+            .iter()
+            .any(|code| self.give_up_on.iter().any(|c| c == code))
+    }
+
+    /// How long to wait after the `attempt`-th attempt (starting from 1) failed
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self
+            .multiplier
+            .max(1.0)
+            .powi(attempt.saturating_sub(1) as i32);
+        let base = (self.initial_delay_ms as f64 * exp).min(self.max_delay_ms as f64);
+
+        // random in [-1, 1), without pulling in a crate for it
+        let random = RandomState::new().hash_one(attempt) as f64 / u64::MAX as f64 * 2.0 - 1.0;
+        let jittered = base * (1.0 + self.jitter.clamp(0.0, 1.0) * random);
+        Duration::from_millis(jittered.max(0.0) as u64)
+    }
+}
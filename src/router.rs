@@ -0,0 +1,314 @@
+//! Router mode, keeping LAN devices behind this host authenticated
+//!
+//! The portal authenticates whatever `ip` a login carries, so a gateway box can log in its
+//! downstream devices on their behalf. Devices are listed in the config file:
+//!
+//! ```json
+//! {
+//!   "username": "...",
+//!   "password": "...",
+//!   "dm": false,
+//!   "poll_interval": 60,
+//!   "devices": [
+//!     { "ip": "10.1.2.3", "name": "tv" },
+//!     { "ip": "10.1.2.4", "username": "...", "password": "..." },
+//!     { "ip": "10.2.0.5", "portal": { "url": "http://10.0.0.56" } }
+//!   ]
+//! }
+//! ```
+//!
+//! Devices without their own credentials are logged in with the top-level account, and devices
+//! without their own `portal` through the top-level one. The `ac_id` is discovered once per
+//! portal, and reused for every device logging in through it.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use crate::client::get_login_state_of;
+use crate::client::SrunClient;
+use crate::client::SrunLoginState;
+use crate::error::SrunError;
+use crate::portal::PortalOverrides;
+use crate::portal::PortalProfile;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::{debug, info, warn};
+use reqwest::Client;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// A LAN device kept online in router mode
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouterDevice {
+    pub ip: IpAddr,
+    /// Human readable name for logs and status, the IP by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Account to login with, the top-level one by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Portal to login through, the top-level one by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub portal: Option<PortalProfile>,
+}
+
+impl RouterDevice {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.ip.to_string())
+    }
+}
+
+/// Validate the devices' own portal profiles, like the top-level one
+pub fn finalize_portals(devices: &mut [RouterDevice]) -> Result<()> {
+    for device in devices {
+        if let Some(portal) = device.portal.take() {
+            let portal = PortalProfile::finalize(Some(portal), &PortalOverrides::default())
+                .with_context(|| format!("invalid portal for device {}", device))?;
+            device.portal = Some(portal);
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for RouterDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.ip),
+            None => write!(f, "{}", self.ip),
+        }
+    }
+}
+
+/// What happened to a device in the last check
+#[derive(Debug, Clone)]
+pub enum DeviceState {
+    /// Already online, with its login state
+    Online(Box<SrunLoginState>),
+    /// Was offline and has just been logged in
+    LoggedIn,
+    /// Offline, and not logged in (either only checked, or the login was refused)
+    Offline(SrunError),
+    /// The portal could not be reached or the login failed otherwise
+    Failed(String),
+}
+
+impl fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceState::Online(_) => write!(f, "online"),
+            DeviceState::LoggedIn => write!(f, "logged in"),
+            DeviceState::Offline(SrunError::NotOnline) => write!(f, "offline"),
+            DeviceState::Offline(err) => write!(f, "offline ({})", err),
+            DeviceState::Failed(err) => write!(f, "failed ({})", err),
+        }
+    }
+}
+
+/// Per-device status reported by [`Router`]
+#[derive(Debug, Clone)]
+pub struct DeviceStatus {
+    pub device: RouterDevice,
+    /// The account the device is (or would be) logged in with
+    pub username: String,
+    pub state: DeviceState,
+}
+
+/// Keeps a list of LAN devices authenticated through one portal
+#[derive(Debug)]
+pub struct Router {
+    pub http_client: Client,
+    pub portal: PortalProfile,
+    pub devices: Vec<RouterDevice>,
+    /// Fallback account for devices without their own credentials
+    pub username: String,
+    pub password: String,
+    /// Where `portal.lock` is kept, see [`crate::client::SrunClientBuilder::lock_dir`]
+    pub lock_dir: Option<PathBuf>,
+    /// Discovered on the first login through each portal, and reused for the others
+    clients: Vec<SrunClient>,
+}
+
+impl Router {
+    pub fn new(
+        http_client: Client,
+        portal: PortalProfile,
+        devices: Vec<RouterDevice>,
+        username: String,
+        password: String,
+    ) -> Self {
+        Self {
+            http_client,
+            portal,
+            devices,
+            username,
+            password,
+            lock_dir: None,
+            clients: Vec::new(),
+        }
+    }
+
+    /// The `ac_id` discovered for the top-level portal, if any device logged in through it
+    pub fn ac_id(&self) -> Option<String> {
+        self.clients
+            .iter()
+            .find(|client| client.portal == self.portal)
+            .map(|client| client.ac_id.clone())
+    }
+
+    fn portal<'a>(&'a self, device: &'a RouterDevice) -> &'a PortalProfile {
+        device.portal.as_ref().unwrap_or(&self.portal)
+    }
+
+    fn credentials(&self, device: &RouterDevice) -> (String, String) {
+        (
+            device.username.clone().unwrap_or(self.username.clone()),
+            device.password.clone().unwrap_or(self.password.clone()),
+        )
+    }
+
+    /// Query each device's login state from `/rad_user_info`, without logging any in
+    pub async fn check(&self) -> Vec<DeviceStatus> {
+        let mut statuses = Vec::with_capacity(self.devices.len());
+        for device in &self.devices {
+            let (username, _) = self.credentials(device);
+            let state =
+                match get_login_state_of(&self.http_client, self.portal(device), device.ip, false)
+                    .await
+                {
+                    Ok(state) => match state.srun_error() {
+                        SrunError::Ok => DeviceState::Online(Box::new(state)),
+                        err => DeviceState::Offline(err),
+                    },
+                    Err(e) => DeviceState::Failed(format!("{:#}", e)),
+                };
+            statuses.push(DeviceStatus {
+                device: device.clone(),
+                username,
+                state,
+            });
+        }
+        statuses
+    }
+
+    /// Check every device, and login those that dropped offline
+    pub async fn keep_online(&mut self) -> Vec<DeviceStatus> {
+        let mut statuses = self.check().await;
+        for status in statuses.iter_mut() {
+            if !matches!(status.state, DeviceState::Offline(_)) {
+                continue;
+            }
+            debug!("{} is offline, logging in...", status.device);
+            status.state = match self.login(&status.device).await {
+                Ok(()) => DeviceState::LoggedIn,
+                Err(e) => match e.downcast_ref::<SrunError>() {
+                    Some(err) => DeviceState::Offline(err.clone()),
+                    None => DeviceState::Failed(format!("{:#}", e)),
+                },
+            };
+        }
+        statuses
+    }
+
+    async fn login(&mut self, device: &RouterDevice) -> Result<()> {
+        let resp = self.client(device).await?.login(true, false).await?;
+        match resp.srun_error() {
+            SrunError::Ok => Ok(()),
+            err => Err(err.into()),
+        }
+    }
+
+    /// Logout the devices without their own credentials that are online with another account,
+    /// e.g., the one failed over to before a new billing cycle, so that they login with the
+    /// router's account again
+    ///
+    /// Returns whether every such device was logged out.
+    pub async fn reclaim(&mut self) -> bool {
+        let mut reclaimed = true;
+        for status in self.check().await {
+            let DeviceState::Online(state) = status.state else {
+                continue;
+            };
+            let Some(other) = state
+                .user_name
+                .clone()
+                .filter(|other| *other != self.username)
+            else {
+                continue;
+            };
+            if status.device.username.is_some() {
+                continue;
+            }
+            info!(
+                "{} is online as {}, logging it out to login as {}",
+                status.device, other, self.username
+            );
+            let logout = match self.client(&status.device).await {
+                Ok(client) => {
+                    let client = SrunClient {
+                        username: other,
+                        login_state: *state,
+                        ..client
+                    };
+                    client.logout(true, false).await
+                }
+                Err(e) => Err(e),
+            };
+            match logout.map(|resp| resp.srun_error()) {
+                Ok(err) if err.is_ok() => {}
+                Ok(err) => {
+                    warn!("Failed to logout {}: {}", status.device, err);
+                    reclaimed = false;
+                }
+                Err(e) => {
+                    warn!("Failed to logout {}: {:#}", status.device, e);
+                    reclaimed = false;
+                }
+            }
+        }
+        reclaimed
+    }
+
+    /// A client for `device` with its credentials, discovered once per portal
+    async fn client(&mut self, device: &RouterDevice) -> Result<SrunClient> {
+        let (username, password) = self.credentials(device);
+        let portal = self.portal(device).clone();
+        let discovered = match self.clients.iter().find(|client| client.portal == portal) {
+            Some(client) => client.clone(),
+            None => {
+                let mut builder = SrunClient::builder(&username, &password)
+                    .http_client(self.http_client.clone())
+                    .portal(portal)
+                    .ip(device.ip)
+                    // the gateway sees this host, not the device
+                    .fix_ip_mismatch(false);
+                if let Some(dir) = &self.lock_dir {
+                    builder = builder.lock_dir(dir);
+                }
+                let client = builder.build().await?;
+                self.clients.push(client.clone());
+                client
+            }
+        };
+        Ok(SrunClient {
+            username,
+            password,
+            ip: device.ip,
+            ..discovered
+        })
+    }
+}
+
+/// Log per-device status after a router tick
+pub fn log_device_statuses(statuses: &[DeviceStatus]) {
+    for status in statuses {
+        match &status.state {
+            DeviceState::Online(_) => debug!("{} is online", status.device),
+            DeviceState::LoggedIn => info!("{} logged in as {}", status.device, status.username),
+            state => warn!("{} is {}", status.device, state),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! systemd units for `bitsrun keep-alive`, written by `bitsrun service install`
+//!
+//! The system unit (`/etc/systemd/system/bitsrun.service`) overrides the one shipped in the
+//! Debian package. It runs as root with its state in `/var/lib/bitsrun` and its control socket in
+//! `/run/bitsrun/control`. The `--user` unit goes to `~/.config/systemd/user/bitsrun.service`,
+//! and keeps the user's own dirs.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use directories::BaseDirs;
+
+/// Name of the unit, the same as the Debian package's so that it is overridden
+pub const UNIT_NAME: &str = "bitsrun.service";
+
+/// Runtime dir of the system unit, created by systemd while it runs
+pub const SYSTEM_RUNTIME_DIR: &str = "/run/bitsrun";
+
+/// Control socket of the system unit, set through `XDG_RUNTIME_DIR`
+pub const SYSTEM_CONTROL_SOCKET: &str = "/run/bitsrun/control/control.sock";
+
+/// State file of the system unit, set through `XDG_STATE_HOME`
+pub const SYSTEM_DAEMON_STATE: &str = "/var/lib/bitsrun/daemon.json";
+
+/// Where the unit is installed
+pub fn unit_path(user: bool) -> Result<PathBuf> {
+    match user {
+        true => {
+            let dirs = BaseDirs::new().context("unable to locate the home dir")?;
+            Ok(dirs.config_dir().join("systemd/user").join(UNIT_NAME))
+        }
+        false => Ok(Path::new("/etc/systemd/system").join(UNIT_NAME)),
+    }
+}
+
+/// Quote a path for a unit file command line, where `%` and `$` are special as well
+fn quote(path: &Path) -> String {
+    let escaped = path
+        .display()
+        .to_string()
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{}\"", escaped)
+}
+
+/// Render the unit running `exe keep-alive --config <config>`
+///
+/// ```
+/// use std::path::Path;
+///
+/// let unit = bitsrun::service::render_unit(
+///     Path::new("/usr/bin/bitsrun"),
+///     Path::new("/home/alice/.config/bitsrun/bit-user.json"),
+///     true,
+/// );
+/// assert!(unit.contains(
+///     r#"ExecStart="/usr/bin/bitsrun" keep-alive --config "/home/alice/.config/bitsrun/bit-user.json""#
+/// ));
+/// assert!(unit.contains("WantedBy=default.target"));
+/// ```
+pub fn render_unit(exe: &Path, config: &Path, user: bool) -> String {
+    let mut unit = String::from(
+        "[Unit]\n\
+         Description=bitsrun - A headless login and logout CLI for 10.0.0.55 at BIT\n",
+    );
+    if !user {
+        unit.push_str("Wants=network-online.target\nAfter=network-online.target\n");
+    }
+
+    unit.push_str(&format!(
+        "\n[Service]\n\
+         Type=notify\n\
+         Restart=always\n\
+         RestartSec=10s\n\
+         ExecStart={} keep-alive --config {}\n\
+         ExecReload=/bin/kill -HUP $MAINPID\n\
+         WatchdogSec=5min\n",
+        quote(exe),
+        quote(config)
+    ));
+
+    // hardening, only what the daemon does not need: it reads the config, writes its state, and
+    // talks to the portal (and to netlink and D-Bus for network changes)
+    unit.push_str("NoNewPrivileges=yes\nLockPersonality=yes\nRestrictRealtime=yes\n");
+    if !user {
+        unit.push_str(
+            "StateDirectory=bitsrun\n\
+             RuntimeDirectory=bitsrun\n\
+             Environment=XDG_DATA_HOME=/var/lib XDG_STATE_HOME=/var/lib XDG_RUNTIME_DIR=/run\n\
+             ProtectSystem=strict\n\
+             ProtectHome=read-only\n\
+             PrivateTmp=yes\n\
+             ProtectKernelTunables=yes\n\
+             ProtectKernelModules=yes\n\
+             ProtectControlGroups=yes\n\
+             RestrictSUIDSGID=yes\n\
+             MemoryDenyWriteExecute=yes\n\
+             RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK\n",
+        );
+    }
+
+    unit.push_str(&format!(
+        "\n[Install]\nWantedBy={}\n",
+        match user {
+            true => "default.target",
+            false => "multi-user.target",
+        }
+    ));
+    unit
+}
+
+/// Run `systemctl [--user] <args>`, failing on a non-zero exit
+pub fn systemctl(user: bool, args: &[&str]) -> Result<()> {
+    let mut command = Command::new("systemctl");
+    if user {
+        command.arg("--user");
+    }
+    let status = command
+        .args(args)
+        .status()
+        .with_context(|| "failed to run `systemctl`, is systemd installed?")?;
+    if !status.success() {
+        let flag = if user { "--user " } else { "" };
+        bail!("`systemctl {}{}` failed ({})", flag, args.join(" "), status)
+    }
+    Ok(())
+}
+
+/// Write the unit to `path`, creating its dir
+pub fn write_unit(path: &Path, unit: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create `{}`", dir.display()))?;
+    }
+    fs::write(path, unit).with_context(|| format!("failed to write `{}`", path.display()))
+}
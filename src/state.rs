@@ -0,0 +1,471 @@
+//! Persistent state kept between runs, under the XDG state dir (`~/.local/state/bitsrun`)
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::bind::BindOptions;
+use crate::portal::PortalProfile;
+
+use anyhow::Context;
+use anyhow::Result;
+use chrono::NaiveDate;
+use chrono::Utc;
+use directories::ProjectDirs;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Directory for persistent state, `$XDG_STATE_HOME/bitsrun` on Linux
+///
+/// Platforms without a state dir (macOS, Windows) use the local data dir instead.
+pub fn state_dir() -> Option<PathBuf> {
+    let dirs = ProjectDirs::from("", "", "bitsrun")?;
+    Some(
+        dirs.state_dir()
+            .unwrap_or_else(|| dirs.data_local_dir())
+            .to_path_buf(),
+    )
+}
+
+/// Directory for runtime files (sockets and locks), `$XDG_RUNTIME_DIR/bitsrun` on Linux
+///
+/// Falls back to the state dir where there is no runtime dir.
+pub fn runtime_dir() -> Option<PathBuf> {
+    let dirs = ProjectDirs::from("", "", "bitsrun")?;
+    match dirs.runtime_dir() {
+        Some(dir) => Some(dir.to_path_buf()),
+        None => state_dir(),
+    }
+}
+
+/// An `ac_id` discovered on a network
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedAcId {
+    pub ac_id: String,
+    /// Unix timestamp of the discovery
+    pub discovered_at: i64,
+}
+
+/// How long a discovered `ac_id` is trusted before it is discovered again
+const AC_ID_MAX_AGE: i64 = 30 * 24 * 60 * 60;
+
+/// Discovered `ac_id`s keyed by portal and network, saved as `ac_id.json` in the state dir
+///
+/// Saves the captive portal redirect dance on every start, and keeps logins working when the
+/// redirect trick fails. Entries expire after 30 days, and are evicted when a login with them
+/// fails, so that a wrong one is not stuck forever.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AcIdCache {
+    #[serde(skip)]
+    path: PathBuf,
+    pub networks: BTreeMap<String, CachedAcId>,
+}
+
+impl AcIdCache {
+    /// Load the cache from the state dir, starting empty if there is none yet
+    pub fn load() -> Option<Self> {
+        let path = state_dir()?.join("ac_id.json");
+        let cache = fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| serde_json::from_str::<AcIdCache>(&s).ok())
+            .unwrap_or_default();
+        Some(Self { path, ..cache })
+    }
+
+    fn key(portal: &PortalProfile, network: &str) -> String {
+        format!("{} {}", portal.url, network)
+    }
+
+    pub fn get(&self, portal: &PortalProfile, network: &str) -> Option<&str> {
+        let now = Utc::now().timestamp();
+        self.networks
+            .get(&Self::key(portal, network))
+            .filter(|cached| now - cached.discovered_at < AC_ID_MAX_AGE)
+            .map(|cached| cached.ac_id.as_str())
+    }
+
+    pub fn insert(&mut self, portal: &PortalProfile, network: &str, ac_id: &str) {
+        self.networks.insert(
+            Self::key(portal, network),
+            CachedAcId {
+                ac_id: ac_id.to_string(),
+                discovered_at: Utc::now().timestamp(),
+            },
+        );
+    }
+
+    /// Forget the `ac_id` of a network, returning whether there was one
+    pub fn remove(&mut self, portal: &PortalProfile, network: &str) -> bool {
+        self.networks.remove(&Self::key(portal, network)).is_some()
+    }
+
+    /// Drop the cached `ac_id` of a network from the cache on disk, after a login with it failed
+    pub fn evict(portal: &PortalProfile, network: &str) -> Result<()> {
+        let Some(mut cache) = Self::load() else {
+            return Ok(());
+        };
+        match cache.remove(portal, network) {
+            true => cache.save(),
+            false => Ok(()),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        write_atomic(&self.path, &serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Write a file through a temporary one, so that readers never see half of it
+///
+/// The temporary file is unique to the process and the call, so concurrent writers (e.g., the
+/// daemon and a `bitsrun login`) never write into each other's.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create dir `{}`", dir.display()))?;
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let next = NEXT.fetch_add(1, Ordering::Relaxed);
+    let tmp = path.with_file_name(format!(".{}.{}.{}.tmp", name, std::process::id(), next));
+    fs::write(&tmp, contents).with_context(|| format!("failed to write `{}`", tmp.display()))?;
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e).with_context(|| format!("failed to write `{}`", path.display()));
+    }
+    Ok(())
+}
+
+/// What the keep-alive daemon is doing, as far as `bitsrun daemon-status` is concerned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DaemonPhase {
+    /// Started, not checked yet
+    #[default]
+    Starting,
+    Online,
+    /// Offline, but not failing to login (e.g., off campus)
+    Offline,
+    /// The last login attempts failed, and will be retried
+    Failing,
+    /// The portal refused the login for good, see [`crate::retry::RetryPolicy::give_up_on`]
+    GaveUp,
+    Paused,
+    Stopped,
+}
+
+impl fmt::Display for DaemonPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self {
+            DaemonPhase::Starting => "starting",
+            DaemonPhase::Online => "online",
+            DaemonPhase::Offline => "offline",
+            DaemonPhase::Failing => "failing",
+            DaemonPhase::GaveUp => "gave up",
+            DaemonPhase::Paused => "paused",
+            DaemonPhase::Stopped => "stopped",
+        };
+        write!(f, "{}", phase)
+    }
+}
+
+/// A change of [`DaemonState`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaemonTransition {
+    /// Unix timestamp
+    pub at: i64,
+    pub phase: DaemonPhase,
+    pub summary: String,
+}
+
+/// Number of transitions kept in [`DaemonState::history`]
+const DAEMON_HISTORY: usize = 50;
+
+/// The keep-alive daemon's state, saved as `daemon.json` in the state dir on every transition
+///
+/// Outlives the daemon, so that a restarted daemon still knows its last login, and so that
+/// `bitsrun daemon-status` can tell what happened without any network access.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DaemonState {
+    #[serde(skip)]
+    path: PathBuf,
+    pub pid: u32,
+    pub username: String,
+    pub router: bool,
+    pub phase: DaemonPhase,
+    /// Unix timestamps
+    pub started_at: i64,
+    pub updated_at: i64,
+    #[serde(default)]
+    pub last_check: Option<i64>,
+    #[serde(default)]
+    pub last_login: Option<i64>,
+    #[serde(default)]
+    pub ip: Option<IpAddr>,
+    #[serde(default)]
+    pub ac_id: Option<String>,
+    /// Local date the daemon failed over to `username`, see [`crate::failover::Failover`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub switched_on: Option<NaiveDate>,
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Oldest first
+    #[serde(default)]
+    pub history: Vec<DaemonTransition>,
+}
+
+impl DaemonState {
+    pub fn path() -> Option<PathBuf> {
+        Some(state_dir()?.join("daemon.json"))
+    }
+
+    /// Load the state left by the last daemon, if there is any
+    pub fn load() -> Result<Option<Self>> {
+        Self::load_from(Self::path().context("unable to locate the state dir")?)
+    }
+
+    /// Load the state left by a daemon with another state dir, e.g., the system service's
+    pub fn load_from(path: PathBuf) -> Result<Option<Self>> {
+        let state = match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str::<DaemonState>(&s)
+                .with_context(|| format!("failed to parse `{}`", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read `{}`", path.display()))
+            }
+        };
+        Ok(Some(Self { path, ..state }))
+    }
+
+    /// Start the state of a new daemon, carrying over the last login and history of `previous`
+    ///
+    /// Saved on its first [`DaemonState::transition`].
+    pub fn start(username: &str, router: bool, previous: Option<DaemonState>) -> Option<Self> {
+        let now = Utc::now().timestamp();
+        let mut state = Self {
+            path: Self::path()?,
+            pid: std::process::id(),
+            username: username.to_string(),
+            router,
+            started_at: now,
+            ..Default::default()
+        };
+        if let Some(previous) = previous {
+            state.last_login = previous.last_login;
+            state.history = previous.history;
+        }
+        Some(state)
+    }
+
+    /// Record a change, and save the state
+    pub fn transition(&mut self, phase: DaemonPhase, summary: String) {
+        let now = Utc::now().timestamp();
+        self.phase = phase;
+        self.updated_at = now;
+        self.history.push(DaemonTransition {
+            at: now,
+            phase,
+            summary,
+        });
+        let excess = self.history.len().saturating_sub(DAEMON_HISTORY);
+        self.history.drain(..excess);
+        self.persist();
+    }
+
+    /// Save the state without recording a change, e.g., after a check
+    pub fn persist(&self) {
+        if let Err(e) = self.save() {
+            log::warn!("Failed to save the daemon state: {:#}", e);
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        write_atomic(&self.path, &serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Identify the network this device is on, for keying per-network state
+///
+/// The bound interface or source address if any, otherwise the default gateway (and its
+/// interface), followed by the SSID if the interface is on Wi-Fi. Returns `None` if neither can be
+/// determined.
+pub fn current_network(bind: &BindOptions) -> Option<String> {
+    let (network, interface) = match (&bind.interface, bind.source_ip) {
+        (Some(interface), _) => (format!("interface:{}", interface), Some(interface.clone())),
+        (None, Some(source_ip)) => (format!("source:{}", source_ip), None),
+        (None, None) => {
+            let (gateway, interface) = default_gateway()?;
+            (
+                format!("gateway:{}%{}", gateway, interface),
+                Some(interface),
+            )
+        }
+    };
+    // Wi-Fi networks with their own ac_id often share the gateway and interface
+    match interface.as_deref().and_then(wifi_ssid) {
+        Some(ssid) => Some(format!("{} ssid:{}", network, ssid)),
+        None => Some(network),
+    }
+}
+
+/// The SSID the interface is connected to, from `iw dev <interface> link`
+#[cfg(target_os = "linux")]
+fn wifi_ssid(interface: &str) -> Option<String> {
+    let output = std::process::Command::new("iw")
+        .args(["dev", interface, "link"])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("SSID: "))
+        .map(str::to_string)
+}
+
+/// The SSID the interface is connected to, from `networksetup -getairportnetwork <interface>`
+#[cfg(target_os = "macos")]
+fn wifi_ssid(interface: &str) -> Option<String> {
+    let output = std::process::Command::new("networksetup")
+        .args(["-getairportnetwork", interface])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    output
+        .trim()
+        .strip_prefix("Current Wi-Fi Network: ")
+        .map(str::to_string)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn wifi_ssid(_interface: &str) -> Option<String> {
+    None
+}
+
+/// The default IPv4 gateway and its interface, from `/proc/net/route`
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<(std::net::Ipv4Addr, String)> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // Iface, Destination, Gateway, ... all in little endian hex
+        match fields.as_slice() {
+            [iface, "00000000", gateway, ..] => {
+                let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                Some((
+                    std::net::Ipv4Addr::from(gateway.to_le_bytes()),
+                    iface.to_string(),
+                ))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// The default IPv4 gateway and its interface, from `route -n get default`
+#[cfg(target_os = "macos")]
+fn default_gateway() -> Option<(std::net::Ipv4Addr, String)> {
+    let output = std::process::Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let field = |name: &str| {
+        output.lines().find_map(|line| {
+            let (key, value) = line.trim().split_once(':')?;
+            (key == name).then(|| value.trim().to_string())
+        })
+    };
+    Some((field("gateway")?.parse().ok()?, field("interface")?))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn default_gateway() -> Option<(std::net::Ipv4Addr, String)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_atomic;
+    use super::AcIdCache;
+    use super::CachedAcId;
+    use super::DaemonState;
+    use super::AC_ID_MAX_AGE;
+
+    use crate::portal::PortalProfile;
+
+    use chrono::Utc;
+
+    #[test]
+    fn ac_id_cache_expires_and_removes() {
+        let portal = PortalProfile::default();
+        let mut cache = AcIdCache::default();
+        cache.insert(&portal, "gateway:10.0.0.1%eth0", "7");
+        assert_eq!(cache.get(&portal, "gateway:10.0.0.1%eth0"), Some("7"));
+        assert_eq!(cache.get(&portal, "gateway:10.0.0.1%wlan0"), None);
+
+        let stale = Utc::now().timestamp() - AC_ID_MAX_AGE;
+        for cached in cache.networks.values_mut() {
+            *cached = CachedAcId {
+                ac_id: "7".into(),
+                discovered_at: stale,
+            };
+        }
+        assert_eq!(cache.get(&portal, "gateway:10.0.0.1%eth0"), None);
+
+        assert!(cache.remove(&portal, "gateway:10.0.0.1%eth0"));
+        assert!(!cache.remove(&portal, "gateway:10.0.0.1%eth0"));
+    }
+
+    #[test]
+    fn persisting_saves_the_last_check() {
+        let path = std::env::temp_dir().join(format!("bitsrun-state-{}.json", std::process::id()));
+        let mut state = DaemonState {
+            path: path.clone(),
+            last_check: Some(1),
+            ..Default::default()
+        };
+        state.persist();
+        state.last_check = Some(2);
+        state.persist();
+
+        let saved: DaemonState =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.last_check, Some(2));
+        assert!(saved.history.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn concurrent_writes_never_mix() {
+        let dir = std::env::temp_dir().join(format!("bitsrun-atomic-{}", std::process::id()));
+        let path = dir.join("state.json");
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        write_atomic(&path, &i.to_string().repeat(4096)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.len(), 4096);
+        assert!(contents.chars().all(|c| contents.starts_with(c)));
+        // no temporary file left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,88 @@
+//! The sd_notify protocol, for running `keep-alive` as a `Type=notify` systemd service
+//!
+//! Messages are datagrams sent to the Unix socket in `$NOTIFY_SOCKET`, which systemd sets only
+//! for services expecting them. Without it (or off Unix) every message is dropped silently.
+
+use std::env;
+use std::time::Duration;
+
+use log::debug;
+
+/// Send a `VAR=value` message (or several, one per line) to systemd
+///
+/// Returns whether it was sent.
+pub fn notify(state: &str) -> bool {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return false;
+    };
+    match send(&path, state) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Failed to notify systemd of `{}`: {}", state.trim(), e);
+            false
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        // an abstract socket, only on Linux
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Tell systemd the daemon has started up
+pub fn ready() -> bool {
+    notify("READY=1")
+}
+
+/// Tell systemd the daemon is shutting down
+pub fn stopping() -> bool {
+    notify("STOPPING=1")
+}
+
+/// Set the one-line status shown by `systemctl status`
+pub fn status(status: &str) -> bool {
+    notify(&format!("STATUS={}", status.replace('\n', " ")))
+}
+
+/// Tell the watchdog the daemon is alive
+pub fn watchdog() -> bool {
+    notify("WATCHDOG=1")
+}
+
+/// How often to ping the watchdog, half its timeout, if systemd enabled it for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // the watchdog may be meant for another process, e.g., a wrapper script
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    match usec {
+        0 => None,
+        usec => Some(Duration::from_micros(usec / 2)),
+    }
+}
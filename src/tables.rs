@@ -1,5 +1,16 @@
 use crate::client::SrunLoginState;
 use crate::config::enumerate_config_paths;
+use crate::control::DaemonStatus;
+use crate::control::DeviceSummary;
+use crate::debug::FieldCheck;
+use crate::probe::ProbeVote;
+use crate::router::DeviceState;
+use crate::router::DeviceStatus;
+use crate::state::DaemonPhase;
+use crate::state::DaemonState;
+use crate::state::DaemonTransition;
+use crate::usage::sparkline;
+use crate::usage::UsageRow;
 
 use chrono::Duration;
 use chrono_humanize::Accuracy::Rough;
@@ -80,3 +91,411 @@ pub fn print_login_state(state: SrunLoginState) {
     let mut table = builder.build();
     println!("{}", table.with(Style::sharp()).with(Width::increase(60)));
 }
+
+/// Print the live login state of `status --watch`, with the download and upload rates (in bytes
+/// per second) since the last poll
+///
+/// # Example output
+///
+/// ┌────────────┬───────────┬─────────────┬──────────────┬────────────┐
+/// │ Download   │ Upload    │ Session     │ Traffic Used │ Remaining  │
+/// ├────────────┼───────────┼─────────────┼──────────────┼────────────┤
+/// │ 1.21 MiB/s │ 88 KiB/s  │ 3 hours     │ 188.10 GiB   │ 11.90 GiB  │
+/// └────────────┴───────────┴─────────────┴──────────────┴────────────┘
+pub fn print_live_state(state: &SrunLoginState, rates: Option<(u64, u64)>) {
+    let mut builder = Builder::default();
+    builder.set_header(["Download", "Upload", "Session", "Traffic Used", "Remaining"]);
+
+    let (download, upload) = match rates {
+        Some((down, up)) => (
+            format!("{}/s", format_size(down, BINARY)),
+            format!("{}/s", format_size(up, BINARY)),
+        ),
+        None => ("-".to_string(), "-".to_string()),
+    };
+    let session = match state.add_time {
+        Some(add_time) => {
+            let secs = (chrono::Utc::now().timestamp() - add_time).max(0);
+            HumanTime::from(Duration::seconds(secs)).to_text_en(Rough, Present)
+        }
+        None => "-".to_string(),
+    };
+    let remaining = match state.remain_bytes {
+        Some(bytes) if bytes > 0 => format_size(bytes as u64, BINARY),
+        _ => "-".to_string(),
+    };
+
+    builder.push_record([
+        download
+            .if_supports_color(Stdout, |t| t.green())
+            .to_string(),
+        upload.if_supports_color(Stdout, |t| t.cyan()).to_string(),
+        session
+            .if_supports_color(Stdout, |t| t.yellow())
+            .to_string(),
+        format_size(state.sum_bytes.unwrap_or(0), BINARY),
+        remaining
+            .if_supports_color(Stdout, |t| t.magenta())
+            .to_string(),
+    ]);
+
+    let mut table = builder.build();
+    println!("{}", table.with(Style::sharp()).with(Width::increase(60)));
+}
+
+/// Print the fields of a decoded login request against their expected values
+///
+/// # Example output
+///
+/// ┌───────────────┬──────────┬──────────┬───────┐
+/// │ Field         │ Sent     │ Expected │ Match │
+/// ├───────────────┼──────────┼──────────┼───────┤
+/// │ info.username │ 1120xxxx │ 1120xxxx │ yes   │
+/// └───────────────┴──────────┴──────────┴───────┘
+pub fn print_field_checks(checks: &[FieldCheck]) {
+    let mut builder = Builder::default();
+    builder.set_header(["Field", "Sent", "Expected", "Match"]);
+
+    for check in checks {
+        let matches = match check.matches() {
+            Some(true) => "yes".if_supports_color(Stdout, |t| t.green()).to_string(),
+            Some(false) => "no".if_supports_color(Stdout, |t| t.red()).to_string(),
+            None => "-".if_supports_color(Stdout, |t| t.dimmed()).to_string(),
+        };
+        builder.push_record([
+            check.name.to_string(),
+            check.sent.clone(),
+            check.expected.clone().unwrap_or_default(),
+            matches,
+        ]);
+    }
+
+    let mut table = builder.build();
+    println!("{}", table.with(Style::sharp()));
+}
+
+/// Print router mode devices and their login state
+///
+/// # Example output
+///
+/// ┌────────┬──────────┬─────────┬──────────┬──────────────┬─────────────┐
+/// │ Device │ IP       │ Status  │ User     │ Traffic Used │ Online Time │
+/// ├────────┼──────────┼─────────┼──────────┼──────────────┼─────────────┤
+/// │ tv     │ 10.1.2.3 │ online  │ 1120xxxx │ 1.20 GiB     │ 3 hours     │
+/// │ nas    │ 10.1.2.4 │ offline │          │              │             │
+/// └────────┴──────────┴─────────┴──────────┴──────────────┴─────────────┘
+pub fn print_device_statuses(statuses: &[DeviceStatus]) {
+    let mut builder = Builder::default();
+    builder.set_header([
+        "Device",
+        "IP",
+        "Status",
+        "User",
+        "Traffic Used",
+        "Online Time",
+    ]);
+
+    for status in statuses {
+        let state = status.state.to_string();
+        let mut record = vec![
+            status.device.name(),
+            status.device.ip.to_string(),
+            match &status.state {
+                DeviceState::Online(_) | DeviceState::LoggedIn => {
+                    state.if_supports_color(Stdout, |t| t.green()).to_string()
+                }
+                _ => state.if_supports_color(Stdout, |t| t.red()).to_string(),
+            },
+        ];
+        match &status.state {
+            DeviceState::Online(login_state) => {
+                let human_time =
+                    HumanTime::from(Duration::seconds(login_state.sum_seconds.unwrap_or(0)));
+                record.extend([
+                    login_state.user_name.clone().unwrap_or_default(),
+                    format_size(login_state.sum_bytes.unwrap_or(0), BINARY),
+                    human_time.to_text_en(Rough, Present),
+                ]);
+            }
+            _ => record.extend([String::new(), String::new(), String::new()]),
+        }
+        builder.push_record(record);
+    }
+
+    let mut table = builder.build();
+    println!("{}", table.with(Style::sharp()));
+}
+
+/// Print the votes of connectivity probes, in the order they arrived
+///
+/// # Example output
+///
+/// ┌──────────────────────────────────────────┬────────┐
+/// │ Probe                                    │ Vote   │
+/// ├──────────────────────────────────────────┼────────┤
+/// │ http://connect.rom.miui.com/generate_204 │ online │
+/// └──────────────────────────────────────────┴────────┘
+pub fn print_probe_votes(votes: &[(String, ProbeVote)]) {
+    let mut builder = Builder::default();
+    builder.set_header(["Probe", "Vote"]);
+
+    for (url, vote) in votes {
+        let vote_str = vote.to_string();
+        builder.push_record([
+            url.clone(),
+            match vote {
+                ProbeVote::Online => vote_str
+                    .if_supports_color(Stdout, |t| t.green())
+                    .to_string(),
+                ProbeVote::Intercepted(_) => vote_str
+                    .if_supports_color(Stdout, |t| t.yellow())
+                    .to_string(),
+                ProbeVote::Unreachable(_) => {
+                    vote_str.if_supports_color(Stdout, |t| t.red()).to_string()
+                }
+            },
+        ]);
+    }
+
+    let mut table = builder.build();
+    println!("{}", table.with(Style::sharp()));
+}
+
+/// Print traffic and online time per period, with a sparkline of the traffic
+///
+/// # Example output
+///
+/// ┌────────────┬───────────┬───────────┬──────────┬─────────────┐
+/// │ Period     │ Traffic   │ Download  │ Upload   │ Online Time │
+/// ├────────────┼───────────┼───────────┼──────────┼─────────────┤
+/// │ 2024-03-01 │ 3.20 GiB  │ 3.01 GiB  │ 192 MiB  │ 9 hours     │
+/// │ 2024-03-02 │ 1.10 GiB  │ 1.02 GiB  │ 80 MiB   │ 4 hours     │
+/// └────────────┴───────────┴───────────┴──────────┴─────────────┘
+/// Traffic █▃
+pub fn print_usage(rows: &[UsageRow]) {
+    let mut builder = Builder::default();
+    builder.set_header(["Period", "Traffic", "Download", "Upload", "Online Time"]);
+
+    for row in rows {
+        let human_time = HumanTime::from(Duration::seconds(row.seconds));
+        builder.push_record([
+            row.period.clone(),
+            format_size(row.bytes, BINARY)
+                .if_supports_color(Stdout, |t| t.green())
+                .to_string(),
+            format_size(row.bytes_in, BINARY),
+            format_size(row.bytes_out, BINARY),
+            human_time
+                .to_text_en(Rough, Present)
+                .if_supports_color(Stdout, |t| t.yellow())
+                .to_string(),
+        ]);
+    }
+
+    let mut table = builder.build();
+    println!("{}", table.with(Style::sharp()));
+
+    let traffic: Vec<u64> = rows.iter().map(|row| row.bytes).collect();
+    println!(
+        "Traffic {}",
+        sparkline(&traffic).if_supports_color(Stdout, |t| t.green())
+    );
+}
+
+/// Print what a running daemon reports through `bitsrun ctl`
+///
+/// # Example output
+///
+/// ┌──────────┬───────────┬─────────┬────────────┬───────────────┐
+/// │ Account  │ Mode      │ State   │ Interval   │ Last Check    │
+/// ├──────────┼───────────┼─────────┼────────────┼───────────────┤
+/// │ 1120xxxx │ this host │ running │ an hour    │ 2 minutes ago │
+/// └──────────┴───────────┴─────────┴────────────┴───────────────┘
+pub fn print_daemon_status(status: &DaemonStatus) {
+    let mut builder = Builder::default();
+    builder.set_header(["Account", "Mode", "State", "Interval", "Last Check"]);
+
+    let state = if status.gave_up {
+        "gave up".if_supports_color(Stdout, |t| t.red()).to_string()
+    } else if status.paused {
+        "paused"
+            .if_supports_color(Stdout, |t| t.yellow())
+            .to_string()
+    } else {
+        "running"
+            .if_supports_color(Stdout, |t| t.green())
+            .to_string()
+    };
+    let mode = match status.router {
+        true => format!("{} devices", status.devices.len()),
+        false => "this host".to_string(),
+    };
+    let interval = HumanTime::from(Duration::seconds(status.poll_interval as i64));
+    let last_check = match status.last_check {
+        Some(timestamp) => HumanTime::from(Duration::seconds(
+            timestamp - chrono::Utc::now().timestamp(),
+        ))
+        .to_string(),
+        None => "never".to_string(),
+    };
+    builder.push_record([
+        status.username.clone(),
+        mode,
+        state,
+        interval.to_text_en(Rough, Present),
+        last_check,
+    ]);
+
+    let mut table = builder.build();
+    println!("{}", table.with(Style::sharp()));
+}
+
+/// Print router mode devices as last checked by the daemon
+///
+/// # Example output
+///
+/// ┌────────┬──────────┬──────────┬─────────┐
+/// │ Device │ IP       │ User     │ Status  │
+/// ├────────┼──────────┼──────────┼─────────┤
+/// │ tv     │ 10.1.2.3 │ 1120xxxx │ online  │
+/// └────────┴──────────┴──────────┴─────────┘
+pub fn print_device_summaries(devices: &[DeviceSummary]) {
+    let mut builder = Builder::default();
+    builder.set_header(["Device", "IP", "User", "Status"]);
+
+    for device in devices {
+        let state = match device.state.as_str() {
+            "online" | "logged in" => device
+                .state
+                .if_supports_color(Stdout, |t| t.green())
+                .to_string(),
+            _ => device
+                .state
+                .if_supports_color(Stdout, |t| t.red())
+                .to_string(),
+        };
+        builder.push_record([
+            device.name.clone(),
+            device.ip.to_string(),
+            device.username.clone(),
+            state,
+        ]);
+    }
+
+    let mut table = builder.build();
+    println!("{}", table.with(Style::sharp()));
+}
+
+/// A Unix timestamp as local time, and how long ago it was
+fn local_time(timestamp: i64) -> String {
+    match chrono::DateTime::from_timestamp(timestamp, 0) {
+        Some(time) => format!(
+            "{} ({})",
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            HumanTime::from(Duration::seconds(
+                timestamp - chrono::Utc::now().timestamp()
+            ))
+        ),
+        None => timestamp.to_string(),
+    }
+}
+
+/// Print the daemon state file read by `bitsrun daemon-status`
+///
+/// # Example output
+///
+/// ┌────────────┬─────────────────────────────────────┐
+/// │ Account    │ 1120xxxx                            │
+/// ├────────────┼─────────────────────────────────────┤
+/// │ State      │ online                              │
+/// │ Process    │ 4242 (running)                      │
+/// │ Started    │ 2024-03-01 08:00:00 (2 hours ago)   │
+/// │ Last Check │ 2024-03-01 10:00:00 (3 minutes ago) │
+/// │ Last Login │ 2024-03-01 09:12:40 (an hour ago)   │
+/// │ IP         │ 10.1.2.3                            │
+/// │ ac_id      │ 1                                   │
+/// │ Failures   │ 0                                   │
+/// └────────────┴─────────────────────────────────────┘
+pub fn print_daemon_state(state: &DaemonState, running: bool) {
+    let mut builder = Builder::default();
+    let never = || "never".to_string();
+
+    let phase = state.phase.to_string();
+    let phase = match state.phase {
+        DaemonPhase::Online => phase.if_supports_color(Stdout, |t| t.green()).to_string(),
+        DaemonPhase::Failing | DaemonPhase::GaveUp => {
+            phase.if_supports_color(Stdout, |t| t.red()).to_string()
+        }
+        _ => phase.if_supports_color(Stdout, |t| t.yellow()).to_string(),
+    };
+    let mode = match state.router {
+        true => " (router mode)",
+        false => "",
+    };
+    builder.push_record(["Account".to_string(), format!("{}{}", state.username, mode)]);
+    builder.push_record(["State".to_string(), phase]);
+    builder.push_record([
+        "Process".to_string(),
+        match running {
+            true => format!("{} (running)", state.pid),
+            false => format!("{} (not running)", state.pid),
+        },
+    ]);
+    builder.push_record(["Started".to_string(), local_time(state.started_at)]);
+    builder.push_record([
+        "Last Check".to_string(),
+        state.last_check.map_or_else(never, local_time),
+    ]);
+    builder.push_record([
+        "Last Login".to_string(),
+        state.last_login.map_or_else(never, local_time),
+    ]);
+    if let Some(ip) = state.ip {
+        builder.push_record(["IP".to_string(), ip.to_string()]);
+    }
+    if let Some(ac_id) = &state.ac_id {
+        builder.push_record(["ac_id".to_string(), ac_id.clone()]);
+    }
+    builder.push_record([
+        "Failures".to_string(),
+        state.consecutive_failures.to_string(),
+    ]);
+    if let Some(error) = &state.last_error {
+        builder.push_record(["Last Error".to_string(), error.clone()]);
+    }
+
+    let mut table = builder.build();
+    println!("{}", table.with(Style::sharp()));
+}
+
+/// Print the most recent transitions of the daemon, oldest first
+///
+/// # Example output
+///
+/// ┌───────────────────────────────────┬──────────┬──────────────────────────────┐
+/// │ Time                              │ State    │ Summary                      │
+/// ├───────────────────────────────────┼──────────┼──────────────────────────────┤
+/// │ 2024-03-01 08:00:00 (2 hours ago) │ starting │ started                      │
+/// │ 2024-03-01 08:00:01 (2 hours ago) │ online   │ 1120xxxx: online at 10.1.2.3 │
+/// └───────────────────────────────────┴──────────┴──────────────────────────────┘
+pub fn print_daemon_history(history: &[DaemonTransition]) {
+    let mut builder = Builder::default();
+    builder.set_header(["Time", "State", "Summary"]);
+
+    for transition in history {
+        builder.push_record([
+            local_time(transition.at),
+            transition.phase.to_string(),
+            transition.summary.clone(),
+        ]);
+    }
+
+    let mut table = builder.build();
+    println!(
+        "{}",
+        table
+            .with(Style::sharp())
+            .with(Width::wrap(120).keep_words())
+    );
+}
@@ -0,0 +1,434 @@
+//! Usage history, snapshots of the login state kept in `usage.jsonl` under the data dir
+//!
+//! The keep-alive daemon appends a snapshot on every tick, and so does `bitsrun status --record`.
+//! As the portal only reports running totals, usage of a period is the sum of the increases
+//! between consecutive snapshots of an account. A counter that went down (a new month or
+//! session) counts from zero.
+//!
+//! Once a day, snapshots older than a week are thinned to the last one of every hour, which keeps
+//! the store small without changing the usage it adds up to.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use crate::client::SrunLoginState;
+use crate::state::write_atomic;
+
+use anyhow::Context;
+use anyhow::Result;
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::Days;
+use chrono::Local;
+use chrono::Months;
+use chrono::NaiveDate;
+use chrono::Utc;
+use directories::ProjectDirs;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// A snapshot of an account's running totals
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageSample {
+    /// Unix timestamp of the snapshot
+    pub at: i64,
+    pub username: String,
+    pub online_ip: IpAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sum_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sum_seconds: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_in: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_out: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_balance: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wallet_balance: Option<f64>,
+}
+
+impl UsageSample {
+    /// Snapshot a login state, with `username` used if the portal did not report one
+    pub fn new(username: &str, state: &SrunLoginState) -> Self {
+        Self {
+            at: Utc::now().timestamp(),
+            username: state
+                .user_name
+                .clone()
+                .unwrap_or_else(|| username.to_string()),
+            online_ip: state.online_ip,
+            sum_bytes: state.sum_bytes,
+            sum_seconds: state.sum_seconds,
+            bytes_in: state.bytes_in,
+            bytes_out: state.bytes_out,
+            user_balance: state.user_balance,
+            wallet_balance: state.wallet_balance,
+        }
+    }
+}
+
+/// Append-only store of [`UsageSample`]s, one JSON object per line
+#[derive(Debug, Clone)]
+pub struct UsageStore {
+    pub path: PathBuf,
+}
+
+impl UsageStore {
+    /// The store in the data dir, `$XDG_DATA_HOME/bitsrun/usage.jsonl` on Linux
+    pub fn open() -> Option<Self> {
+        let dirs = ProjectDirs::from("", "", "bitsrun")?;
+        Some(Self {
+            path: dirs.data_dir().join("usage.jsonl"),
+        })
+    }
+
+    pub fn append(&self, sample: &UsageSample) -> Result<()> {
+        // the first write of a day compacts the store
+        let modified = fs::metadata(&self.path).and_then(|meta| meta.modified());
+        if let Ok(modified) = modified {
+            let modified: DateTime<Local> = modified.into();
+            if modified.date_naive() != Local::now().date_naive() {
+                if let Err(e) = self.compact(sample.at) {
+                    warn!("Failed to compact usage history: {:#}", e);
+                }
+            }
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create data dir `{}`", dir.display()))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("failed to open `{}`", self.path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(sample)?)
+            .with_context(|| format!("failed to write `{}`", self.path.display()))
+    }
+
+    /// Every sample in the store, oldest first, skipping lines that fail to parse
+    ///
+    /// A store that does not exist yet is empty.
+    pub fn load(&self) -> Result<Vec<UsageSample>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read `{}`", self.path.display()))
+            }
+        };
+        let mut samples: Vec<UsageSample> = content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        samples.sort_by_key(|s| s.at);
+        Ok(samples)
+    }
+
+    /// Thin samples older than a week before `now`, see [`compact`]
+    pub fn compact(&self, now: i64) -> Result<()> {
+        let samples = self.load()?;
+        let kept = compact(&samples, now - FULL_RESOLUTION);
+        if kept.len() == samples.len() {
+            return Ok(());
+        }
+        let mut content = String::new();
+        for sample in &kept {
+            content.push_str(&serde_json::to_string(sample)?);
+            content.push('\n');
+        }
+        write_atomic(&self.path, &content)
+    }
+}
+
+/// How long samples are kept as they are, in seconds
+const FULL_RESOLUTION: i64 = 7 * 24 * 60 * 60;
+
+/// Keep only the last sample of every hour of each account before `before`
+///
+/// The first sample of an account, and any sample right before a running total went down, are
+/// kept as well, so that [`summarize`] adds up to the same usage.
+pub fn compact(samples: &[UsageSample], before: i64) -> Vec<UsageSample> {
+    // walk backwards, so that the next sample of each account is known
+    let mut next: HashMap<&str, &UsageSample> = HashMap::new();
+    let mut keep: Vec<bool> = samples
+        .iter()
+        .rev()
+        .map(|sample| {
+            let next = next.insert(&sample.username, sample);
+            sample.at >= before
+                || next.is_none_or(|next| {
+                    next.at / 3600 != sample.at / 3600
+                        || next.sum_bytes < sample.sum_bytes
+                        || next.bytes_in < sample.bytes_in
+                        || next.bytes_out < sample.bytes_out
+                        || next.sum_seconds < sample.sum_seconds
+                })
+        })
+        .collect();
+    keep.reverse();
+
+    let mut seen: HashSet<&str> = HashSet::new();
+    samples
+        .iter()
+        .zip(keep)
+        .filter(|(sample, keep)| seen.insert(&sample.username) || *keep)
+        .map(|(sample, _)| sample.clone())
+        .collect()
+}
+
+/// How usage is grouped in the report
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UsagePeriod {
+    #[default]
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl UsagePeriod {
+    /// First day of the period a timestamp falls in, in local time
+    fn start(&self, at: i64) -> NaiveDate {
+        let date = DateTime::from_timestamp(at, 0)
+            .unwrap_or_default()
+            .with_timezone(&Local)
+            .date_naive();
+        match self {
+            UsagePeriod::Daily => date,
+            UsagePeriod::Weekly => date.week(chrono::Weekday::Mon).first_day(),
+            UsagePeriod::Monthly => date.with_day(1).unwrap_or(date),
+        }
+    }
+
+    /// First day of the period after the one starting on `start`
+    fn next(&self, start: NaiveDate) -> NaiveDate {
+        match self {
+            UsagePeriod::Daily => start + Days::new(1),
+            UsagePeriod::Weekly => start + Days::new(7),
+            UsagePeriod::Monthly => start + Months::new(1),
+        }
+    }
+
+    /// Label of the period starting on `start`
+    fn label(&self, start: NaiveDate) -> String {
+        match self {
+            UsagePeriod::Daily => start.format("%Y-%m-%d").to_string(),
+            UsagePeriod::Weekly => {
+                let week = start.iso_week();
+                format!("{}-W{:02}", week.year(), week.week())
+            }
+            UsagePeriod::Monthly => start.format("%Y-%m").to_string(),
+        }
+    }
+}
+
+/// How the report is printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UsageFormat {
+    /// Table with a sparkline of the traffic
+    #[default]
+    Table,
+    Csv,
+    Json,
+}
+
+/// Traffic and online time used in a period
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UsageRow {
+    pub period: String,
+    pub bytes: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub seconds: i64,
+}
+
+/// Increase of a running total, counting from zero if it was reset
+fn increase<T: Copy + PartialOrd + std::ops::Sub<Output = T>>(
+    prev: Option<T>,
+    cur: Option<T>,
+) -> Option<T> {
+    match (prev, cur) {
+        (Some(prev), Some(cur)) if cur >= prev => Some(cur - prev),
+        (_, Some(cur)) => Some(cur),
+        (_, None) => None,
+    }
+}
+
+/// Group the increases between consecutive samples of each account by period, oldest first
+///
+/// Only samples of `username` are counted if set. Periods without usage, from the first one with
+/// some up to the one `now` falls in, are reported as zero.
+pub fn summarize(
+    samples: &[UsageSample],
+    period: UsagePeriod,
+    username: Option<&str>,
+    now: i64,
+) -> Vec<UsageRow> {
+    let mut rows: Vec<UsageRow> = vec![];
+    let mut last: Vec<&UsageSample> = vec![];
+    let mut current: Option<NaiveDate> = None;
+    // zero rows up to (and including) the period starting on `until`
+    let fill = |rows: &mut Vec<UsageRow>, current: &mut Option<NaiveDate>, until| {
+        let mut start = match *current {
+            Some(start) if start < until => period.next(start),
+            Some(_) => return,
+            None => until,
+        };
+        while start <= until {
+            rows.push(UsageRow {
+                period: period.label(start),
+                ..Default::default()
+            });
+            *current = Some(start);
+            start = period.next(start);
+        }
+    };
+
+    for sample in samples {
+        if username.is_some_and(|u| u != sample.username) {
+            continue;
+        }
+        let Some(prev_pos) = last.iter().position(|s| s.username == sample.username) else {
+            // the first sample of an account is only a baseline
+            last.push(sample);
+            continue;
+        };
+        let prev = std::mem::replace(&mut last[prev_pos], sample);
+
+        fill(&mut rows, &mut current, period.start(sample.at));
+        let row = rows.last_mut().expect("filled up to this period");
+        row.bytes += increase(prev.sum_bytes, sample.sum_bytes).unwrap_or(0);
+        row.bytes_in += increase(prev.bytes_in, sample.bytes_in).unwrap_or(0);
+        row.bytes_out += increase(prev.bytes_out, sample.bytes_out).unwrap_or(0);
+        row.seconds += increase(prev.sum_seconds, sample.sum_seconds).unwrap_or(0);
+    }
+    if current.is_some() {
+        fill(&mut rows, &mut current, period.start(now));
+    }
+    rows
+}
+
+/// Render values as a line of block characters, scaled to the largest
+///
+/// ```
+/// use bitsrun::usage::sparkline;
+///
+/// assert_eq!(sparkline(&[0, 1, 2, 4, 8]), "▁▂▃▅█");
+/// assert_eq!(sparkline(&[0, 0]), "▁▁");
+/// ```
+pub fn sparkline(values: &[u64]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().max().unwrap_or(0).max(1);
+    values
+        .iter()
+        .map(|&v| BARS[((v as f64 / max as f64) * 7.0).round() as usize])
+        .collect()
+}
+
+/// Render rows as CSV, with a header
+pub fn to_csv(rows: &[UsageRow]) -> String {
+    let mut csv = String::from("period,bytes,bytes_in,bytes_out,seconds\n");
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            row.period, row.bytes, row.bytes_in, row.bytes_out, row.seconds
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compact;
+    use super::summarize;
+    use super::UsagePeriod;
+    use super::UsageSample;
+
+    const DAY: i64 = 24 * 60 * 60;
+    // 2026-03-02 12:00:00 UTC, a Monday
+    const START: i64 = 1772452800;
+
+    fn sample(at: i64, username: &str, sum_bytes: u64) -> UsageSample {
+        UsageSample {
+            at,
+            username: username.to_string(),
+            online_ip: "10.1.2.3".parse().unwrap(),
+            sum_bytes: Some(sum_bytes),
+            sum_seconds: Some(sum_bytes as i64),
+            bytes_in: None,
+            bytes_out: None,
+            user_balance: None,
+            wallet_balance: None,
+        }
+    }
+
+    #[test]
+    fn summarize_fills_periods_without_samples() {
+        let samples = [
+            sample(START, "a", 100),
+            sample(START + 3600, "a", 150),
+            sample(START + 3 * DAY, "a", 400),
+        ];
+        let rows = summarize(&samples, UsagePeriod::Daily, None, START + 5 * DAY);
+        let bytes: Vec<u64> = rows.iter().map(|row| row.bytes).collect();
+        assert_eq!(bytes, [50, 0, 0, 250, 0, 0]);
+
+        let rows = summarize(&samples, UsagePeriod::Weekly, None, START + 5 * DAY);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].bytes, 300);
+
+        assert!(summarize(&samples, UsagePeriod::Daily, Some("b"), START).is_empty());
+    }
+
+    #[test]
+    fn summarize_counts_resets_from_zero() {
+        let samples = [
+            sample(START, "a", 100),
+            sample(START + 60, "b", 1000),
+            sample(START + 120, "a", 200),
+            sample(START + 180, "a", 30),
+            sample(START + 240, "b", 1500),
+        ];
+        let rows = summarize(&samples, UsagePeriod::Daily, None, START);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].bytes, 100 + 30 + 500);
+    }
+
+    #[test]
+    fn compact_keeps_the_same_usage() {
+        // every 10 minutes for two days, with the counter reset halfway through
+        let mut samples = vec![];
+        for i in 0..288 {
+            let bytes = match i < 144 {
+                true => 1000 + i * 10,
+                false => (i - 144) * 7,
+            };
+            samples.push(sample(START + i as i64 * 600, "a", bytes));
+            samples.push(sample(START + i as i64 * 600 + 1, "b", i * 3));
+        }
+        let now = START + 2 * DAY;
+        let compacted = compact(&samples, now - DAY);
+        assert!(compacted.len() < samples.len() * 3 / 4);
+        // the last day is left as is
+        assert!(compacted.ends_with(&samples[288..]));
+
+        for period in [UsagePeriod::Daily, UsagePeriod::Monthly] {
+            let total = |samples: &[UsageSample]| -> (u64, i64) {
+                let rows = summarize(samples, period, None, now);
+                (
+                    rows.iter().map(|row| row.bytes).sum(),
+                    rows.iter().map(|row| row.seconds).sum(),
+                )
+            };
+            assert_eq!(total(&compacted), total(&samples));
+        }
+    }
+}
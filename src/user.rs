@@ -1,7 +1,14 @@
+use crate::bind::BindOptions;
 use crate::config;
+use crate::portal::PortalOverrides;
+use crate::portal::PortalProfile;
+use crate::retry::RetryPolicy;
+use crate::router;
+use crate::router::RouterDevice;
 
 use std::fs;
 
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use owo_colors::OwoColorize;
@@ -15,6 +22,10 @@ pub struct BitUser {
     pub username: String,
     pub password: String,
     pub dm: bool,
+    pub portal: PortalProfile,
+    /// Skips `ac_id` discovery if set
+    pub ac_id: Option<String>,
+    pub retry: RetryPolicy,
 }
 
 /// Partial campus network user credentials
@@ -23,6 +34,16 @@ pub struct BitUserPartial {
     pub username: Option<String>,
     pub password: Option<String>,
     pub dm: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub portal: Option<PortalProfile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ac_id: Option<String>,
+    #[serde(flatten)]
+    pub bind: BindOptions,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<RouterDevice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
 }
 
 impl BitUserPartial {
@@ -31,6 +52,11 @@ impl BitUserPartial {
             username: username.clone(),
             password: password.clone(),
             dm,
+            portal: None,
+            ac_id: None,
+            bind: BindOptions::default(),
+            devices: vec![],
+            retry: None,
         }
     }
 }
@@ -59,20 +85,28 @@ fn parse_bit_user_config(config_path: &Option<String>) -> Result<BitUserPartial>
 ///
 /// Note that when logging out, `password` is not required.
 /// In this case, `require_password` should be set to `false`.
+///
+/// The portal profile is taken from the config file (if any), with `overrides` from the command line.
 pub fn finalize_bit_user(
     username: &Option<String>,
     password: &Option<String>,
     dm: bool,
     config_path: &Option<String>,
+    overrides: &PortalOverrides,
     require_password: bool,
 ) -> Result<BitUser> {
     let mut bit_user = BitUserPartial::new(username, password, Some(dm));
+    let parsed_config = parse_bit_user_config(config_path);
 
     // username and password priority: command line > config file > prompt
     if bit_user.username.is_none() | (require_password & bit_user.password.is_none()) {
         let mut user_from_file = BitUserPartial::default();
-        match parse_bit_user_config(config_path) {
-            Ok(value) => user_from_file = value,
+        match &parsed_config {
+            Ok(value) => {
+                user_from_file.username = value.username.clone();
+                user_from_file.password = value.password.clone();
+                user_from_file.dm = value.dm;
+            }
             Err(e) => println!(
                 "{} {}",
                 "warning:".if_supports_color(Stdout, |t| t.yellow()),
@@ -122,9 +156,50 @@ pub fn finalize_bit_user(
         };
     }
 
+    let parsed_config = parsed_config.ok();
+    let ac_id = parsed_config.as_ref().and_then(|u| u.ac_id.clone());
+    let retry = parsed_config.as_ref().and_then(|u| u.retry.clone());
+    let portal = PortalProfile::finalize(parsed_config.and_then(|u| u.portal), overrides)?;
+
     Ok(BitUser {
         username: bit_user.username.unwrap_or_default(),
         password: bit_user.password.unwrap_or_default(),
         dm: bit_user.dm.unwrap_or_default(),
+        portal,
+        ac_id,
+        retry: retry.unwrap_or_default(),
     })
 }
+
+/// Get the portal profile from the config file (if any), with `overrides` from the command line
+///
+/// Unlike [`finalize_bit_user`], a missing or invalid config file is not an error here.
+pub fn finalize_portal_profile(
+    config_path: &Option<String>,
+    overrides: &PortalOverrides,
+) -> Result<PortalProfile> {
+    let from_file = parse_bit_user_config(config_path)
+        .ok()
+        .and_then(|u| u.portal);
+    PortalProfile::finalize(from_file, overrides)
+}
+
+/// Get the interface or source address to bind to from the config file (if any), with
+/// `overrides` from the command line
+pub fn finalize_bind_options(
+    config_path: &Option<String>,
+    overrides: &BindOptions,
+) -> Result<BindOptions> {
+    let from_file = parse_bit_user_config(config_path).ok().map(|u| u.bind);
+    BindOptions::finalize(from_file, overrides)
+}
+
+/// Get the router mode devices from the config file, which must list some
+pub fn finalize_devices(config_path: &Option<String>) -> Result<Vec<RouterDevice>> {
+    let mut devices = parse_bit_user_config(config_path)?.devices;
+    if devices.is_empty() {
+        bail!("no `devices` listed in the config file")
+    }
+    router::finalize_portals(&mut devices)?;
+    Ok(devices)
+}
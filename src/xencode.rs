@@ -72,9 +72,99 @@ pub fn xencode(msg: &str, key: &str) -> Vec<u8> {
     splite(msg, false)
 }
 
+/// Inverse of `xencode`, returns an empty buffer if the decrypted size record is invalid
+pub fn xdecode(payload: &[u8], key: &str) -> Vec<u8> {
+    // at least one word of message and the size record
+    if payload.len() < 8 {
+        return vec![];
+    }
+    let mut msg = mix(payload, false);
+    // tokens are long enough in practice, but a mistyped one must not panic
+    let mut key = mix(key.as_bytes(), false);
+    key.resize(key.len().max(4), 0);
+
+    let len = msg.len();
+    let last = len - 1;
+    let c: u32 = 0x9e3779b9;
+
+    let count = 6 + 52 / msg.len();
+    let mut d: u32 = (count as u32).wrapping_mul(c);
+    for _ in 0..count {
+        let e = d >> 2 & 3;
+        for p in (0..=last).rev() {
+            let left = msg[(p + 1) % len];
+            let right = msg[(p + last) % len];
+            let mx = ((right >> 5) ^ (left << 2))
+                .wrapping_add((left >> 3 ^ right << 4) ^ (d ^ left))
+                .wrapping_add(key[(p & 3) ^ e as usize] ^ right);
+            msg[p] = msg[p].wrapping_sub(mx);
+        }
+        d = d.wrapping_sub(c);
+    }
+    splite(msg, true)
+}
+
 pub fn fkbase64(payload: Vec<u8>) -> String {
     let alphabet = Alphabet::new(BASE64_ALPHABET).unwrap();
     let engine = GeneralPurpose::new(&alphabet, GeneralPurposeConfig::new());
 
     engine.encode(payload)
 }
+
+/// Inverse of `fkbase64`
+pub fn fkbase64_decode(payload: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    let alphabet = Alphabet::new(BASE64_ALPHABET).unwrap();
+    let engine = GeneralPurpose::new(&alphabet, GeneralPurposeConfig::new());
+
+    engine.decode(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fkbase64;
+    use super::fkbase64_decode;
+    use super::xdecode;
+    use super::xencode;
+
+    const TOKEN: &str = "2f9c1b4fa5c0d0c1c8f5b0e1c7e5e1a4b1a3c0d7e1f2a3b4c5d6e7f8a9b0c1d2";
+
+    #[test]
+    fn xdecode_inverts_xencode() {
+        let info = r#"{"username":"1120230000","password":"password","ip":"10.1.2.3","acid":"7","enc_ver":"srun_bx1"}"#;
+        // every remainder of the length modulo 4
+        for len in 1..=info.len() {
+            let msg = &info[..len];
+            assert_eq!(
+                xdecode(&xencode(msg, TOKEN), TOKEN),
+                msg.as_bytes(),
+                "{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn xdecode_rejects_wrong_key_and_short_payloads() {
+        let encoded = xencode("hello, srun", TOKEN);
+        assert_ne!(xdecode(&encoded, "not the token"), b"hello, srun");
+        assert!(xdecode(&encoded[..4], TOKEN).is_empty());
+        assert!(xdecode(&[], "").is_empty());
+    }
+
+    #[test]
+    fn fkbase64_round_trip() {
+        for len in 0..=12 {
+            let payload: Vec<u8> = (0..len).map(|i| (i * 37 + 200) as u8).collect();
+            let encoded = fkbase64(payload.clone());
+            assert_eq!(fkbase64_decode(&encoded).unwrap(), payload);
+        }
+        let encoded = fkbase64(xencode("hello, srun", TOKEN));
+        let decoded = fkbase64_decode(&encoded).unwrap();
+        assert_eq!(xdecode(&decoded, TOKEN), b"hello, srun");
+    }
+
+    #[test]
+    fn fkbase64_uses_custom_alphabet() {
+        assert_eq!(fkbase64(b"\0\0\0".to_vec()), "LLLL");
+        assert!(fkbase64_decode("not base64!").is_err());
+    }
+}
@@ -0,0 +1,527 @@
+//! Drive `SrunClient` against the mock portal, over real HTTP on localhost
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use bitsrun::client::get_login_state;
+use bitsrun::client::SrunClient;
+use bitsrun::encoder::EncoderKind;
+use bitsrun::error::SrunError;
+use bitsrun::mock::MockFault;
+use bitsrun::mock::MockPortal;
+use bitsrun::portal::PortalProfile;
+use bitsrun::probe::probe_connectivity;
+use bitsrun::probe::Connectivity;
+use bitsrun::probe::ConnectivityConfig;
+use bitsrun::probe::Probe;
+use bitsrun::probe::ProbeVote;
+
+use tokio::net::TcpListener;
+
+const USERNAME: &str = "1120230000";
+const PASSWORD: &str = "password";
+
+/// Serve `portal` on a free port, returning a profile pointing at it
+async fn serve(portal: &MockPortal) -> PortalProfile {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = portal.clone();
+    tokio::spawn(async move { server.serve(listener).await });
+    PortalProfile {
+        captive_portal_test: url.clone(),
+        url,
+        ..Default::default()
+    }
+}
+
+async fn mock_portal() -> (MockPortal, PortalProfile) {
+    let portal = MockPortal::new("7");
+    portal.add_user(USERNAME, PASSWORD);
+    let profile = serve(&portal).await;
+    (portal, profile)
+}
+
+async fn client(profile: &PortalProfile, password: &str) -> SrunClient {
+    SrunClient::builder(USERNAME, password)
+        .portal(profile.clone())
+        .lock_dir(lock_dir())
+        .build()
+        .await
+        .unwrap()
+}
+
+/// A lock dir of the test's own, so that it waits neither on other tests nor on a running daemon
+fn lock_dir() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let next = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("bitsrun-test-{}-{}", std::process::id(), next))
+}
+
+fn localhost() -> IpAddr {
+    "127.0.0.1".parse().unwrap()
+}
+
+#[tokio::test]
+async fn discovers_ac_id_and_login_state() {
+    let (_, profile) = mock_portal().await;
+    let client = client(&profile, PASSWORD).await;
+    assert_eq!(client.ac_id, "7");
+    assert_eq!(client.ip, localhost());
+    assert_eq!(client.login_state.srun_error(), SrunError::NotOnline);
+}
+
+#[tokio::test]
+async fn login_and_logout() {
+    let (portal, profile) = mock_portal().await;
+    let client = client(&profile, PASSWORD).await;
+
+    let resp = client.login(false, false).await.unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Ok);
+    assert!(portal.is_online(&localhost()));
+
+    let state = get_login_state(&client.http_client, &profile, false)
+        .await
+        .unwrap();
+    assert_eq!(state.srun_error(), SrunError::Ok);
+    assert_eq!(state.user_name.as_deref(), Some(USERNAME));
+    assert_eq!(state.online_ip, localhost());
+
+    let resp = client.logout(true, false).await.unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Ok);
+    assert!(!portal.is_online(&localhost()));
+}
+
+#[tokio::test]
+async fn login_twice_is_already_online() {
+    let (_, profile) = mock_portal().await;
+    let client = client(&profile, PASSWORD).await;
+    client.login(false, false).await.unwrap();
+    let resp = client.login(true, false).await.unwrap();
+    assert_eq!(resp.suc_msg.as_deref(), Some("ip_already_online_error"));
+}
+
+#[tokio::test]
+async fn wrong_password_is_refused() {
+    let (portal, profile) = mock_portal().await;
+    let client = client(&profile, "wrong").await;
+    let resp = client.login(false, false).await.unwrap();
+    assert_eq!(resp.srun_error(), SrunError::WrongPassword);
+    assert!(!portal.is_online(&localhost()));
+}
+
+#[tokio::test]
+async fn unknown_user_is_refused() {
+    let (_, profile) = mock_portal().await;
+    let client = SrunClient::builder("nobody", PASSWORD)
+        .portal(profile)
+        .lock_dir(lock_dir())
+        .build()
+        .await
+        .unwrap();
+    let resp = client.login(false, false).await.unwrap();
+    assert_eq!(resp.srun_error(), SrunError::UserNotFound);
+}
+
+#[tokio::test]
+async fn hmac_encoder_logs_in() {
+    let portal = MockPortal::new("7").with_encoder(EncoderKind::SrunBx1Hmac);
+    portal.add_user(USERNAME, PASSWORD);
+    let mut profile = serve(&portal).await;
+
+    // the default encoder does not match what the portal expects
+    let resp = client(&profile, PASSWORD)
+        .await
+        .login(false, false)
+        .await
+        .unwrap();
+    assert_eq!(resp.srun_error(), SrunError::SignError);
+
+    profile.encoder = EncoderKind::SrunBx1Hmac;
+    let resp = client(&profile, PASSWORD)
+        .await
+        .login(false, false)
+        .await
+        .unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Ok);
+}
+
+#[tokio::test]
+async fn auto_encoder_detected_from_srun_ver() {
+    let (_, mut profile) = mock_portal().await;
+    profile.encoder = EncoderKind::Auto;
+    let client = client(&profile, PASSWORD).await;
+    assert_eq!(client.portal.encoder, EncoderKind::SrunBx1);
+    let resp = client.login(false, false).await.unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Ok);
+}
+
+#[tokio::test]
+async fn fault_error() {
+    let (portal, profile) = mock_portal().await;
+    portal.inject(MockFault::Error("E2616".into()));
+    let client = client(&profile, PASSWORD).await;
+    let resp = client.login(false, false).await.unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Arrears);
+    assert!(!portal.is_online(&localhost()));
+
+    portal.clear_faults();
+    let resp = client.login(false, false).await.unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Ok);
+}
+
+#[tokio::test]
+async fn fault_latency() {
+    let (portal, profile) = mock_portal().await;
+    portal.inject(MockFault::Latency(Duration::from_millis(150)));
+    let client = client(&profile, PASSWORD).await;
+    let started = Instant::now();
+    let resp = client.login(false, false).await.unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Ok);
+    // `get_challenge` and `srun_portal`
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    // beyond the 400ms request timeout
+    portal.inject(MockFault::Latency(Duration::from_millis(500)));
+    assert!(client.logout(true, false).await.is_err());
+}
+
+#[tokio::test]
+async fn fault_ip_mismatch() {
+    let (portal, profile) = mock_portal().await;
+    let client = client(&profile, PASSWORD).await;
+    let seen: IpAddr = "10.1.2.3".parse().unwrap();
+    portal.inject(MockFault::IpMismatch(seen));
+
+    // the client follows the IP the gateway saw in `get_challenge`
+    let resp = client.login(false, false).await.unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Ok);
+    assert_eq!(resp.online_ip, seen);
+    assert!(portal.is_online(&seen));
+    assert!(!portal.is_online(&localhost()));
+}
+
+#[tokio::test]
+async fn fault_malformed_jsonp() {
+    let (portal, profile) = mock_portal().await;
+    let client = client(&profile, PASSWORD).await;
+    portal.inject(MockFault::MalformedJsonp);
+    assert!(client.login(false, false).await.is_err());
+    assert!(get_login_state(&client.http_client, &profile, false)
+        .await
+        .is_err());
+    assert!(!portal.is_online(&localhost()));
+}
+
+#[tokio::test]
+async fn dual_stack_login_and_logout() {
+    let (portal, mut profile) = mock_portal().await;
+    profile.double_stack = true;
+    profile.url_v6 = Some(profile.url.clone());
+    let ipv6: IpAddr = "fd00::1".parse().unwrap();
+    let client = SrunClient::builder(USERNAME, PASSWORD)
+        .portal(profile)
+        .lock_dir(lock_dir())
+        .ipv6(ipv6)
+        .build()
+        .await
+        .unwrap();
+
+    let resp = client.login_v6(false, false).await.unwrap().unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Ok);
+    assert!(portal.is_online(&ipv6));
+
+    let resp = client.logout_v6(true, false).await.unwrap().unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Ok);
+    assert!(!portal.is_online(&ipv6));
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn deprecated_new_still_builds() {
+    let (_, profile) = mock_portal().await;
+    let client = SrunClient::new(
+        USERNAME.to_string(),
+        PASSWORD.to_string(),
+        None,
+        None,
+        Some(true),
+        Some(profile),
+    )
+    .await
+    .unwrap();
+    assert_eq!(client.ac_id, "7");
+    assert_eq!(client.ip, localhost());
+    assert!(client.dm);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn binds_to_interface() {
+    use bitsrun::bind::BindOptions;
+
+    let (_, profile) = mock_portal().await;
+    let bind = |interface: &str| BindOptions {
+        interface: Some(interface.to_string()),
+        source_ip: None,
+    };
+    let client = SrunClient::builder(USERNAME, PASSWORD)
+        .portal(profile.clone())
+        .lock_dir(lock_dir())
+        .bind(bind("lo"))
+        .build()
+        .await
+        .unwrap();
+    let resp = client.login(false, false).await.unwrap();
+    assert_eq!(resp.srun_error(), SrunError::Ok);
+
+    assert!(SrunClient::builder(USERNAME, PASSWORD)
+        .portal(profile)
+        .lock_dir(lock_dir())
+        .bind(bind("bitsrun-none0"))
+        .build()
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn router_logs_in_devices_through_their_portals() {
+    use bitsrun::router::DeviceState;
+    use bitsrun::router::Router;
+    use bitsrun::router::RouterDevice;
+
+    let (portal, profile) = mock_portal().await;
+    let other = MockPortal::new("9");
+    other.add_user("other", "secret");
+    let other_profile = serve(&other).await;
+
+    let device = |ip: &str| RouterDevice {
+        ip: ip.parse().unwrap(),
+        name: None,
+        username: None,
+        password: None,
+        portal: None,
+    };
+    let devices = vec![
+        device("10.1.2.3"),
+        device("10.1.2.4"),
+        RouterDevice {
+            username: Some("other".into()),
+            password: Some("secret".into()),
+            portal: Some(other_profile),
+            ..device("10.2.0.5")
+        },
+    ];
+    let http_client = reqwest::Client::new();
+    let mut router = Router::new(
+        http_client,
+        profile,
+        devices,
+        USERNAME.into(),
+        PASSWORD.into(),
+    );
+    router.lock_dir = Some(lock_dir());
+
+    let statuses = router.keep_online().await;
+    assert!(statuses
+        .iter()
+        .all(|status| matches!(status.state, DeviceState::LoggedIn)));
+    assert!(portal.is_online(&"10.1.2.3".parse().unwrap()));
+    assert!(portal.is_online(&"10.1.2.4".parse().unwrap()));
+    assert!(other.is_online(&"10.2.0.5".parse().unwrap()));
+    assert!(!portal.is_online(&"10.2.0.5".parse().unwrap()));
+    assert_eq!(router.ac_id().as_deref(), Some("7"));
+
+    let statuses = router.check().await;
+    assert!(statuses
+        .iter()
+        .all(|status| matches!(status.state, DeviceState::Online(_))));
+}
+
+#[tokio::test]
+async fn router_reclaims_devices_from_the_account_failed_over_to() {
+    use bitsrun::router::DeviceState;
+    use bitsrun::router::Router;
+    use bitsrun::router::RouterDevice;
+
+    let (portal, profile) = mock_portal().await;
+    portal.add_user("backup", "secret");
+    portal.add_user("own", "secret");
+    let device = |ip: &str| RouterDevice {
+        ip: ip.parse().unwrap(),
+        name: None,
+        username: None,
+        password: None,
+        portal: None,
+    };
+    let devices = vec![
+        device("10.1.2.3"),
+        RouterDevice {
+            username: Some("own".into()),
+            password: Some("secret".into()),
+            ..device("10.1.2.4")
+        },
+    ];
+    let http_client = reqwest::Client::new();
+    let mut router = Router::new(
+        http_client,
+        profile,
+        devices,
+        "backup".into(),
+        "secret".into(),
+    );
+    router.lock_dir = Some(lock_dir());
+    router.keep_online().await;
+
+    // a new billing cycle started
+    router.username = USERNAME.into();
+    router.password = PASSWORD.into();
+    assert!(router.reclaim().await);
+    assert!(!portal.is_online(&"10.1.2.3".parse().unwrap()));
+    assert!(portal.is_online(&"10.1.2.4".parse().unwrap()));
+
+    router.keep_online().await;
+    let users: Vec<_> = router
+        .check()
+        .await
+        .into_iter()
+        .map(|status| match status.state {
+            DeviceState::Online(state) => state.user_name,
+            state => panic!("{} is {}", status.device, state),
+        })
+        .collect();
+    assert_eq!(users, [Some(USERNAME.to_string()), Some("own".to_string())]);
+}
+
+#[tokio::test]
+async fn login_with_retry_stops_on_permanent_errors() {
+    use bitsrun::retry::RetryPolicy;
+
+    let (portal, profile) = mock_portal().await;
+    let retry = RetryPolicy {
+        max_attempts: 3,
+        initial_delay_ms: 100,
+        jitter: 0.0,
+        ..Default::default()
+    };
+    let client = |password: &str| {
+        SrunClient::builder(USERNAME, password)
+            .portal(profile.clone())
+            .lock_dir(lock_dir())
+            .retry(retry.clone())
+            .build()
+    };
+
+    // a wrong password is not retried
+    let resp = client("wrong")
+        .await
+        .unwrap()
+        .login_with_retry(false, false)
+        .await
+        .unwrap();
+    assert_eq!(resp.srun_error(), SrunError::WrongPassword);
+    assert_eq!(portal.login_attempts(), 1);
+
+    // a transient error is, up to `max_attempts`
+    portal.inject(MockFault::Error("E2532".into()));
+    let resp = client(PASSWORD)
+        .await
+        .unwrap()
+        .login_with_retry(false, false)
+        .await
+        .unwrap();
+    assert_eq!(resp.srun_error(), SrunError::TooFrequent);
+    assert_eq!(portal.login_attempts(), 4);
+    assert!(!portal.is_online(&localhost()));
+}
+
+/// Probe `paths` of the mock portal, deciding on `quorum` agreeing votes
+fn probes(profile: &PortalProfile, paths: &[&str], quorum: usize) -> ConnectivityConfig {
+    ConnectivityConfig {
+        probes: paths
+            .iter()
+            .map(|path| Probe::generate_204(&format!("{}{}", profile.url, path)))
+            .collect(),
+        quorum,
+        timeout_ms: 2000,
+        guess_ac_id: false,
+    }
+}
+
+#[tokio::test]
+async fn probe_decided_by_first_to_quorum() {
+    let (_, profile) = mock_portal().await;
+    let http = reqwest::Client::new();
+    let online = format!("{}/probe/online", profile.url);
+
+    // the slow probe is not waited for
+    let config = probes(&profile, &["/probe/slow", "/probe/online"], 1);
+    let report = probe_connectivity(&http, &config, "1").await;
+    assert_eq!(report.connectivity, Connectivity::Online);
+    assert_eq!(report.decided_by, Some(online.clone()));
+    assert_eq!(report.votes, [(online.clone(), ProbeVote::Online)]);
+
+    let paths = ["/probe/online", "/probe/slow", "/probe/online"];
+    let report = probe_connectivity(&http, &probes(&profile, &paths, 2), "1").await;
+    assert_eq!(report.connectivity, Connectivity::Online);
+    assert_eq!(report.decided_by, Some(online));
+    assert_eq!(report.votes.len(), 2);
+}
+
+#[tokio::test]
+async fn probe_finds_ac_id_in_redirect_or_body() {
+    let (_, profile) = mock_portal().await;
+    let http = reqwest::Client::new();
+    for path in ["/generate_204", "/probe/page"] {
+        let report = probe_connectivity(&http, &probes(&profile, &[path], 1), "1").await;
+        assert_eq!(
+            report.connectivity,
+            Connectivity::Intercepted(Some("7".into()))
+        );
+    }
+
+    client(&profile, PASSWORD)
+        .await
+        .login(false, false)
+        .await
+        .unwrap();
+    let report = probe_connectivity(&http, &probes(&profile, &["/generate_204"], 1), "1").await;
+    assert_eq!(report.connectivity, Connectivity::Online);
+}
+
+#[tokio::test]
+async fn probe_guesses_ac_id_only_if_allowed() {
+    let (_, profile) = mock_portal().await;
+    let http = reqwest::Client::new();
+    let mut config = probes(&profile, &["/probe/blocked"], 1);
+    let report = probe_connectivity(&http, &config, "1").await;
+    assert_eq!(report.connectivity, Connectivity::Intercepted(None));
+
+    config.guess_ac_id = true;
+    let report = probe_connectivity(&http, &config, "1").await;
+    assert_eq!(
+        report.connectivity,
+        Connectivity::Intercepted(Some("1".into()))
+    );
+}
+
+#[tokio::test]
+async fn probe_unreachable_without_quorum() {
+    let (_, profile) = mock_portal().await;
+    let http = reqwest::Client::new();
+    let config = probes(&profile, &["/probe/online", "/probe/blocked"], 2);
+    let report = probe_connectivity(&http, &config, "1").await;
+    assert_eq!(report.connectivity, Connectivity::Unreachable);
+    assert_eq!(report.decided_by, None);
+    assert_eq!(report.votes.len(), 2);
+
+    // timed out probes do not vote for anything
+    let mut config = probes(&profile, &["/probe/slow"], 1);
+    config.timeout_ms = 100;
+    let report = probe_connectivity(&http, &config, "1").await;
+    assert_eq!(report.connectivity, Connectivity::Unreachable);
+    assert!(matches!(report.votes[0].1, ProbeVote::Unreachable(_)));
+}